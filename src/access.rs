@@ -0,0 +1,246 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::image::Image;
+
+// Addresses per row of the heatmap, which makes 4 KiB a square.
+const HEATMAP_WIDTH: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Anomaly {
+    // An address that was executed is written to.
+    SelfModifyingCode,
+    // An address that was read or written as data is executed.
+    ExecutedData,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Finding {
+    pub anomaly: Anomaly,
+    pub address: u16,
+    // The instruction that did the write, or the one being executed.
+    pub program_counter: u16,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.anomaly {
+            Anomaly::SelfModifyingCode => write!(
+                f,
+                "Self-modifying code: {:03X} writes to {:03X}, which was executed before.",
+                self.program_counter, self.address
+            ),
+            Anomaly::ExecutedData => write!(
+                f,
+                "Executing data: {:03X} was read or written as data before.",
+                self.address
+            ),
+        }
+    }
+}
+
+// How often each byte of memory was read, written and executed. Every anomaly is reported
+// once per address.
+#[derive(Debug)]
+pub struct AccessCounts {
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+    pub executes: Vec<u32>,
+    findings: Vec<Finding>,
+    reported: HashSet<(Anomaly, u16)>,
+}
+
+impl AccessCounts {
+    pub fn new(memory_size: usize) -> AccessCounts {
+        AccessCounts {
+            reads: vec![0; memory_size],
+            writes: vec![0; memory_size],
+            executes: vec![0; memory_size],
+            findings: Vec::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    pub fn read(self: &mut Self, address: usize) {
+        if let Some(count) = self.reads.get_mut(address) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    pub fn write(self: &mut Self, address: usize, program_counter: u16) {
+        if address >= self.writes.len() {
+            return;
+        }
+        self.writes[address] = self.writes[address].saturating_add(1);
+        if self.executes[address] > 0 {
+            self.report(Anomaly::SelfModifyingCode, address, program_counter);
+        }
+    }
+
+    pub fn execute(self: &mut Self, address: usize) {
+        if address >= self.executes.len() {
+            return;
+        }
+        self.executes[address] = self.executes[address].saturating_add(1);
+        if self.reads[address] > 0 || self.writes[address] > 0 {
+            self.report(Anomaly::ExecutedData, address, address as u16);
+        }
+    }
+
+    fn report(self: &mut Self, anomaly: Anomaly, address: usize, program_counter: u16) {
+        if self.reported.insert((anomaly, address as u16)) {
+            self.findings.push(Finding {
+                anomaly,
+                address: address as u16,
+                program_counter,
+            });
+        }
+    }
+
+    // Anomalies found since the last call.
+    pub fn take_findings(self: &mut Self) -> Vec<Finding> {
+        std::mem::take(&mut self.findings)
+    }
+
+    // One pixel per address, left to right and top to bottom, with writes in red, executes
+    // in green and reads in blue. Counts are on a logarithmic scale, relative to the
+    // highest count of their kind.
+    pub fn heatmap(self: &Self) -> Image {
+        let height = (self.reads.len() as u32).div_ceil(HEATMAP_WIDTH);
+        let mut pixels = vec![0; (HEATMAP_WIDTH * height * 3) as usize];
+        for (channel, counts) in [&self.writes, &self.executes, &self.reads]
+            .iter()
+            .enumerate()
+        {
+            let max = counts.iter().copied().max().unwrap_or(0);
+            for (address, &count) in counts.iter().enumerate() {
+                pixels[address * 3 + channel] = intensity(count, max);
+            }
+        }
+
+        Image {
+            width: HEATMAP_WIDTH,
+            height,
+            pixels,
+        }
+    }
+}
+
+// Anything that was accessed at all stands out from what wasn't.
+fn intensity(count: u32, max: u32) -> u8 {
+    if count == 0 {
+        0
+    } else if max <= 1 {
+        255
+    } else {
+        (64.0 + 191.0 * (count as f32).ln() / (max as f32).ln()).round() as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::keypad::Keypad;
+
+    #[test]
+    fn counts_every_access() {
+        let mut counts = AccessCounts::new(16);
+        counts.read(3);
+        counts.read(3);
+        counts.write(4, 0);
+        counts.execute(5);
+        // Accesses outside memory are ignored.
+        counts.read(16);
+        counts.write(16, 0);
+        counts.execute(16);
+        assert_eq!(
+            (counts.reads[3], counts.writes[3], counts.executes[3]),
+            (2, 0, 0)
+        );
+        assert_eq!(
+            (counts.reads[4], counts.writes[4], counts.executes[4]),
+            (0, 1, 0)
+        );
+        assert_eq!(
+            (counts.reads[5], counts.writes[5], counts.executes[5]),
+            (0, 0, 1)
+        );
+        assert!(counts.take_findings().is_empty());
+    }
+
+    #[test]
+    fn reports_each_anomaly_once_per_address() {
+        let mut counts = AccessCounts::new(16);
+        counts.execute(2);
+        counts.write(2, 8);
+        counts.write(2, 10);
+        counts.read(6);
+        counts.execute(6);
+        counts.execute(6);
+
+        let findings = counts.take_findings();
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].anomaly, Anomaly::SelfModifyingCode);
+        assert_eq!((findings[0].address, findings[0].program_counter), (2, 8));
+        assert_eq!(
+            findings[0].to_string(),
+            "Self-modifying code: 008 writes to 002, which was executed before."
+        );
+        assert_eq!(findings[1].anomaly, Anomaly::ExecutedData);
+        assert_eq!(findings[1].address, 6);
+
+        // Findings are only handed out once, and not found again.
+        counts.write(2, 12);
+        assert!(counts.take_findings().is_empty());
+        // The other anomaly at the same address is still reported.
+        counts.execute(2);
+        assert_eq!(counts.take_findings()[0].anomaly, Anomaly::ExecutedData);
+    }
+
+    #[test]
+    fn heatmap_scales_each_kind_of_access_on_its_own() {
+        let mut counts = AccessCounts::new(100);
+        for _ in 0..100 {
+            counts.read(0);
+        }
+        for _ in 0..10 {
+            counts.read(1);
+        }
+        counts.read(2);
+        counts.write(65, 0);
+        counts.execute(99);
+
+        let heatmap = counts.heatmap();
+        assert_eq!((heatmap.width, heatmap.height), (64, 2));
+        assert_eq!(heatmap.pixel(0, 0), [0, 0, 255]);
+        assert_eq!(heatmap.pixel(1, 0), [0, 0, 160]);
+        assert_eq!(heatmap.pixel(2, 0), [0, 0, 64]);
+        assert_eq!(heatmap.pixel(3, 0), [0, 0, 0]);
+        assert_eq!(heatmap.pixel(1, 1), [255, 0, 0]);
+        assert_eq!(heatmap.pixel(35, 1), [0, 255, 0]);
+        // The padding after the end of memory stays black.
+        assert_eq!(heatmap.pixel(63, 1), [0, 0, 0]);
+    }
+
+    #[test]
+    fn the_interpreter_reports_programs_that_overwrite_themselves() {
+        // I := 0x200, v0 := 0, save v0, then loop forever.
+        let mut interpreter = Interpreter::new(&[0xa2, 0x00, 0x60, 0x00, 0xf0, 0x55, 0x12, 0x06]);
+        interpreter.set_trace(false);
+        interpreter.enable_access_counts();
+        interpreter.run_frame(&mut Keypad::new(0), 10).unwrap();
+
+        let counts = interpreter.access_counts().unwrap();
+        assert_eq!(counts.executes[0x200], 1);
+        assert_eq!(counts.executes[0x206], 7);
+        assert_eq!(counts.writes[0x200], 1);
+        let findings = interpreter.take_findings();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].anomaly, Anomaly::SelfModifyingCode);
+        assert_eq!(
+            (findings[0].address, findings[0].program_counter),
+            (0x200, 0x204)
+        );
+    }
+}
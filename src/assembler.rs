@@ -0,0 +1,314 @@
+use crate::symbols::Symbols;
+
+// Where programs are loaded.
+const ORIGIN: u16 = 0x200;
+
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    // Every label, and a data region for each run of DB and DW lines.
+    pub symbols: Symbols,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    Register(u16),
+    Number(u16),
+    I,
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    Bcd,
+}
+
+// A line of source, without its label and comment.
+struct Statement<'a> {
+    line_number: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+// Assembles the mnemonics the disassembler produces, so that its output can be fed back
+// in. Each line holds an optional `label:`, an instruction or a DB/DW directive, and an
+// optional `; comment`. Numbers are #NN or 0xNN in hexadecimal, or decimal otherwise.
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    // The first pass gives every label its address.
+    let mut symbols = Symbols::default();
+    let mut statements = Vec::new();
+    let mut address = ORIGIN;
+    let mut data_start: Option<(u16, Option<String>)> = None;
+    let mut pending_label: Option<String> = None;
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let at_line = |e: String| format!("line {}: {}", line_number, e);
+        let mut text = line.split(';').next().unwrap_or("").trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) || is_keyword(label) {
+                return Err(at_line(format!("Invalid label: {}", label)));
+            }
+            if symbols.address(label).is_some() {
+                return Err(at_line(format!("Label {} is defined twice.", label)));
+            }
+            symbols.add_label(address, label).map_err(at_line)?;
+            pending_label = Some(label.to_string());
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, operands),
+            None => (text, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let operands: Vec<&str> = operands
+            .split(',')
+            .map(|operand| operand.trim())
+            .filter(|operand| !operand.is_empty())
+            .collect();
+
+        let size = match mnemonic.as_str() {
+            "DB" => operands.len() as u16,
+            "DW" => operands.len() as u16 * 2,
+            _ => 2,
+        };
+        let is_data = mnemonic == "DB" || mnemonic == "DW";
+        match (&data_start, is_data) {
+            (None, true) => data_start = Some((address, pending_label.clone())),
+            (Some((start, name)), false) => {
+                symbols.add_data(*start..address, name.clone());
+                data_start = None;
+            }
+            _ => (),
+        }
+        pending_label = None;
+
+        if address as usize + size as usize > 0x1000 {
+            return Err(at_line("The program doesn't fit in memory.".to_string()));
+        }
+        address += size;
+        statements.push(Statement {
+            line_number,
+            mnemonic,
+            operands,
+        });
+    }
+    if let Some((start, name)) = data_start {
+        symbols.add_data(start..address, name);
+    }
+
+    let mut rom = Vec::new();
+    for statement in &statements {
+        encode(statement, &symbols, &mut rom)
+            .map_err(|e| format!("line {}: {}", statement.line_number, e))?;
+    }
+
+    Ok(Assembly { rom, symbols })
+}
+
+fn encode(statement: &Statement, symbols: &Symbols, rom: &mut Vec<u8>) -> Result<(), String> {
+    let operands = statement
+        .operands
+        .iter()
+        .map(|operand| parse_operand(operand, symbols))
+        .collect::<Result<Vec<Operand>, String>>()?;
+
+    if statement.mnemonic == "DB" || statement.mnemonic == "DW" {
+        for operand in operands {
+            let value = match operand {
+                Operand::Number(value) => value,
+                _ => return Err(format!("{} only takes numbers.", statement.mnemonic)),
+            };
+            if statement.mnemonic == "DB" {
+                rom.push(byte(value)?);
+            } else {
+                rom.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        return Ok(());
+    }
+
+    use Operand::*;
+    let opcode = match (statement.mnemonic.as_str(), operands.as_slice()) {
+        ("CLS", []) => 0x00e0,
+        ("RET", []) => 0x00ee,
+        ("SYS", [Number(a)]) => address(*a)?,
+        ("JP", [Number(a)]) => 0x1000 | address(*a)?,
+        ("JP", [Register(0), Number(a)]) => 0xb000 | address(*a)?,
+        ("CALL", [Number(a)]) => 0x2000 | address(*a)?,
+        ("SE", [Register(x), Number(n)]) => 0x3000 | x << 8 | byte(*n)? as u16,
+        ("SE", [Register(x), Register(y)]) => 0x5000 | x << 8 | y << 4,
+        ("SNE", [Register(x), Number(n)]) => 0x4000 | x << 8 | byte(*n)? as u16,
+        ("SNE", [Register(x), Register(y)]) => 0x9000 | x << 8 | y << 4,
+        ("LD", [Register(x), Number(n)]) => 0x6000 | x << 8 | byte(*n)? as u16,
+        ("LD", [Register(x), Register(y)]) => 0x8000 | x << 8 | y << 4,
+        ("LD", [I, Number(a)]) => 0xa000 | address(*a)?,
+        ("LD", [Register(x), DelayTimer]) => 0xf007 | x << 8,
+        ("LD", [Register(x), Key]) => 0xf00a | x << 8,
+        ("LD", [DelayTimer, Register(x)]) => 0xf015 | x << 8,
+        ("LD", [SoundTimer, Register(x)]) => 0xf018 | x << 8,
+        ("LD", [Font, Register(x)]) => 0xf029 | x << 8,
+        ("LD", [Bcd, Register(x)]) => 0xf033 | x << 8,
+        ("LD", [IndirectI, Register(x)]) => 0xf055 | x << 8,
+        ("LD", [Register(x), IndirectI]) => 0xf065 | x << 8,
+        ("ADD", [Register(x), Number(n)]) => 0x7000 | x << 8 | byte(*n)? as u16,
+        ("ADD", [Register(x), Register(y)]) => 0x8004 | x << 8 | y << 4,
+        ("ADD", [I, Register(x)]) => 0xf01e | x << 8,
+        ("OR", [Register(x), Register(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [Register(x), Register(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [Register(x), Register(y)]) => 0x8003 | x << 8 | y << 4,
+        ("SUB", [Register(x), Register(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [Register(x)]) => 0x8006 | x << 8 | x << 4,
+        ("SHR", [Register(x), Register(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SUBN", [Register(x), Register(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [Register(x)]) => 0x800e | x << 8 | x << 4,
+        ("SHL", [Register(x), Register(y)]) => 0x800e | x << 8 | y << 4,
+        ("RND", [Register(x), Number(n)]) => 0xc000 | x << 8 | byte(*n)? as u16,
+        ("DRW", [Register(x), Register(y), Number(n)]) if *n <= 0xf => 0xd000 | x << 8 | y << 4 | n,
+        ("SKP", [Register(x)]) => 0xe09e | x << 8,
+        ("SKNP", [Register(x)]) => 0xe0a1 | x << 8,
+        _ => {
+            return Err(format!(
+                "Invalid instruction: {} {}",
+                statement.mnemonic,
+                statement.operands.join(", ")
+            ))
+        }
+    };
+    rom.extend_from_slice(&opcode.to_be_bytes());
+    Ok(())
+}
+
+fn parse_operand(text: &str, symbols: &Symbols) -> Result<Operand, String> {
+    let upper = text.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => return Ok(Operand::I),
+        "[I]" => return Ok(Operand::IndirectI),
+        "DT" => return Ok(Operand::DelayTimer),
+        "ST" => return Ok(Operand::SoundTimer),
+        "K" => return Ok(Operand::Key),
+        "F" => return Ok(Operand::Font),
+        "B" => return Ok(Operand::Bcd),
+        _ => (),
+    }
+    if let Some(register) = register(&upper) {
+        return Ok(Operand::Register(register));
+    }
+    if let Some(address) = symbols.address(text) {
+        return Ok(Operand::Number(address));
+    }
+
+    let number = if let Some(digits) = upper.strip_prefix("0X").or_else(|| upper.strip_prefix('#'))
+    {
+        u16::from_str_radix(digits, 16)
+    } else {
+        upper.parse()
+    };
+    match number {
+        Ok(number) => Ok(Operand::Number(number)),
+        Err(..) => Err(format!("Not a number, register or known label: {}", text)),
+    }
+}
+
+fn register(text: &str) -> Option<u16> {
+    let digit = text.strip_prefix('V')?;
+    match u16::from_str_radix(digit, 16) {
+        Ok(register) if digit.len() == 1 => Some(register),
+        _ => None,
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut characters = text.chars();
+    match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            characters.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Names an operand can't be a label for.
+fn is_keyword(text: &str) -> bool {
+    let upper = text.to_ascii_uppercase();
+    matches!(upper.as_str(), "I" | "DT" | "ST" | "K" | "F" | "B") || register(&upper).is_some()
+}
+
+fn byte(value: u16) -> Result<u8, String> {
+    u8::try_from(value).map_err(|_| format!("Not a byte: {:#X}", value))
+}
+
+fn address(value: u16) -> Result<u16, String> {
+    if value <= 0xfff {
+        Ok(value)
+    } else {
+        Err(format!("Not an address: {:#X}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler;
+
+    // Opcodes with bits the decoder ignores, e.g. 5XY1, come back without them, so it is
+    // the disassembly that has to survive the round trip.
+    #[test]
+    fn reassembles_every_disassembled_opcode() {
+        for opcode in 0..=0xffff_u16 {
+            let source = disassembler::disassemble(opcode);
+            let rom = assemble(&source)
+                .map_err(|e| format!("{}: {}", source, e))
+                .unwrap()
+                .rom;
+            assert_eq!(rom.len(), 2, "{}", source);
+            let reassembled = u16::from_be_bytes([rom[0], rom[1]]);
+            assert_eq!(disassembler::disassemble(reassembled), source);
+        }
+    }
+
+    #[test]
+    fn resolves_labels_and_marks_data() {
+        let assembly = assemble(
+            "start: LD I, sprite ; point at the sprite\n\
+             \x20      DRW V0, V1, 2\n\
+             loop:  JP loop\n\
+             sprite: DB #F0, 0x90\n\
+             \x20      DW 4660\n\
+             after: CALL start\n",
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.rom,
+            [0xa2, 0x06, 0xd0, 0x12, 0x12, 0x04, 0xf0, 0x90, 0x12, 0x34, 0x22, 0x00]
+        );
+        assert_eq!(assembly.symbols.address("loop"), Some(0x204));
+        assert_eq!(assembly.symbols.address("after"), Some(0x20a));
+        let region = assembly.symbols.data_region(0x209).unwrap();
+        assert_eq!(region.range, 0x206..0x20a);
+        assert_eq!(region.name.as_deref(), Some("sprite"));
+        assert!(assembly.symbols.data_region(0x20a).is_none());
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        for (source, error) in [
+            (
+                "CLS\nJP nowhere",
+                "line 2: Not a number, register or known label: nowhere",
+            ),
+            ("a: CLS\na: CLS", "line 2: Label a is defined twice."),
+            ("LD V0, 256", "line 1: Not a byte: 0x100"),
+            ("V0: CLS", "line 1: Invalid label: V0"),
+            (
+                "DRW V0, V1, 16",
+                "line 1: Invalid instruction: DRW V0, V1, 16",
+            ),
+        ] {
+            assert_eq!(assemble(source).err().as_deref(), Some(error), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::interpreter::Interpreter;
+use crate::symbols::Symbols;
+
+// Something a condition can look at.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(usize),
+    MemoryRegister,
+    DelayTimer,
+    SoundTimer,
+    ProgramCounter,
+    // The byte at [address] or [I + offset].
+    Memory { relative_to_i: bool, offset: u16 },
+    Number(u16),
+}
+
+impl Operand {
+    fn parse(text: &str, symbols: &Symbols) -> Result<Operand, String> {
+        let upper = text.to_ascii_uppercase();
+        match upper.as_str() {
+            "I" => return Ok(Operand::MemoryRegister),
+            "DT" => return Ok(Operand::DelayTimer),
+            "ST" => return Ok(Operand::SoundTimer),
+            "PC" => return Ok(Operand::ProgramCounter),
+            _ => (),
+        }
+        if let Some(digit) = upper.strip_prefix('V') {
+            if let (1, Ok(register)) = (digit.len(), usize::from_str_radix(digit, 16)) {
+                return Ok(Operand::Register(register));
+            }
+        }
+        if let Some(inner) = text
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            let inner: String = inner.split_whitespace().collect();
+            let upper = inner.to_ascii_uppercase();
+            if upper == "I" {
+                return Ok(Operand::Memory {
+                    relative_to_i: true,
+                    offset: 0,
+                });
+            }
+            if let Some(offset) = upper.strip_prefix("I+") {
+                return Ok(Operand::Memory {
+                    relative_to_i: true,
+                    offset: symbols.parse_address(offset)?,
+                });
+            }
+            return Ok(Operand::Memory {
+                relative_to_i: false,
+                offset: symbols.parse_address(&inner)?,
+            });
+        }
+        Ok(Operand::Number(symbols.parse_address(text)?))
+    }
+
+    fn value(self: &Self, interpreter: &Interpreter) -> u16 {
+        match *self {
+            Operand::Register(register) => interpreter.registers()[register] as u16,
+            Operand::MemoryRegister => interpreter.memory_register(),
+            Operand::DelayTimer => interpreter.delay_timer() as u16,
+            Operand::SoundTimer => interpreter.sound_timer() as u16,
+            Operand::ProgramCounter => interpreter.program_counter(),
+            Operand::Memory {
+                relative_to_i,
+                offset,
+            } => {
+                let base = if relative_to_i {
+                    interpreter.memory_register()
+                } else {
+                    0
+                };
+                let address = base.wrapping_add(offset) as usize;
+                interpreter.memory().get(address).copied().unwrap_or(0) as u16
+            }
+            Operand::Number(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// Two-character operators come first, so that `<=` isn't taken for `<`.
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+// Comparisons joined by && and ||, where && binds tighter, e.g. `V3 == 0x10 && [I] != 0`.
+// Operands are V0-VF, I, DT, ST, PC, bytes of memory at [address], [I] or [I+offset], and
+// hexadecimal numbers or labels.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    text: String,
+    // Any of the groups holds when all of its comparisons do.
+    any: Vec<Vec<(Operand, Comparison, Operand)>>,
+}
+
+impl Condition {
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Condition, String> {
+        let mut any = Vec::new();
+        for group in text.split("||") {
+            let mut all = Vec::new();
+            for comparison in group.split("&&") {
+                let (left, operator, right) = COMPARISONS
+                    .iter()
+                    .find_map(|(symbol, operator)| {
+                        comparison
+                            .split_once(symbol)
+                            .map(|(left, right)| (left, *operator, right))
+                    })
+                    .ok_or_else(|| format!("Not a comparison: {}", comparison.trim()))?;
+                all.push((
+                    Operand::parse(left.trim(), symbols)?,
+                    operator,
+                    Operand::parse(right.trim(), symbols)?,
+                ));
+            }
+            any.push(all);
+        }
+
+        Ok(Condition {
+            text: text.trim().to_string(),
+            any,
+        })
+    }
+
+    pub fn holds(self: &Self, interpreter: &Interpreter) -> bool {
+        self.any.iter().any(|all| {
+            all.iter().all(|(left, operator, right)| {
+                let (left, right) = (left.value(interpreter), right.value(interpreter));
+                match operator {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            })
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+enum Entry {
+    Breakpoint {
+        address: Option<u16>,
+        condition: Option<Condition>,
+        // Whether the condition held when breakpoints were last checked.
+        held: bool,
+    },
+    Watchpoint {
+        range: RangeInclusive<u16>,
+        read: bool,
+        write: bool,
+    },
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Breakpoint {
+                address, condition, ..
+            } => {
+                write!(f, "break")?;
+                if let Some(address) = address {
+                    write!(f, " at {:03X}", address)?;
+                }
+                if let Some(condition) = condition {
+                    write!(f, " if {}", condition)?;
+                }
+                Ok(())
+            }
+            Entry::Watchpoint { range, read, write } => {
+                let access = match (read, write) {
+                    (true, true) => "reads and writes",
+                    (true, false) => "reads",
+                    _ => "writes",
+                };
+                write!(
+                    f,
+                    "watch {} of {:03X}-{:03X}",
+                    access,
+                    range.start(),
+                    range.end()
+                )
+            }
+        }
+    }
+}
+
+// Why execution stopped.
+#[derive(Debug, Clone, Copy)]
+pub enum Stop {
+    Breakpoint {
+        number: u32,
+        program_counter: u16,
+    },
+    Watchpoint {
+        number: u32,
+        access: Access,
+        address: u16,
+        // The instruction that made the access.
+        program_counter: u16,
+    },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint {
+                number,
+                program_counter,
+            } => write!(f, "Breakpoint {} hit at {:03X}", number, program_counter),
+            Stop::Watchpoint {
+                number,
+                access,
+                address,
+                program_counter,
+            } => {
+                let verb = match access {
+                    Access::Read => "read",
+                    Access::Write => "wrote to",
+                };
+                write!(
+                    f,
+                    "Watchpoint {}: {:03X} {} {:03X}",
+                    number, program_counter, verb, address
+                )
+            }
+        }
+    }
+}
+
+// Breakpoints and watchpoints, by number. Breakpoints are checked before each instruction:
+// one with an address stops before the instruction there runs if its condition holds,
+// while one with only a condition stops when the condition starts to hold. Resuming runs
+// the instruction a breakpoint stopped at. Watchpoints stop after the instruction that
+// accessed their range.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    entries: BTreeMap<u32, Entry>,
+    next_number: u32,
+}
+
+impl Breakpoints {
+    pub fn is_empty(self: &Self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn add_breakpoint(
+        self: &mut Self,
+        address: Option<u16>,
+        condition: Option<Condition>,
+    ) -> u32 {
+        self.add(Entry::Breakpoint {
+            address,
+            condition,
+            held: false,
+        })
+    }
+
+    pub fn add_watchpoint(
+        self: &mut Self,
+        range: RangeInclusive<u16>,
+        read: bool,
+        write: bool,
+    ) -> u32 {
+        self.add(Entry::Watchpoint { range, read, write })
+    }
+
+    fn add(self: &mut Self, entry: Entry) -> u32 {
+        self.next_number += 1;
+        self.entries.insert(self.next_number, entry);
+        self.next_number
+    }
+
+    pub fn remove(self: &mut Self, number: u32) -> Result<(), String> {
+        match self.entries.remove(&number) {
+            Some(..) => Ok(()),
+            None => Err(format!("No breakpoint or watchpoint {}.", number)),
+        }
+    }
+
+    // One line per breakpoint or watchpoint.
+    pub fn list(self: &Self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|(number, entry)| format!("{}: {}", number, entry))
+            .collect()
+    }
+
+    // The first breakpoint that stops before the next instruction.
+    pub fn check(self: &mut Self, interpreter: &Interpreter) -> Option<Stop> {
+        let program_counter = interpreter.program_counter();
+        let mut stop = None;
+        for (&number, entry) in &mut self.entries {
+            if let Entry::Breakpoint {
+                address,
+                condition,
+                held,
+            } = entry
+            {
+                let holds = condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.holds(interpreter));
+                let hit = match address {
+                    Some(address) => *address == program_counter && holds,
+                    None => holds && !*held,
+                };
+                *held = holds;
+                if hit && stop.is_none() {
+                    stop = Some(Stop::Breakpoint {
+                        number,
+                        program_counter,
+                    });
+                }
+            }
+        }
+        stop
+    }
+
+    // The first watchpoint that covers the access.
+    pub fn check_access(
+        self: &Self,
+        address: u16,
+        access: Access,
+        program_counter: u16,
+    ) -> Option<Stop> {
+        self.entries
+            .iter()
+            .find_map(|(&number, entry)| match entry {
+                Entry::Watchpoint { range, read, write }
+                    if range.contains(&address)
+                        && ((access == Access::Read && *read)
+                            || (access == Access::Write && *write)) =>
+                {
+                    Some(Stop::Watchpoint {
+                        number,
+                        access,
+                        address,
+                        program_counter,
+                    })
+                }
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+
+    // LD V0, 0; ADD V0, 1; JP #202
+    const COUNTER: [u8; 6] = [0x60, 0x00, 0x70, 0x01, 0x12, 0x02];
+
+    fn interpreter() -> Interpreter {
+        let mut interpreter = Interpreter::new(&COUNTER);
+        interpreter.set_trace(false);
+        interpreter
+    }
+
+    fn condition(text: &str) -> Condition {
+        Condition::parse(text, &Symbols::default()).unwrap()
+    }
+
+    // Runs until a breakpoint or watchpoint stops execution, or the frame ends.
+    fn run(interpreter: &mut Interpreter, keypad: &mut Keypad) -> Option<Stop> {
+        interpreter.run_frame(keypad, 100).unwrap();
+        interpreter.take_stop()
+    }
+
+    #[test]
+    fn comparisons_with_two_characters_win() {
+        // V0 is 0 and PC 0x200 before the first instruction.
+        let interpreter = interpreter();
+        assert!(condition("V0 <= 0").holds(&interpreter));
+        assert!(!condition("V0 < 0").holds(&interpreter));
+        assert!(condition("PC >= 200").holds(&interpreter));
+        assert!(!condition("PC > 200").holds(&interpreter));
+        assert!(condition("V0 != 1").holds(&interpreter));
+        assert!(condition("[202] == 70").holds(&interpreter));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let interpreter = interpreter();
+        // Taken as (false && false) || true, rather than false && (false || true).
+        assert!(condition("V0 == 1 && V1 == 1 || V2 == 0").holds(&interpreter));
+        assert!(!condition("V0 == 1 && V1 == 1 || V2 == 1").holds(&interpreter));
+        assert!(!condition("V0 == 0 && V1 == 1 || V2 == 1").holds(&interpreter));
+        assert!(condition("V0 == 1 || V1 == 0 && V2 == 0").holds(&interpreter));
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        for text in ["V0", "V0 = 1", "VG == 1", "V0 == 1 &&", "[I+] == 0"] {
+            assert!(
+                Condition::parse(text, &Symbols::default()).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_first_instruction_and_resume_past_it() {
+        let mut interpreter = interpreter();
+        let mut keypad = Keypad::new(0);
+        interpreter
+            .breakpoints_mut()
+            .add_breakpoint(Some(0x200), None);
+        assert!(matches!(
+            run(&mut interpreter, &mut keypad),
+            Some(Stop::Breakpoint {
+                program_counter: 0x200,
+                ..
+            })
+        ));
+        assert_eq!(interpreter.program_counter(), 0x200);
+        assert!(run(&mut interpreter, &mut keypad).is_none());
+        assert_ne!(interpreter.program_counter(), 0x200);
+    }
+
+    #[test]
+    fn breakpoints_added_at_the_current_instruction_stop_on_resume() {
+        let mut interpreter = interpreter();
+        let mut keypad = Keypad::new(0);
+        interpreter.run_frame(&mut keypad, 2).unwrap();
+        assert_eq!(interpreter.program_counter(), 0x204);
+        interpreter
+            .breakpoints_mut()
+            .add_breakpoint(Some(0x204), None);
+        assert!(run(&mut interpreter, &mut keypad).is_some());
+        assert_eq!(interpreter.program_counter(), 0x204);
+    }
+
+    #[test]
+    fn conditions_see_changes_a_watchpoint_stopped_for() {
+        let mut interpreter = interpreter();
+        let mut keypad = Keypad::new(0);
+        // LD V0, 0; ADD V0, 1; LD I, #300; LD [I], V0; JP #208
+        for (address, byte) in [0x60, 0x00, 0x70, 0x01, 0xa3, 0x00, 0xf0, 0x55, 0x12, 0x08]
+            .into_iter()
+            .enumerate()
+        {
+            interpreter
+                .set_memory(0x200 + address as u16, byte)
+                .unwrap();
+        }
+        let watchpoint = interpreter
+            .breakpoints_mut()
+            .add_watchpoint(0x300..=0x300, false, true);
+        interpreter
+            .breakpoints_mut()
+            .add_breakpoint(None, Some(condition("[300] == 1")));
+        assert!(matches!(
+            run(&mut interpreter, &mut keypad),
+            Some(Stop::Watchpoint { number, .. }) if number == watchpoint
+        ));
+        interpreter.breakpoints_mut().remove(watchpoint).unwrap();
+        assert!(run(&mut interpreter, &mut keypad).is_none());
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::disassembler;
+use crate::interpreter::{Instruction, Interpreter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    // Into the next instruction, including when a skip doesn't skip.
+    Fallthrough,
+    Jump,
+    // Over the next instruction, when a skip condition holds.
+    Skip,
+    Call,
+}
+
+// A run of instructions that is only entered at the start and only left at the end.
+#[derive(Debug)]
+pub struct Block {
+    pub start: u16,
+    // The address after the last instruction.
+    pub end: u16,
+    pub successors: Vec<(u16, EdgeKind)>,
+    pub returns: bool,
+    // Ends with a computed jump (BNNN), whose targets can't be known without running it.
+    pub unresolved: bool,
+    // Ends with something that isn't an instruction.
+    pub invalid: bool,
+}
+
+// The control-flow graph of a program, found by following every path from the entry
+// point without running anything. Calls are assumed to return to the next instruction.
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<u16, Block>,
+    // The blocks making up each subroutine, by entry point. The program's entry point
+    // counts as a subroutine too. Blocks shared by several subroutines belong to the
+    // first one found.
+    pub subroutines: BTreeMap<u16, Vec<u16>>,
+    code: Vec<bool>,
+}
+
+impl ControlFlowGraph {
+    pub fn analyse(memory: &[u8], entry: u16) -> ControlFlowGraph {
+        let in_memory = |address: u16| (address as usize) + 1 < memory.len();
+
+        // Find every reachable instruction, and where blocks have to start.
+        let mut instructions: BTreeMap<u16, Instruction> = BTreeMap::new();
+        let mut leaders = BTreeSet::from([entry]);
+        let mut subroutine_entries = BTreeSet::from([entry]);
+        let mut pending = VecDeque::from([entry]);
+        while let Some(address) = pending.pop_front() {
+            if instructions.contains_key(&address) || !in_memory(address) {
+                continue;
+            }
+            let instruction =
+                Interpreter::decode_opcode(disassembler::read_opcode(memory, address));
+            instructions.insert(address, instruction);
+
+            let (successors, _, ends_block) = successors(address, &instruction);
+            for (successor, kind) in successors {
+                if kind == EdgeKind::Call {
+                    subroutine_entries.insert(successor);
+                }
+                if ends_block {
+                    leaders.insert(successor);
+                }
+                pending.push_back(successor);
+            }
+        }
+
+        let mut code = vec![false; memory.len()];
+        for &address in instructions.keys() {
+            code[address as usize] = true;
+            code[address as usize + 1] = true;
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in &leaders {
+            if !instructions.contains_key(&start) {
+                continue;
+            }
+            let mut address = start;
+            loop {
+                let instruction = instructions[&address];
+                let (successors, flags, ends_block) = successors(address, &instruction);
+                let next = address.wrapping_add(2);
+                let block_ends =
+                    ends_block || leaders.contains(&next) || !instructions.contains_key(&next);
+                if !block_ends {
+                    address = next;
+                    continue;
+                }
+
+                let successors = if ends_block {
+                    successors
+                } else if instructions.contains_key(&next) {
+                    vec![(next, EdgeKind::Fallthrough)]
+                } else {
+                    Vec::new()
+                };
+                blocks.insert(
+                    start,
+                    Block {
+                        start,
+                        end: next,
+                        successors,
+                        returns: flags == Exit::Return,
+                        unresolved: flags == Exit::Unresolved,
+                        invalid: flags == Exit::Invalid,
+                    },
+                );
+                break;
+            }
+        }
+
+        // Subroutines are made of the blocks reachable from their entry without calls.
+        let mut owner: BTreeMap<u16, u16> = BTreeMap::new();
+        let mut subroutines = BTreeMap::new();
+        for &subroutine in &subroutine_entries {
+            let mut members = Vec::new();
+            let mut pending = VecDeque::from([subroutine]);
+            while let Some(start) = pending.pop_front() {
+                if owner.contains_key(&start) {
+                    continue;
+                }
+                let block = match blocks.get(&start) {
+                    Some(block) => block,
+                    None => continue,
+                };
+                owner.insert(start, subroutine);
+                members.push(start);
+                for &(successor, kind) in &block.successors {
+                    if kind != EdgeKind::Call {
+                        pending.push_back(successor);
+                    }
+                }
+            }
+            members.sort();
+            subroutines.insert(subroutine, members);
+        }
+
+        ControlFlowGraph {
+            blocks,
+            subroutines,
+            code,
+        }
+    }
+
+    // Whether the byte at the given address belongs to a reachable instruction.
+    pub fn is_code(self: &Self, address: u16) -> bool {
+        self.code.get(address as usize).copied().unwrap_or(false)
+    }
+
+    // The ranges of the ROM that aren't code.
+    pub fn data_ranges(self: &Self, rom: Range<u16>) -> Vec<Range<u16>> {
+        let mut ranges: Vec<Range<u16>> = Vec::new();
+        for address in rom {
+            if self.is_code(address) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == address => range.end = address + 1,
+                _ => ranges.push(address..address + 1),
+            }
+        }
+        ranges
+    }
+
+    pub fn unresolved_jumps(self: &Self) -> Vec<u16> {
+        self.blocks
+            .values()
+            .filter(|block| block.unresolved)
+            .map(|block| block.end - 2)
+            .collect()
+    }
+
+    pub fn summary(self: &Self, rom: Range<u16>) -> String {
+        let mut summary = String::new();
+        let code_bytes = rom.clone().filter(|&address| self.is_code(address)).count();
+        let _ = writeln!(
+            summary,
+            "{} blocks in {} subroutines, {} of {} ROM bytes are code.",
+            self.blocks.len(),
+            self.subroutines.len(),
+            code_bytes,
+            rom.len()
+        );
+        for range in self.data_ranges(rom) {
+            let _ = writeln!(summary, "Data: {:03X}-{:03X}", range.start, range.end - 1);
+        }
+        for address in self.unresolved_jumps() {
+            let _ = writeln!(summary, "Unresolved computed jump at {:03X}", address);
+        }
+        summary
+    }
+
+    // Graphviz DOT, with a cluster per subroutine. Calls are dashed, and computed jumps
+    // lead to a placeholder node.
+    pub fn to_dot(self: &Self, memory: &[u8]) -> String {
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph cfg {{");
+        let _ = writeln!(dot, "  node [shape=box, fontname=\"monospace\"];");
+
+        for (entry, members) in &self.subroutines {
+            let _ = writeln!(dot, "  subgraph cluster_{:03X} {{", entry);
+            let _ = writeln!(dot, "    label=\"sub {:03X}\";", entry);
+            for start in members {
+                let block = &self.blocks[start];
+                let mut label = String::new();
+                for address in (block.start..block.end).step_by(2) {
+                    let opcode = disassembler::read_opcode(memory, address);
+                    let _ = write!(
+                        label,
+                        "{:03X}  {:04X}  {}\\l",
+                        address,
+                        opcode,
+                        disassembler::disassemble(opcode)
+                    );
+                }
+                let _ = writeln!(dot, "    b{:03X} [label=\"{}\"];", start, label);
+            }
+            let _ = writeln!(dot, "  }}");
+        }
+
+        for block in self.blocks.values() {
+            for &(successor, kind) in &block.successors {
+                let attributes = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                };
+                let _ = writeln!(
+                    dot,
+                    "  b{:03X} -> b{:03X}{};",
+                    block.start, successor, attributes
+                );
+            }
+            if block.unresolved {
+                let _ = writeln!(
+                    dot,
+                    "  unresolved_{:03X} [label=\"?\", shape=diamond];",
+                    block.start
+                );
+                let _ = writeln!(
+                    dot,
+                    "  b{:03X} -> unresolved_{:03X} [label=\"computed\", style=dotted];",
+                    block.start, block.start
+                );
+            }
+        }
+
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+}
+
+#[derive(PartialEq)]
+enum Exit {
+    None,
+    Return,
+    Unresolved,
+    Invalid,
+}
+
+// Where control can go after the instruction at the given address, how it leaves the
+// code if it does, and whether the instruction has to end a block.
+fn successors(address: u16, instruction: &Instruction) -> (Vec<(u16, EdgeKind)>, Exit, bool) {
+    let next = address.wrapping_add(2);
+    match instruction {
+        Instruction::JP(target) => (vec![(*target, EdgeKind::Jump)], Exit::None, true),
+        Instruction::CALL(target) => (
+            vec![(*target, EdgeKind::Call), (next, EdgeKind::Fallthrough)],
+            Exit::None,
+            true,
+        ),
+        Instruction::RET => (Vec::new(), Exit::Return, true),
+        Instruction::JP0A(..) => (Vec::new(), Exit::Unresolved, true),
+        Instruction::INVALID => (Vec::new(), Exit::Invalid, true),
+        Instruction::SERV(..)
+        | Instruction::SNERV(..)
+        | Instruction::SERR(..)
+        | Instruction::SNERR(..)
+        | Instruction::SKP(..)
+        | Instruction::SKNP(..) => (
+            vec![
+                (next, EdgeKind::Fallthrough),
+                (next.wrapping_add(2), EdgeKind::Skip),
+            ],
+            Exit::None,
+            true,
+        ),
+        _ => (vec![(next, EdgeKind::Fallthrough)], Exit::None, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: Range<u16> = 0x200..0x214;
+
+    // The program at 0x200 in an otherwise empty memory.
+    fn memory(program: &[u8]) -> Vec<u8> {
+        let mut memory = vec![0; 0x1000];
+        memory[0x200..0x200 + program.len()].copy_from_slice(program);
+        memory
+    }
+
+    fn program() -> Vec<u8> {
+        memory(&[
+            0x60, 0x00, // 200: v0 := 0
+            0x22, 0x10, // 202: call 210
+            0x30, 0x01, // 204: if v0 == 1 skip
+            0x12, 0x04, // 206: jump 204
+            0xb3, 0x00, // 208: jump0 300
+            0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, // 20A: data
+            0x70, 0x01, // 210: v0 += 1
+            0x00, 0xee, // 212: return
+        ])
+    }
+
+    #[test]
+    fn splits_blocks_at_branches_and_their_targets() {
+        let cfg = ControlFlowGraph::analyse(&program(), 0x200);
+        let blocks: Vec<(u16, u16)> = cfg.blocks.values().map(|b| (b.start, b.end)).collect();
+        assert_eq!(
+            blocks,
+            [
+                (0x200, 0x204),
+                (0x204, 0x206),
+                (0x206, 0x208),
+                (0x208, 0x20a),
+                (0x210, 0x214)
+            ]
+        );
+
+        let block = |start| &cfg.blocks[&start];
+        assert_eq!(
+            block(0x200).successors,
+            [(0x210, EdgeKind::Call), (0x204, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(
+            block(0x204).successors,
+            [(0x206, EdgeKind::Fallthrough), (0x208, EdgeKind::Skip)]
+        );
+        assert_eq!(block(0x206).successors, [(0x204, EdgeKind::Jump)]);
+        assert!(block(0x208).successors.is_empty() && block(0x208).unresolved);
+        assert!(block(0x210).successors.is_empty() && block(0x210).returns);
+        assert!(!block(0x200).returns && !block(0x200).unresolved && !block(0x200).invalid);
+    }
+
+    #[test]
+    fn groups_blocks_by_subroutine() {
+        let cfg = ControlFlowGraph::analyse(&program(), 0x200);
+        assert_eq!(
+            cfg.subroutines,
+            BTreeMap::from([
+                (0x200, vec![0x200, 0x204, 0x206, 0x208]),
+                (0x210, vec![0x210])
+            ])
+        );
+    }
+
+    #[test]
+    fn jumps_into_straight_code_split_it() {
+        let memory = memory(&[
+            0x60, 0x00, // 200: v0 := 0
+            0x70, 0x01, // 202: v0 += 1
+            0x12, 0x02, // 204: jump 202
+        ]);
+        let cfg = ControlFlowGraph::analyse(&memory, 0x200);
+        assert_eq!(cfg.blocks.len(), 2);
+        assert_eq!(cfg.blocks[&0x200].end, 0x202);
+        assert_eq!(
+            cfg.blocks[&0x200].successors,
+            [(0x202, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(cfg.blocks[&0x202].end, 0x206);
+        assert_eq!(cfg.blocks[&0x202].successors, [(0x202, EdgeKind::Jump)]);
+    }
+
+    #[test]
+    fn finds_data_and_unresolved_jumps() {
+        let cfg = ControlFlowGraph::analyse(&program(), 0x200);
+        assert!(cfg.is_code(0x208) && cfg.is_code(0x209));
+        assert!(!cfg.is_code(0x20a) && !cfg.is_code(0x300));
+        assert_eq!(cfg.data_ranges(ROM), vec![0x20a..0x210]);
+        assert_eq!(cfg.unresolved_jumps(), [0x208]);
+        assert_eq!(
+            cfg.summary(ROM),
+            "5 blocks in 2 subroutines, 14 of 20 ROM bytes are code.\n\
+             Data: 20A-20F\n\
+             Unresolved computed jump at 208\n"
+        );
+    }
+
+    #[test]
+    fn draws_a_cluster_per_subroutine() {
+        let memory = program();
+        let dot = ControlFlowGraph::analyse(&memory, 0x200).to_dot(&memory);
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.ends_with("}\n"));
+        for line in [
+            "  subgraph cluster_200 {",
+            "  subgraph cluster_210 {",
+            "    b210 [label=\"210  7001  ADD V0, #01\\l212  00EE  RET\\l\"];",
+            "  b200 -> b210 [label=\"call\", style=dashed];",
+            "  b200 -> b204;",
+            "  b204 -> b208 [label=\"skip\"];",
+            "  b206 -> b204 [label=\"jump\"];",
+            "  b208 -> unresolved_208 [label=\"computed\", style=dotted];",
+        ] {
+            assert!(dot.contains(&format!("{}\n", line)), "missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::breakpoints::Condition;
+use crate::disassembler;
+use crate::interpreter::{Interpreter, Location};
+use crate::symbols::{parse_hex, Symbols};
+
+pub enum Command {
+    Help,
+    Pause,
+    Continue,
+    Step(u32),
+    Registers,
+    Memory { address: u16, length: u16 },
+    Write { address: u16, bytes: Vec<u8> },
+    Break { address: Option<u16>, condition: Option<Condition> },
+    Watch { start: u16, end: u16, read: bool, write: bool },
+    ListBreakpoints,
+    Delete(u32),
+    Back(u32),
+    ReverseContinue,
+    LastChange(Location),
+}
+
+// Instructions the console can step back through, unless --history says otherwise.
+pub const HISTORY_LENGTH: usize = 100_000;
+
+pub const HELP: &str = "Commands (numbers are hexadecimal, addresses can be labels):
+  pause                     pause execution
+  continue | c              resume execution
+  step | s [count]          execute instructions while paused (count is decimal)
+  regs                      print the registers
+  mem <address> [length]    print memory
+  write <address> <byte>... change memory while paused
+  break <address> [if <condition>]
+                            pause before the instruction at the address runs
+  break if <condition>      pause when the condition starts to hold, e.g.
+                            break if V3 == 0x10 && [I] != 0
+  watch <address> [length] [r|w|rw]
+                            pause after memory in the range is read or written
+  breaks                    list breakpoints and watchpoints
+  delete <number>           remove a breakpoint or watchpoint
+  back | b [count]          undo instructions while paused (count is decimal)
+  reverse-continue | rc     undo instructions until a breakpoint or watchpoint
+  last <register|address>   find the instruction that last changed V0-VF, I or memory
+  help                      show this list
+
+Conditions compare V0-VF, I, DT, ST, PC, memory at [address], [I] or [I+offset], and
+numbers with == != < <= > >=, joined by && and ||.";
+
+// Reads debugger commands from standard input. Lines are read on a separate thread so
+// that the emulator never blocks on the terminal.
+pub struct Console {
+    receiver: Receiver<String>,
+}
+
+impl Console {
+    pub fn start() -> Console {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(..) => break,
+                }
+            }
+        });
+
+        Console { receiver }
+    }
+
+    // The next command that was entered, if any. Empty lines are skipped. Labels in the
+    // symbols can be used as addresses.
+    pub fn next_command(self: &Self, symbols: &Symbols) -> Option<Result<Command, String>> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => return Some(parse(&line, symbols)),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+pub fn parse(line: &str, symbols: &Symbols) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let arguments: Vec<&str> = words.collect();
+
+    match (name, arguments.as_slice()) {
+        ("help", []) => Ok(Command::Help),
+        ("pause", []) => Ok(Command::Pause),
+        ("continue" | "c", []) => Ok(Command::Continue),
+        ("step" | "s", []) => Ok(Command::Step(1)),
+        ("step" | "s", [count]) => match count.parse() {
+            Ok(count) => Ok(Command::Step(count)),
+            Err(..) => Err(format!("Invalid step count: {}", count)),
+        },
+        ("break", [_, ..]) => parse_break(&arguments.join(" "), symbols),
+        ("watch", [address, rest @ ..]) => {
+            let (mode, length) = match rest {
+                [length, mode] => (Some(*mode), Some(*length)),
+                [mode] if matches!(*mode, "r" | "w" | "rw") => (Some(*mode), None),
+                [length] => (None, Some(*length)),
+                [] => (None, None),
+                _ => return Err("Usage: watch <address> [length] [r|w|rw]".to_string()),
+            };
+            let (read, write) = match mode {
+                None | Some("rw") => (true, true),
+                Some("r") => (true, false),
+                Some("w") => (false, true),
+                Some(mode) => return Err(format!("Not r, w or rw: {}", mode)),
+            };
+            let start = symbols.parse_address(address)?;
+            let length = match length {
+                Some(length) => parse_hex(length)?.max(1),
+                None => 1,
+            };
+            Ok(Command::Watch {
+                start,
+                end: start.saturating_add(length - 1),
+                read,
+                write,
+            })
+        }
+        ("breaks", []) => Ok(Command::ListBreakpoints),
+        ("delete", [number]) => match number.parse() {
+            Ok(number) => Ok(Command::Delete(number)),
+            Err(..) => Err(format!("Invalid breakpoint number: {}", number)),
+        },
+        ("back" | "b", []) => Ok(Command::Back(1)),
+        ("back" | "b", [count]) => match count.parse() {
+            Ok(count) => Ok(Command::Back(count)),
+            Err(..) => Err(format!("Invalid step count: {}", count)),
+        },
+        ("reverse-continue" | "rc", []) => Ok(Command::ReverseContinue),
+        ("last", [location]) => Ok(Command::LastChange(parse_location(location, symbols)?)),
+        ("regs", []) => Ok(Command::Registers),
+        ("mem", [address]) => Ok(Command::Memory {
+            address: symbols.parse_address(address)?,
+            length: 0x80,
+        }),
+        ("mem", [address, length]) => Ok(Command::Memory {
+            address: symbols.parse_address(address)?,
+            length: parse_hex(length)?,
+        }),
+        ("write", [address, bytes @ ..]) if !bytes.is_empty() => {
+            let mut values = Vec::with_capacity(bytes.len());
+            for byte in bytes {
+                match u8::try_from(parse_hex(byte)?) {
+                    Ok(value) => values.push(value),
+                    Err(..) => return Err(format!("Not a byte: {}", byte)),
+                }
+            }
+            Ok(Command::Write {
+                address: symbols.parse_address(address)?,
+                bytes: values,
+            })
+        }
+        _ => Err(format!("Unknown command: {}. Try help.", line.trim())),
+    }
+}
+
+// Either `<address> [if <condition>]` or `if <condition>`.
+fn parse_break(arguments: &str, symbols: &Symbols) -> Result<Command, String> {
+    let (address, condition) = match arguments.strip_prefix("if ") {
+        Some(condition) => (None, Some(condition)),
+        None => match arguments.split_once(" if ") {
+            Some((address, condition)) => (Some(address), Some(condition)),
+            None => (Some(arguments), None),
+        },
+    };
+    let address = match address {
+        Some(address) => Some(symbols.parse_address(address.trim())?),
+        None => None,
+    };
+    let condition = match condition {
+        Some(condition) => Some(Condition::parse(condition, symbols)?),
+        None => None,
+    };
+    Ok(Command::Break { address, condition })
+}
+
+fn parse_location(text: &str, symbols: &Symbols) -> Result<Location, String> {
+    let upper = text.to_ascii_uppercase();
+    if upper == "I" {
+        return Ok(Location::MemoryRegister);
+    }
+    if let Some(digit) = upper.strip_prefix('V') {
+        if let (1, Ok(register)) = (digit.len(), usize::from_str_radix(digit, 16)) {
+            return Ok(Location::Register(register));
+        }
+    }
+    Ok(Location::Memory(symbols.parse_address(text)?))
+}
+
+pub fn print_registers(interpreter: &Interpreter) {
+    let symbols = interpreter.symbols();
+    let label = |address: u16| match symbols.label(address) {
+        Some(label) => format!(" ({})", label),
+        None => String::new(),
+    };
+    println!(
+        "PC {:03X}{}  I {:03X}{}  DT {:02X}  ST {:02X}",
+        interpreter.program_counter(),
+        label(interpreter.program_counter()),
+        interpreter.memory_register(),
+        label(interpreter.memory_register()),
+        interpreter.delay_timer(),
+        interpreter.sound_timer()
+    );
+    let registers: Vec<String> = interpreter
+        .registers()
+        .iter()
+        .enumerate()
+        .map(|(register, value)| format!("V{:X} {:02X}", register, value))
+        .collect();
+    println!("{}", registers[..8].join("  "));
+    println!("{}", registers[8..].join("  "));
+    let stack: Vec<String> = interpreter
+        .stack()
+        .iter()
+        .map(|&address| format!("{:03X}{}", address, label(address)))
+        .collect();
+    println!("Stack: {}", stack.join(" "));
+}
+
+pub fn print_memory(interpreter: &Interpreter, address: u16, length: u16) {
+    let memory = interpreter.memory();
+    let start = address as usize;
+    let end = (start + length as usize).min(memory.len());
+    for row_start in (start..end).step_by(16) {
+        let row: Vec<String> = memory[row_start..(row_start + 16).min(end)]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        println!("{:03X}: {}", row_start, row.join(" "));
+    }
+}
+
+// The instruction that runs next.
+pub fn print_instruction(interpreter: &Interpreter) {
+    let program_counter = interpreter.program_counter();
+    println!(
+        "{:03X}: {}",
+        program_counter,
+        disassembler::disassemble_at(interpreter.memory(), program_counter, interpreter.symbols())
+    );
+}
+
+pub fn print_last_change(interpreter: &Interpreter, location: Location) {
+    match interpreter.last_change(location) {
+        Some(change) => println!(
+            "Last changed by {:03X}: {}, {} instructions ago",
+            change.program_counter,
+            disassembler::disassemble_with_symbols(change.opcode, interpreter.symbols()),
+            change.instructions_ago
+        ),
+        None => println!("Not changed within the history."),
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::access::AccessCounts;
+use crate::disassembler;
+
+// Data bytes listed per line.
+const DATA_PER_LINE: usize = 8;
+
+// Lists the ROM with what happened to every byte: `X` for instructions that were executed,
+// `D` for data that was read through I (sprites, FX65), `W` for bytes that were only
+// written (FX33, FX55), e.g. variables, and `.` for bytes that were never touched, shown as
+// instructions in case they are code that didn't run. Executed instructions also show how
+// many times they ran.
+pub fn report(memory: &[u8], rom: Range<u16>, access_counts: &AccessCounts) -> String {
+    let start = rom.start as usize;
+    let end = (rom.end as usize).min(memory.len());
+    let usage = |address: usize| {
+        if access_counts.executes[address] > 0 {
+            'X'
+        } else if access_counts.reads[address] > 0 {
+            'D'
+        } else if access_counts.writes[address] > 0 {
+            'W'
+        } else {
+            '.'
+        }
+    };
+
+    let count = |kind: char| {
+        (start..end)
+            .filter(|&address| usage(address) == kind)
+            .count()
+    };
+    let total = (end - start).max(1);
+    let percent = |bytes: usize| 100.0 * bytes as f64 / total as f64;
+    let (executed_bytes, data_bytes, written_bytes, untouched_bytes) =
+        (count('X'), count('D'), count('W'), count('.'));
+
+    let mut report = String::new();
+    let _ = writeln!(
+        report,
+        "ROM {:03X}-{:03X}: {} bytes executed ({:.1}%), {} bytes read as data ({:.1}%), {} bytes only written ({:.1}%), {} bytes untouched ({:.1}%).\n",
+        start,
+        end.saturating_sub(1),
+        executed_bytes,
+        percent(executed_bytes),
+        data_bytes,
+        percent(data_bytes),
+        written_bytes,
+        percent(written_bytes),
+        untouched_bytes,
+        percent(untouched_bytes)
+    );
+
+    let mut address = start;
+    while address < end {
+        let kind = usage(address);
+        if kind == 'X' {
+            let opcode = disassembler::read_opcode(memory, address as u16);
+            let _ = writeln!(
+                report,
+                "X {:03X}  {:04X}  {:<16} {:>10}",
+                address,
+                opcode,
+                disassembler::disassemble(opcode),
+                access_counts.executes[address]
+            );
+            address += 2;
+        } else if kind == 'D' || kind == 'W' {
+            let mut bytes = Vec::new();
+            while address < end && usage(address) == kind {
+                bytes.push(format!("{:02X}", memory[address]));
+                address += 1;
+                if bytes.len() == DATA_PER_LINE {
+                    break;
+                }
+            }
+            let _ = writeln!(
+                report,
+                "{} {:03X}  {}",
+                kind,
+                address - bytes.len(),
+                bytes.join(" ")
+            );
+        } else if address + 1 < end && usage(address + 1) == '.' {
+            let opcode = disassembler::read_opcode(memory, address as u16);
+            let _ = writeln!(
+                report,
+                ". {:03X}  {:04X}  {}",
+                address,
+                opcode,
+                disassembler::disassemble(opcode)
+            );
+            address += 2;
+        } else {
+            let _ = writeln!(report, ". {:03X}  {:02X}", address, memory[address]);
+            address += 1;
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_bytes_by_how_they_were_used() {
+        let mut memory = vec![0; 0x1000];
+        // LD I, #208; DRW V0, V0, 1; LD B, V0; JP #206; sprite; BCD digits; unused
+        let rom = [
+            0xa2, 0x08, 0xd0, 0x01, 0xf0, 0x33, 0x12, 0x06, 0x80, 0x00, 0x00, 0x00, 0x12, 0x34,
+        ];
+        memory[0x200..0x200 + rom.len()].copy_from_slice(&rom);
+        let mut access_counts = AccessCounts::new(memory.len());
+        for address in 0x200..0x208 {
+            access_counts.execute(address);
+        }
+        access_counts.read(0x208);
+        for address in 0x209..0x20c {
+            access_counts.write(address, 0x204);
+        }
+
+        let report = report(&memory, 0x200..0x20e, &access_counts);
+        assert!(report.starts_with(
+            "ROM 200-20D: 8 bytes executed (57.1%), 1 bytes read as data (7.1%), 3 bytes only written (21.4%), 2 bytes untouched (14.3%).\n"
+        ));
+        assert!(report.contains("\nX 200  A208  LD I, #208 "));
+        assert!(report.contains("\nD 208  80\nW 209  00 00 00\n. 20C  1234  "));
+    }
+
+    #[test]
+    fn bytes_read_and_written_count_as_data() {
+        let memory = vec![0; 0x1000];
+        let mut access_counts = AccessCounts::new(memory.len());
+        access_counts.write(0x200, 0x300);
+        access_counts.read(0x200);
+        let report = report(&memory, 0x200..0x201, &access_counts);
+        assert!(report.ends_with("\nD 200  00\n"), "{}", report);
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::cfg::ControlFlowGraph;
+use crate::disassembler;
+use crate::interpreter::{Instruction, Interpreter, Quirks};
+
+// Data bytes listed per line.
+const DATA_PER_LINE: usize = 16;
+
+// A skip's condition. The instruction after a skip runs when the condition doesn't hold.
+struct Condition {
+    holds: String,
+    fails: String,
+}
+
+// Turns every subroutine found by the control-flow analysis into a function of structured
+// pseudocode. Backward jumps become loops, skips over a single instruction become ifs, and
+// skips over a forward jump become if/else blocks. Jumps that fit none of these become
+// gotos, or tail calls when they go to another function's entry. Quirks decide what
+// shifts and computed jumps do.
+pub fn decompile(
+    memory: &[u8],
+    graph: &ControlFlowGraph,
+    rom: Range<u16>,
+    quirks: &Quirks,
+) -> String {
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "// Decompiled from the ROM at {:03X}-{:03X}.",
+        rom.start,
+        rom.end.saturating_sub(1)
+    );
+
+    // The function each instruction belongs to.
+    let mut owners = BTreeMap::new();
+    let mut functions = Vec::new();
+    for (entry, members) in &graph.subroutines {
+        let mut instructions = BTreeSet::new();
+        for start in members {
+            let block = &graph.blocks[start];
+            instructions.extend((block.start..block.end).step_by(2));
+        }
+        for &address in &instructions {
+            owners.insert(address, *entry);
+        }
+        functions.push((*entry, instructions));
+    }
+
+    // The first pass finds the gotos, so that the second one can put labels in, including
+    // for gotos coming from other functions.
+    let mut labels: BTreeMap<u16, BTreeSet<u16>> = BTreeMap::new();
+    for (entry, instructions) in &functions {
+        let (first, last) = match (instructions.first(), instructions.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            // Called, but its code was found to belong to another function.
+            _ => {
+                if let Some(owner) = owners.get(entry) {
+                    labels.entry(*owner).or_default().insert(*entry);
+                }
+                continue;
+            }
+        };
+        let mut function = Function::new(memory, instructions, &owners, quirks);
+        function.range(first, last + 2, 1, None, None);
+        if first != *entry {
+            function.gotos.insert(*entry);
+        }
+        for target in function.gotos {
+            if let Some(owner) = owners.get(&target) {
+                labels.entry(*owner).or_default().insert(target);
+            }
+        }
+    }
+
+    for (entry, instructions) in &functions {
+        let _ = writeln!(output, "\nfn {}() {{", function_name(*entry));
+        let (first, last) = match (instructions.first(), instructions.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => {
+                let mut function = Function::new(memory, instructions, &owners, quirks);
+                let goto = function.goto(*entry);
+                let _ = writeln!(output, "    {}", goto);
+                let _ = writeln!(output, "}}");
+                continue;
+            }
+        };
+        let mut function = Function::new(memory, instructions, &owners, quirks);
+        function.labels = labels.remove(entry).unwrap_or_default();
+        if first != *entry {
+            let _ = writeln!(output, "    goto label_{:03X};", entry);
+        }
+        function.range(first, last + 2, 1, None, None);
+        output.push_str(&function.output);
+        let _ = writeln!(output, "}}");
+    }
+
+    let data_ranges = graph.data_ranges(rom);
+    if !data_ranges.is_empty() {
+        let _ = writeln!(output, "\n// Data");
+    }
+    for range in data_ranges {
+        for line_start in range.clone().step_by(DATA_PER_LINE) {
+            let line_end = (line_start as usize + DATA_PER_LINE).min(range.end as usize);
+            let bytes: Vec<String> = memory[line_start as usize..line_end]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            let _ = writeln!(output, "// {:03X}: {}", line_start, bytes.join(" "));
+        }
+    }
+
+    output
+}
+
+fn function_name(address: u16) -> String {
+    if address == 0x200 {
+        "main".to_string()
+    } else {
+        format!("sub_{:03X}", address)
+    }
+}
+
+struct Function<'a> {
+    memory: &'a [u8],
+    // The addresses of the instructions belonging to the function.
+    instructions: &'a BTreeSet<u16>,
+    // The function each instruction of the program belongs to, by the function's entry.
+    owners: &'a BTreeMap<u16, u16>,
+    quirks: &'a Quirks,
+    labels: BTreeSet<u16>,
+    gotos: BTreeSet<u16>,
+    output: String,
+}
+
+impl<'a> Function<'a> {
+    fn new(
+        memory: &'a [u8],
+        instructions: &'a BTreeSet<u16>,
+        owners: &'a BTreeMap<u16, u16>,
+        quirks: &'a Quirks,
+    ) -> Function<'a> {
+        Function {
+            memory,
+            instructions,
+            owners,
+            quirks,
+            labels: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+            output: String::new(),
+        }
+    }
+
+    fn decode(self: &Self, address: u16) -> Instruction {
+        Interpreter::decode_opcode(disassembler::read_opcode(self.memory, address))
+    }
+
+    fn jumps_to(self: &Self, source: u16, target: u16) -> bool {
+        matches!(self.decode(source), Instruction::JP(address) if address == target)
+    }
+
+    fn line(self: &mut Self, depth: usize, text: &str) {
+        let _ = writeln!(self.output, "{}{}", "    ".repeat(depth), text);
+    }
+
+    // Emits the instructions in [start, end). `innermost_loop` is the start and end of the
+    // loop being emitted, if any, for breaks and continues. A loop starting at
+    // `loop_started` is already open.
+    fn range(
+        self: &mut Self,
+        start: u16,
+        end: u16,
+        depth: usize,
+        innermost_loop: Option<(u16, u16)>,
+        loop_started: Option<u16>,
+    ) {
+        let mut address = start;
+        while address < end {
+            if !self.instructions.contains(&address) {
+                address += 1;
+                continue;
+            }
+            if self.labels.contains(&address) && loop_started != Some(address) {
+                self.line(depth.saturating_sub(1), &format!("label_{:03X}:", address));
+            }
+
+            // The last backward jump to here closes the loop. Any others are continues.
+            if loop_started != Some(address) {
+                let back_jump = self
+                    .instructions
+                    .range(address..end)
+                    .copied()
+                    .rev()
+                    .find(|&source| self.jumps_to(source, address));
+                if let Some(back_jump) = back_jump {
+                    self.line(depth, "loop {");
+                    let body_loop = Some((address, back_jump + 2));
+                    self.range(address, back_jump, depth + 1, body_loop, Some(address));
+                    self.line(depth, "}");
+                    address = back_jump + 2;
+                    continue;
+                }
+            }
+
+            let instruction = self.decode(address);
+            if let Some(condition) = self.condition(&instruction) {
+                address = self.skip(address, end, &condition, depth, innermost_loop);
+                continue;
+            }
+
+            let statement = self.statement(&instruction, innermost_loop);
+            self.line(depth, &statement);
+            address += 2;
+        }
+    }
+
+    // Emits a skip and whatever it skips over. Returns the address to continue from.
+    fn skip(
+        self: &mut Self,
+        address: u16,
+        end: u16,
+        condition: &Condition,
+        depth: usize,
+        innermost_loop: Option<(u16, u16)>,
+    ) -> u16 {
+        let next = address + 2;
+        let after = address + 4;
+        if next >= end || !self.instructions.contains(&next) {
+            let goto = self.goto(after);
+            self.line(depth, &format!("if {} {{ {} }}", condition.holds, goto));
+            return next;
+        }
+
+        let next_instruction = self.decode(next);
+        match next_instruction {
+            // The jump is skipped when the condition holds, so the code between the jump
+            // and its target runs only then.
+            Instruction::JP(target)
+                if target > after
+                    && target <= end
+                    && !self.is_loop_exit(target, innermost_loop) =>
+            {
+                let else_jump = target - 2;
+                let else_end = match self.decode(else_jump) {
+                    Instruction::JP(else_end)
+                        if else_jump > after
+                            && self.instructions.contains(&else_jump)
+                            && else_end > target
+                            && else_end <= end
+                            && !self.is_loop_exit(else_end, innermost_loop) =>
+                    {
+                        Some(else_end)
+                    }
+                    _ => None,
+                };
+
+                self.line(depth, &format!("if {} {{", condition.holds));
+                match else_end {
+                    Some(else_end) => {
+                        self.range(after, else_jump, depth + 1, innermost_loop, None);
+                        self.line(depth, "} else {");
+                        self.range(target, else_end, depth + 1, innermost_loop, None);
+                        self.line(depth, "}");
+                        else_end
+                    }
+                    None => {
+                        self.range(after, target, depth + 1, innermost_loop, None);
+                        self.line(depth, "}");
+                        target
+                    }
+                }
+            }
+            _ if self.condition(&next_instruction).is_some() => {
+                let goto = self.goto(after);
+                self.line(depth, &format!("if {} {{ {} }}", condition.holds, goto));
+                next
+            }
+            _ => {
+                let statement = self.statement(&next_instruction, innermost_loop);
+                self.line(
+                    depth,
+                    &format!("if {} {{ {} }}", condition.fails, statement),
+                );
+                after
+            }
+        }
+    }
+
+    fn is_loop_exit(self: &Self, target: u16, innermost_loop: Option<(u16, u16)>) -> bool {
+        match innermost_loop {
+            Some((start, end)) => target == start || target == end,
+            None => false,
+        }
+    }
+
+    // A jump within the function becomes a goto, and one to the entry of another function
+    // a tail call. Any other jump out of the function, e.g. into code shared with another
+    // subroutine, becomes a goto to a label in the function owning the code.
+    fn goto(self: &mut Self, target: u16) -> String {
+        if self.instructions.contains(&target) {
+            self.gotos.insert(target);
+            return format!("goto label_{:03X};", target);
+        }
+        match self.owners.get(&target) {
+            Some(&owner) if owner == target => {
+                format!("{}(); return; // tail call", function_name(target))
+            }
+            Some(&owner) => {
+                self.gotos.insert(target);
+                format!("goto label_{:03X}; // in {}", target, function_name(owner))
+            }
+            None => format!("jump(0x{:03X});", target),
+        }
+    }
+
+    fn condition(self: &Self, instruction: &Instruction) -> Option<Condition> {
+        let (holds, fails) = match *instruction {
+            Instruction::SERV(x, value) => (
+                format!("v{:X} == 0x{:02X}", x, value),
+                format!("v{:X} != 0x{:02X}", x, value),
+            ),
+            Instruction::SNERV(x, value) => (
+                format!("v{:X} != 0x{:02X}", x, value),
+                format!("v{:X} == 0x{:02X}", x, value),
+            ),
+            Instruction::SERR(x, y) => (
+                format!("v{:X} == v{:X}", x, y),
+                format!("v{:X} != v{:X}", x, y),
+            ),
+            Instruction::SNERR(x, y) => (
+                format!("v{:X} != v{:X}", x, y),
+                format!("v{:X} == v{:X}", x, y),
+            ),
+            Instruction::SKP(x) => (
+                format!("key_down(v{:X})", x),
+                format!("!key_down(v{:X})", x),
+            ),
+            Instruction::SKNP(x) => (
+                format!("!key_down(v{:X})", x),
+                format!("key_down(v{:X})", x),
+            ),
+            _ => return None,
+        };
+        Some(Condition { holds, fails })
+    }
+
+    fn statement(
+        self: &mut Self,
+        instruction: &Instruction,
+        innermost_loop: Option<(u16, u16)>,
+    ) -> String {
+        let shift_source = |x: usize, y: usize| if self.quirks.shift { x } else { y };
+        match *instruction {
+            Instruction::INVALID => "invalid();".to_string(),
+            Instruction::SYS => "sys();".to_string(),
+            Instruction::CLS => "clear_screen();".to_string(),
+            Instruction::RET => "return;".to_string(),
+            Instruction::JP(target) => match innermost_loop {
+                Some((_, end)) if target == end => "break;".to_string(),
+                Some((start, _)) if target == start => "continue;".to_string(),
+                _ => self.goto(target),
+            },
+            Instruction::CALL(target) => format!("{}();", function_name(target)),
+            Instruction::LDRV(x, value) => format!("v{:X} = 0x{:02X};", x, value),
+            Instruction::ADDRV(x, value) => format!("v{:X} += 0x{:02X};", x, value),
+            Instruction::LDRR(x, y) => format!("v{:X} = v{:X};", x, y),
+            Instruction::ORRR(x, y) => format!("v{:X} |= v{:X};", x, y),
+            Instruction::ANDRR(x, y) => format!("v{:X} &= v{:X};", x, y),
+            Instruction::XORRR(x, y) => format!("v{:X} ^= v{:X};", x, y),
+            Instruction::ADDRR(x, y) => format!("v{:X} += v{:X}; // vF = carry", x, y),
+            Instruction::SUBRR(x, y) => format!("v{:X} -= v{:X}; // vF = no borrow", x, y),
+            Instruction::SUBN(x, y) => {
+                format!("v{:X} = v{:X} - v{:X}; // vF = no borrow", x, y, x)
+            }
+            Instruction::SHR(x, y) => format!(
+                "v{:X} = v{:X} >> 1; // vF = bit shifted out",
+                x,
+                shift_source(x, y)
+            ),
+            Instruction::SHL(x, y) => format!(
+                "v{:X} = v{:X} << 1; // vF = bit shifted out",
+                x,
+                shift_source(x, y)
+            ),
+            Instruction::LDI(address) => format!("i = 0x{:03X};", address),
+            Instruction::JP0A(address) => {
+                let register = if self.quirks.jump {
+                    (address >> 8) & 0xf
+                } else {
+                    0
+                };
+                format!("jump(0x{:03X} + v{:X});", address, register)
+            }
+            Instruction::RND(x, value) => format!("v{:X} = random() & 0x{:02X};", x, value),
+            Instruction::DRW(x, y, n) => format!("vF = draw(v{:X}, v{:X}, {});", x, y, n),
+            Instruction::LDRDT(x) => format!("v{:X} = dt;", x),
+            Instruction::LDRK(x) => format!("v{:X} = wait_key();", x),
+            Instruction::LDDTR(x) => format!("dt = v{:X};", x),
+            Instruction::LDSTR(x) => format!("st = v{:X};", x),
+            Instruction::ADDI(x) => format!("i += v{:X};", x),
+            Instruction::LDF(x) => format!("i = font(v{:X});", x),
+            Instruction::LDB(x) => format!("memory[i..i + 3] = bcd(v{:X});", x),
+            Instruction::LDIR(x) => format!("memory[i..=i + {}] = v0..=v{:X};", x, x),
+            Instruction::LDRI(x) => format!("v0..=v{:X} = memory[i..=i + {}];", x, x),
+            // Skips are handled by `skip`.
+            Instruction::SERV(..)
+            | Instruction::SNERV(..)
+            | Instruction::SERR(..)
+            | Instruction::SNERR(..)
+            | Instruction::SKP(..)
+            | Instruction::SKNP(..) => "skip();".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompile_program(program: &[u8]) -> String {
+        let mut memory = vec![0; 0x1000];
+        memory[0x200..0x200 + program.len()].copy_from_slice(program);
+        let graph = ControlFlowGraph::analyse(&memory, 0x200);
+        let rom = 0x200..0x200 + program.len() as u16;
+        decompile(&memory, &graph, rom, &Quirks::default())
+    }
+
+    #[test]
+    fn backward_jumps_become_loops() {
+        // LD V0, 0; ADD V0, 1; SNE V0, 10; JP #20A; JP #202; JP #20A
+        let output = decompile_program(&[
+            0x60, 0x00, 0x70, 0x01, 0x40, 0x0a, 0x12, 0x0a, 0x12, 0x02, 0x12, 0x0a,
+        ]);
+        assert!(output.contains(
+            "    loop {\n        v0 += 0x01;\n        if v0 == 0x0A { break; }\n    }\n"
+        ));
+    }
+
+    #[test]
+    fn skips_over_jumps_become_if_else() {
+        // LD V0, 0; SE V0, 1; JP #20A; LD V1, 1; JP #20C; LD V1, 2; JP #20C
+        let output = decompile_program(&[
+            0x60, 0x00, 0x30, 0x01, 0x12, 0x0a, 0x61, 0x01, 0x12, 0x0c, 0x61, 0x02, 0x12, 0x0c,
+        ]);
+        assert!(output.contains(
+            "    if v0 == 0x01 {\n        v1 = 0x01;\n    } else {\n        v1 = 0x02;\n    }\n"
+        ));
+    }
+
+    #[test]
+    fn entry_gets_a_label_when_code_comes_before_it() {
+        // CALL #206; JP #202; RET; LD V1, 1; JP #204
+        let output =
+            decompile_program(&[0x22, 0x06, 0x12, 0x02, 0x00, 0xee, 0x61, 0x01, 0x12, 0x04]);
+        assert!(output.contains("fn sub_206() {\n    goto label_206;\n"));
+        assert!(output.contains("    label_206:\n"));
+    }
+
+    // Every function called, including by tail calls, has to be defined.
+    fn assert_calls_defined(output: &str) {
+        for (index, _) in output.match_indices("sub_") {
+            let name = &output[index..index + 7];
+            if output[index + 7..].starts_with("()") {
+                assert!(output.contains(&format!("fn {}()", name)), "{}", output);
+            }
+        }
+    }
+
+    #[test]
+    fn jumps_to_another_entry_become_tail_calls() {
+        // CALL #206; CALL #20A; JP #204; LD V1, 1; RET; LD V2, 2; JP #206
+        let output = decompile_program(&[
+            0x22, 0x06, 0x22, 0x0a, 0x12, 0x04, 0x61, 0x01, 0x00, 0xee, 0x62, 0x02, 0x12, 0x06,
+        ]);
+        assert!(output.contains(
+            "    v2 = 0x02;
+    sub_206(); return; // tail call
+}"
+        ));
+        assert!(!output.contains("label_206"));
+        assert_calls_defined(&output);
+    }
+
+    #[test]
+    fn jumps_into_another_function_become_gotos_to_its_labels() {
+        // CALL #206; CALL #20A; JP #204; LD V1, 1; RET; JP #208
+        let output = decompile_program(&[
+            0x22, 0x06, 0x22, 0x0a, 0x12, 0x04, 0x61, 0x01, 0x00, 0xee, 0x12, 0x08,
+        ]);
+        assert!(output.contains(
+            "fn sub_20A() {
+    goto label_208; // in sub_206
+}"
+        ));
+        assert!(output.contains(
+            "fn sub_206() {
+    v1 = 0x01;
+label_208:
+    return;
+}"
+        ));
+        assert!(!output.contains("sub_208"));
+        assert_calls_defined(&output);
+    }
+
+    #[test]
+    fn calls_into_another_function_get_a_function() {
+        // CALL #204; LD V1, 1; LD V2, 2; RET
+        let output = decompile_program(&[0x22, 0x04, 0x61, 0x01, 0x62, 0x02, 0x00, 0xee]);
+        assert!(output.contains("fn sub_204() {\n    goto label_204; // in main\n}"));
+        assert!(output.contains("label_204:\n    v2 = 0x02;"));
+        assert_calls_defined(&output);
+    }
+}
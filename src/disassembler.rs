@@ -0,0 +1,66 @@
+use crate::interpreter::{Instruction, Interpreter};
+use crate::symbols::Symbols;
+
+// Reads the big-endian opcode at the given address. Bytes past the end of memory read as 0.
+pub fn read_opcode(memory: &[u8], address: u16) -> u16 {
+    let byte = |address: usize| memory.get(address).copied().unwrap_or(0) as u16;
+    (byte(address as usize) << 8) | byte(address as usize + 1)
+}
+
+// Mnemonics follow http://devernay.free.fr/hacks/chip8/C8TECH10.HTM, with hexadecimal
+// constants written as #NN. Anything that isn't an instruction is shown as data.
+pub fn disassemble(opcode: u16) -> String {
+    disassemble_with_symbols(opcode, &Symbols::default())
+}
+
+// Like `disassemble`, with the instruction at the given address shown as bytes when it is
+// in a data region of the symbols.
+pub fn disassemble_at(memory: &[u8], address: u16, symbols: &Symbols) -> String {
+    let opcode = read_opcode(memory, address);
+    match symbols.data_region(address) {
+        Some(..) => format!("DB #{:02X}, #{:02X}", opcode >> 8, opcode & 0xff),
+        None => disassemble_with_symbols(opcode, symbols),
+    }
+}
+
+// Addresses that have a label are shown as the label.
+pub fn disassemble_with_symbols(opcode: u16, symbols: &Symbols) -> String {
+    match Interpreter::decode_opcode(opcode) {
+        Instruction::INVALID => format!("DW #{:04X}", opcode),
+        Instruction::SYS => format!("SYS #{:03X}", opcode & 0xfff),
+        Instruction::CLS => "CLS".to_string(),
+        Instruction::RET => "RET".to_string(),
+        Instruction::JP(address) => format!("JP {}", symbols.name(address)),
+        Instruction::CALL(address) => format!("CALL {}", symbols.name(address)),
+        Instruction::SERV(x, value) => format!("SE V{:X}, #{:02X}", x, value),
+        Instruction::SNERV(x, value) => format!("SNE V{:X}, #{:02X}", x, value),
+        Instruction::SERR(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        Instruction::LDRV(x, value) => format!("LD V{:X}, #{:02X}", x, value),
+        Instruction::ADDRV(x, value) => format!("ADD V{:X}, #{:02X}", x, value),
+        Instruction::LDRR(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Instruction::ORRR(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        Instruction::ANDRR(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Instruction::XORRR(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Instruction::ADDRR(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Instruction::SUBRR(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Instruction::SHR(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Instruction::SUBN(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Instruction::SHL(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        Instruction::SNERR(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Instruction::LDI(address) => format!("LD I, {}", symbols.name(address)),
+        Instruction::JP0A(address) => format!("JP V0, {}", symbols.name(address)),
+        Instruction::RND(x, value) => format!("RND V{:X}, #{:02X}", x, value),
+        Instruction::DRW(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Instruction::SKP(x) => format!("SKP V{:X}", x),
+        Instruction::SKNP(x) => format!("SKNP V{:X}", x),
+        Instruction::LDRDT(x) => format!("LD V{:X}, DT", x),
+        Instruction::LDRK(x) => format!("LD V{:X}, K", x),
+        Instruction::LDDTR(x) => format!("LD DT, V{:X}", x),
+        Instruction::LDSTR(x) => format!("LD ST, V{:X}", x),
+        Instruction::ADDI(x) => format!("ADD I, V{:X}", x),
+        Instruction::LDF(x) => format!("LD F, V{:X}", x),
+        Instruction::LDB(x) => format!("LD B, V{:X}", x),
+        Instruction::LDIR(x) => format!("LD [I], V{:X}", x),
+        Instruction::LDRI(x) => format!("LD V{:X}, [I]", x),
+    }
+}
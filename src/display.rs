@@ -1,18 +1,80 @@
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
-use sdl2::render::WindowCanvas;
+use sdl2::render::{BlendMode, Texture, WindowCanvas};
+use sdl2::video::FullscreenType;
 
-pub struct Display {
-    pixels: [[Rect; 32]; 64],
+use crate::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::image::{self, Image};
+use crate::interpreter::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::overlay::{Highlight, Line, Overlay};
+use crate::palette::Palette;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaling {
+    // Largest whole multiple of the CHIP-8 resolution that fits the window.
+    Integer,
+    // Fills the window as much as possible while keeping the aspect ratio.
+    Aspect,
+}
+
+impl Scaling {
+    pub fn from_name(name: &str) -> Result<Scaling, String> {
+        match name {
+            "integer" => Ok(Scaling::Integer),
+            "aspect" => Ok(Scaling::Aspect),
+            _ => Err(format!(
+                "Unknown scaling {}, expected integer or aspect.",
+                name
+            )),
+        }
+    }
+}
+
+// Optional filter against the flicker caused by games erasing and redrawing their sprites.
+// It only changes what is shown, never the emulated framebuffer.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Persistence {
+    Off,
+    // Lit pixels fade out instead of going dark at once. The value is the share of its
+    // brightness a pixel keeps each frame, from 0 (no persistence) up to but excluding 1.
+    Decay(f32),
+    // A pixel is shown lit if it was lit in the current or the previous frame.
+    TwoFrames,
+}
+
+// The window the screen is shown in, and the texture the framebuffer is rendered into.
+struct Output {
     canvas: WindowCanvas,
+    texture: Texture,
+}
+
+// The framebuffer is rendered into a streaming texture at CHIP-8 resolution, which is then
+// stretched over the window. Whatever part of the window the screen doesn't cover is
+// letterboxed.
+//
+// A null display has no window and shows nothing, but still keeps track of the screen so
+// that screenshots and recordings work. It is what the frontend falls back to without a
+// working video device.
+pub struct Display {
+    output: Option<Output>,
+    framebuffer: [u8; 256],
+    previous_framebuffer: [u8; 256],
+    // Brightness of each pixel between 0 and 1, for Persistence::Decay.
+    intensity: [f32; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+    persistence: Persistence,
+    palette: Palette,
+    rotation: u32,
+    scaling: Scaling,
+    overlay: Option<Overlay>,
 }
 
 impl Display {
     pub fn new(sdl_context: &sdl2::Sdl) -> Result<Display, String> {
         let video_subsystem = sdl_context.video()?;
         let window = match video_subsystem
-            .window("CHIP-8 emulator", 640, 320)
+            .window("CHIP-8 emulator", SCREEN_WIDTH * 10, SCREEN_HEIGHT * 10)
             .position_centered()
+            .resizable()
             .build()
         {
             Ok(window) => window,
@@ -22,43 +84,382 @@ impl Display {
             Ok(canvas) => canvas,
             Err(err) => return Err(err.to_string()),
         };
-
-        let mut display = Display {
-            pixels: [[Rect::new(0, 0, 10, 10); 32]; 64],
-            canvas,
+        let texture = match canvas.texture_creator().create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+        ) {
+            Ok(texture) => texture,
+            Err(err) => return Err(err.to_string()),
         };
 
-        // Initialize pixel positions.
-        for i in 0..display.pixels.len() {
-            for j in 0..display.pixels[i].len() {
-                let pixel = &mut display.pixels[i][j];
-                pixel.set_x((i * 10) as i32);
-                pixel.set_y((j * 10) as i32);
+        let mut display = Display::null();
+        display.output = Some(Output { canvas, texture });
+        display.update_texture()?;
+
+        Ok(display)
+    }
+
+    pub fn null() -> Display {
+        Display {
+            output: None,
+            framebuffer: [0; 256],
+            previous_framebuffer: [0; 256],
+            intensity: [0.0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+            persistence: Persistence::Off,
+            palette: Palette::default(),
+            rotation: 0,
+            scaling: Scaling::Integer,
+            overlay: None,
+        }
+    }
+
+    pub fn set_palette(self: &mut Self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn palette(self: &Self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_persistence(self: &mut Self, persistence: Persistence) {
+        self.persistence = persistence;
+    }
+
+    pub fn set_scaling(self: &mut Self, scaling: Scaling) {
+        self.scaling = scaling;
+    }
+
+    // Rotates the screen clockwise by the given number of degrees (a multiple of 90).
+    pub fn set_rotation(self: &mut Self, degrees: u32) -> Result<(), String> {
+        let (width, height) = match degrees {
+            0 | 180 => (SCREEN_WIDTH * 10, SCREEN_HEIGHT * 10),
+            90 | 270 => (SCREEN_HEIGHT * 10, SCREEN_WIDTH * 10),
+            _ => return Err(format!("Unsupported screen rotation: {}.", degrees)),
+        };
+        if let Some(output) = &mut self.output {
+            if let Err(err) = output.canvas.window_mut().set_size(width, height) {
+                return Err(err.to_string());
             }
         }
+        self.rotation = degrees;
 
-        Ok(display)
+        Ok(())
+    }
+
+    pub fn toggle_fullscreen(self: &mut Self) -> Result<(), String> {
+        let window = match &mut self.output {
+            Some(output) => output.canvas.window_mut(),
+            None => return Ok(()),
+        };
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(fullscreen)
     }
 
+    // Shown over the screen until replaced, or removed with None.
+    pub fn set_overlay(self: &mut Self, overlay: Option<Overlay>) {
+        self.overlay = overlay;
+    }
+
+    // The texture is only updated when presenting, so that the persistence filter works
+    // on displayed frames however many times the framebuffer changes in between.
     pub fn set_pixels(self: &mut Self, framebuffer: &[u8; 256]) {
-        self.canvas.set_draw_color(Color::RGB(0x22, 0x22, 0x22));
-        self.canvas.clear();
-
-        for col_byte in 0..8 {
-            for row in 0..32 {
-                let fb_byte = framebuffer[col_byte + row * 8];
-                for pixel_x in 0..8 {
-                    let pixel = self.pixels[col_byte * 8 + pixel_x][row];
-                    if fb_byte.wrapping_shr(7 - pixel_x as u32) & 1 == 1 {
-                        self.canvas.set_draw_color(Color::RGB(0, 0xcc, 0x11));
-                        self.canvas.fill_rect(pixel).unwrap();
+        self.framebuffer = *framebuffer;
+    }
+
+    fn update_texture(self: &mut Self) -> Result<(), String> {
+        let texture = match &mut self.output {
+            Some(output) => &mut output.texture,
+            None => return Ok(()),
+        };
+        let framebuffer = &self.framebuffer;
+        let previous_framebuffer = &self.previous_framebuffer;
+        let intensity = &mut self.intensity;
+        let persistence = self.persistence;
+        let colors = self.palette.colors.map(color_from_rgb);
+        texture.with_lock(None, |pixels: &mut [u8], pitch: usize| {
+            for y in 0..SCREEN_HEIGHT as usize {
+                for x in 0..SCREEN_WIDTH as usize {
+                    let lit = |framebuffer: &[u8; 256]| {
+                        framebuffer[x / 8 + y * 8].wrapping_shr(7 - (x % 8) as u32) & 1 == 1
+                    };
+                    // The framebuffer only has one plane, so the colour index is that plane's bit.
+                    let color = match persistence {
+                        Persistence::Off => colors[lit(framebuffer) as usize],
+                        Persistence::TwoFrames => {
+                            colors[(lit(framebuffer) || lit(previous_framebuffer)) as usize]
+                        }
+                        Persistence::Decay(decay) => {
+                            let pixel_intensity = &mut intensity[x + y * SCREEN_WIDTH as usize];
+                            *pixel_intensity = if lit(framebuffer) {
+                                1.0
+                            } else {
+                                *pixel_intensity * decay
+                            };
+                            blend(colors[0], colors[1], *pixel_intensity)
+                        }
+                    };
+                    let offset = y * pitch + x * 3;
+                    pixels[offset] = color.r;
+                    pixels[offset + 1] = color.g;
+                    pixels[offset + 2] = color.b;
+                }
+            }
+        })?;
+        self.previous_framebuffer = self.framebuffer;
+
+        Ok(())
+    }
+
+    // Where the (unrotated) screen goes in the window. Rotation happens around its centre.
+    fn screen_rect(self: &Self) -> Result<Rect, String> {
+        // A null display behaves as if it had a window of the default size.
+        let window_size = match &self.output {
+            Some(output) => output.canvas.output_size()?,
+            None => match self.rotation {
+                90 | 270 => (SCREEN_HEIGHT * 10, SCREEN_WIDTH * 10),
+                _ => (SCREEN_WIDTH * 10, SCREEN_HEIGHT * 10),
+            },
+        };
+        Ok(fit_screen(window_size, self.rotation, self.scaling))
+    }
+
+    // The scale the screen is currently shown at, rounded to a whole number.
+    pub fn scale(self: &Self) -> Result<u32, String> {
+        let scale = (self.screen_rect()?.width() as f32 / SCREEN_WIDTH as f32).round() as u32;
+        Ok(scale.max(1))
+    }
+
+    // The framebuffer in the current palette, scaled by a whole factor and rotated.
+    pub fn image(self: &Self, scale: u32) -> Image {
+        Image::from_framebuffer(&self.framebuffer, &self.palette)
+            .scaled(scale)
+            .rotated(self.rotation)
+    }
+
+    // Saves the framebuffer as two PNG files in the given directory: one at CHIP-8
+    // resolution, and one scaled and rotated the way it is currently shown. Returns the
+    // paths of both.
+    pub fn save_screenshot(self: &Self, directory: &str) -> Result<(String, String), String> {
+        let native = Image::from_framebuffer(&self.framebuffer, &self.palette);
+        let scaled = self.image(self.scale()?);
+
+        let name = format!("screenshot-{}", image::timestamp());
+        let native_path = format!("{}/{}.png", directory, name);
+        let scaled_path = format!("{}/{}-scaled.png", directory, name);
+        native.save_png(&native_path)?;
+        scaled.save_png(&scaled_path)?;
+
+        Ok((native_path, scaled_path))
+    }
+
+    pub fn present(self: &mut Self) {
+        self.update_texture().unwrap();
+
+        let screen_rect = self.screen_rect().unwrap();
+        let output = match &mut self.output {
+            Some(output) => output,
+            None => return,
+        };
+        output.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        output.canvas.clear();
+        output
+            .canvas
+            .copy_ex(
+                &output.texture,
+                None,
+                screen_rect,
+                self.rotation as f64,
+                None,
+                false,
+                false,
+            )
+            .unwrap();
+
+        if let Some(overlay) = &self.overlay {
+            draw_overlay(&mut output.canvas, overlay).unwrap();
+        }
+
+        output.canvas.present();
+    }
+}
+
+// Where the unrotated screen goes in a window of the given size, for it to fit once rotated.
+fn fit_screen(window_size: (u32, u32), rotation: u32, scaling: Scaling) -> Rect {
+    let (screen_width, screen_height) = match rotation {
+        90 | 270 => (SCREEN_HEIGHT, SCREEN_WIDTH),
+        _ => (SCREEN_WIDTH, SCREEN_HEIGHT),
+    };
+    let (window_width, window_height) = window_size;
+
+    let scale_x = window_width as f32 / screen_width as f32;
+    let scale_y = window_height as f32 / screen_height as f32;
+    let mut scale = scale_x.min(scale_y);
+    if scaling == Scaling::Integer && scale >= 1.0 {
+        scale = scale.floor();
+    }
+
+    let width = (SCREEN_WIDTH as f32 * scale) as u32;
+    let height = (SCREEN_HEIGHT as f32 * scale) as u32;
+    Rect::from_center(
+        ((window_width / 2) as i32, (window_height / 2) as i32),
+        width,
+        height,
+    )
+}
+
+fn color_from_rgb(rgb: u32) -> Color {
+    Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
+
+fn blend(from: Color, to: Color, amount: f32) -> Color {
+    let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * amount).round() as u8;
+    Color::RGB(mix(from.r, to.r), mix(from.g, to.g), mix(from.b, to.b))
+}
+
+// Draws the overlay's columns side by side over the whole window, at the largest whole
+// scale that fits, on a translucent background so that the screen stays visible.
+fn draw_overlay(canvas: &mut WindowCanvas, overlay: &Overlay) -> Result<(), String> {
+    let (window_width, window_height) = canvas.output_size()?;
+    let cell_width = GLYPH_WIDTH + 1;
+    let cell_height = GLYPH_HEIGHT + 1;
+    let line_length = |line: &Line| {
+        line.iter()
+            .map(|span| span.text.chars().count())
+            .sum::<usize>()
+    };
+    // Columns are two characters apart, with a margin of one character around everything.
+    let column_widths: Vec<u32> = overlay
+        .columns
+        .iter()
+        .map(|lines| lines.iter().map(line_length).max().unwrap_or(0) as u32 + 2)
+        .collect();
+    let columns: u32 = column_widths.iter().sum();
+    let rows = overlay
+        .columns
+        .iter()
+        .map(|lines| lines.len())
+        .max()
+        .unwrap_or(0) as u32;
+    let scale = (window_width / (columns * cell_width))
+        .min(window_height / ((rows + 2) * cell_height))
+        .max(1);
+
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    let result = canvas.fill_rect(None);
+    canvas.set_blend_mode(BlendMode::None);
+    result?;
+
+    let mut column_x = cell_width * scale;
+    for (lines, width) in overlay.columns.iter().zip(column_widths) {
+        for (row, line) in lines.iter().enumerate() {
+            let line_y = (row as u32 + 1) * cell_height * scale;
+            let mut char_x = column_x;
+            for span in line {
+                let span_width = span.text.chars().count() as u32 * cell_width * scale;
+                if span.highlight == Highlight::Cursor {
+                    canvas.set_draw_color(color_from_rgb(TEXT_COLOR));
+                    canvas.fill_rect(Rect::new(
+                        char_x as i32 - scale as i32,
+                        line_y as i32 - scale as i32,
+                        span_width + scale,
+                        cell_height * scale,
+                    ))?;
+                }
+
+                let mut rects = Vec::new();
+                for c in span.text.chars() {
+                    for (glyph_y, bits) in font::glyph(c).iter().enumerate() {
+                        for glyph_x in 0..GLYPH_WIDTH {
+                            if (bits >> (GLYPH_WIDTH - 1 - glyph_x)) & 1 == 1 {
+                                rects.push(Rect::new(
+                                    (char_x + glyph_x * scale) as i32,
+                                    (line_y + glyph_y as u32 * scale) as i32,
+                                    scale,
+                                    scale,
+                                ));
+                            }
+                        }
                     }
+                    char_x += cell_width * scale;
                 }
+                canvas.set_draw_color(color_from_rgb(highlight_color(span.highlight)));
+                canvas.fill_rects(&rects)?;
             }
         }
+        column_x += width * cell_width * scale;
     }
 
-    pub fn present(self: &mut Self) {
-        self.canvas.present();
+    Ok(())
+}
+
+const TEXT_COLOR: u32 = 0xeeeeee;
+
+fn highlight_color(highlight: Highlight) -> u32 {
+    match highlight {
+        Highlight::Normal => TEXT_COLOR,
+        Highlight::ProgramCounter => 0xffcc00,
+        Highlight::MemoryRegister => 0x00ccff,
+        Highlight::Font => 0x888888,
+        Highlight::Written => 0xff5555,
+        // Drawn on a box of the normal text colour.
+        Highlight::Cursor => 0x000000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scaling_names() {
+        assert_eq!(Scaling::from_name("integer"), Ok(Scaling::Integer));
+        assert_eq!(Scaling::from_name("aspect"), Ok(Scaling::Aspect));
+        assert_eq!(
+            Scaling::from_name("stretch"),
+            Err("Unknown scaling stretch, expected integer or aspect.".to_string())
+        );
+    }
+
+    #[test]
+    fn integer_scaling_uses_whole_multiples() {
+        let rect = fit_screen((700, 400), 0, Scaling::Integer);
+        assert_eq!(rect, Rect::new(30, 40, 640, 320));
+        // Windows smaller than the CHIP-8 resolution still show the whole screen.
+        let rect = fit_screen((32, 32), 0, Scaling::Integer);
+        assert_eq!(rect, Rect::new(0, 8, 32, 16));
+    }
+
+    #[test]
+    fn aspect_scaling_fills_the_window() {
+        let rect = fit_screen((700, 400), 0, Scaling::Aspect);
+        assert_eq!(rect, Rect::new(0, 25, 700, 350));
+    }
+
+    #[test]
+    fn rotated_screens_fit_once_rotated() {
+        // The rect is unrotated, and turns into 320x640 around its centre.
+        let rect = fit_screen((400, 700), 90, Scaling::Integer);
+        assert_eq!(rect, Rect::new(-120, 190, 640, 320));
+    }
+
+    #[test]
+    fn null_display_keeps_the_screen_for_screenshots() {
+        let mut display = Display::null();
+        display.set_rotation(90).unwrap();
+        let mut framebuffer = [0; 256];
+        framebuffer[0] = 0x80;
+        display.set_pixels(&framebuffer);
+        display.present();
+
+        assert_eq!(display.scale(), Ok(10));
+        let image = display.image(2);
+        assert_eq!((image.width, image.height), (64, 128));
+        let fill = display.palette().colors[1].to_be_bytes();
+        assert_eq!(image.pixel(63, 0), [fill[1], fill[2], fill[3]]);
     }
 }
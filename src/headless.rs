@@ -0,0 +1,140 @@
+use crate::image::Image;
+use crate::interpreter::Interpreter;
+use crate::keypad::Keypad;
+use crate::palette::Palette;
+use crate::recorder::Recorder;
+use crate::sound::{Synth, Tone, SAMPLE_RATE};
+use crate::wav::WavWriter;
+
+// What a headless run produces. Frames are recorded at CHIP-8 resolution, rotated like
+// they would be on screen.
+pub struct Outputs<'a> {
+    pub audio_path: Option<&'a str>,
+    pub video_path: Option<&'a str>,
+    pub palette: &'a Palette,
+    pub rotation: u32,
+}
+
+// Runs the given number of frames as fast as possible, without a window, audio device or
+// input, writing the sound and picture to files. Output only depends on the ROM and
+// options, which makes it usable for regression tests.
+pub fn run(
+    interpreter: &mut Interpreter,
+    cycles_per_frame: u32,
+    frames: u64,
+    tone: Tone,
+    outputs: &Outputs,
+) -> Result<(), String> {
+    let mut keypad = Keypad::new(0);
+    let mut synth = Synth::new(tone, SAMPLE_RATE);
+    let mut samples = Vec::new();
+
+    let mut wav = match outputs.audio_path {
+        Some(path) => Some(WavWriter::create(path, SAMPLE_RATE)?),
+        None => None,
+    };
+    let mut recorder = match outputs.video_path {
+        Some(path) => {
+            let frame = frame_image(interpreter, outputs);
+            Some(Recorder::start(path, 1, frame.width, frame.height)?)
+        }
+        None => None,
+    };
+
+    for frame in 0..frames {
+        // Keypad events are in milliseconds, and nothing ever gets pressed.
+        keypad.begin_frame((frame * 1000 / 60) as u32);
+        let buzzer = interpreter.run_frame(&mut keypad, cycles_per_frame)?;
+
+        if let Some(wav) = &mut wav {
+            samples.clear();
+            synth.generate_frame(buzzer, &mut samples);
+            wav.write_samples(&samples)?;
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.add_frame(&frame_image(interpreter, outputs))?;
+        }
+    }
+
+    if let Some(wav) = wav {
+        let path = wav.path().to_string();
+        wav.finish()?;
+        println!("Saved audio {}", path);
+    }
+    if let Some(recorder) = recorder {
+        let path = recorder.path().to_string();
+        recorder.finish()?;
+        println!("Saved recording {}", path);
+    }
+
+    Ok(())
+}
+
+fn frame_image(interpreter: &Interpreter, outputs: &Outputs) -> Image {
+    Image::from_framebuffer(interpreter.framebuffer(), outputs.palette).rotated(outputs.rotation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD V0, 6; LD ST, V0; LD F, V1; DRW V2, V3, 5; JP #208
+    const PROGRAM: [u8; 10] = [0x60, 0x06, 0xf0, 0x18, 0xf1, 0x29, 0xd2, 0x35, 0x12, 0x08];
+
+    // Runs 12 frames of PROGRAM, and returns the WAV and Y4M files written. Tests run in
+    // parallel, so each run needs its own name.
+    fn run_program(name: &str, rotation: u32) -> (Vec<u8>, Vec<u8>) {
+        let temp_path = |extension: &str| {
+            let file_name = format!("chip8emu-{}-{}.{}", std::process::id(), name, extension);
+            std::env::temp_dir()
+                .join(file_name)
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let (audio_path, video_path) = (temp_path("wav"), temp_path("y4m"));
+        let mut interpreter = Interpreter::new(&PROGRAM);
+        interpreter.set_trace(false);
+        let outputs = Outputs {
+            audio_path: Some(&audio_path),
+            video_path: Some(&video_path),
+            palette: &Palette::default(),
+            rotation,
+        };
+        run(&mut interpreter, 10, 12, Tone::default(), &outputs).unwrap();
+
+        let files = (
+            std::fs::read(&audio_path).unwrap(),
+            std::fs::read(&video_path).unwrap(),
+        );
+        std::fs::remove_file(&audio_path).unwrap();
+        std::fs::remove_file(&video_path).unwrap();
+        files
+    }
+
+    #[test]
+    fn records_sound_and_picture_of_every_frame() {
+        let (audio, video) = run_program("frames", 0);
+        let samples: Vec<i16> = audio[44..]
+            .chunks_exact(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        let frame_length = (SAMPLE_RATE / 60) as usize;
+        assert_eq!(samples.len(), 12 * frame_length);
+        assert!(samples[frame_length..6 * frame_length]
+            .iter()
+            .all(|&s| s != 0));
+        assert!(samples[7 * frame_length..].iter().all(|&s| s == 0));
+
+        let header = b"YUV4MPEG2 W64 H32 F60:1 Ip A1:1 C444\n";
+        assert!(video.starts_with(header));
+        assert_eq!(video.len(), header.len() + 12 * (6 + 64 * 32 * 3));
+    }
+
+    #[test]
+    fn output_only_depends_on_the_program() {
+        assert!(run_program("first", 0) == run_program("second", 0));
+        let (_, rotated_video) = run_program("rotated", 90);
+        assert!(rotated_video.starts_with(b"YUV4MPEG2 W32 H64 "));
+    }
+}
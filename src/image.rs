@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::interpreter::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::palette::Palette;
+
+// An RGB image with 8 bits per channel, for saving what is on screen.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn from_framebuffer(framebuffer: &[u8; 256], palette: &Palette) -> Image {
+        let mut pixels = Vec::with_capacity((SCREEN_WIDTH * SCREEN_HEIGHT * 3) as usize);
+        for y in 0..SCREEN_HEIGHT as usize {
+            for x in 0..SCREEN_WIDTH as usize {
+                let fb_byte = framebuffer[x / 8 + y * 8];
+                let rgb = palette.colors[(fb_byte.wrapping_shr(7 - (x % 8) as u32) & 1) as usize];
+                pixels.extend_from_slice(&[(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]);
+            }
+        }
+
+        Image {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            pixels,
+        }
+    }
+
+    pub fn pixel(self: &Self, x: u32, y: u32) -> [u8; 3] {
+        let offset = ((x + y * self.width) * 3) as usize;
+        [
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+        ]
+    }
+
+    // Nearest-neighbour scaling by a whole factor.
+    pub fn scaled(self: &Self, scale: u32) -> Image {
+        let width = self.width * scale;
+        let height = self.height * scale;
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&self.pixel(x / scale, y / scale));
+            }
+        }
+
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    // Rotates clockwise by the given number of degrees (a multiple of 90).
+    pub fn rotated(self: &Self, degrees: u32) -> Image {
+        let (width, height) = match degrees {
+            90 | 270 => (self.height, self.width),
+            _ => (self.width, self.height),
+        };
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = match degrees {
+                    90 => (y, self.height - 1 - x),
+                    180 => (self.width - 1 - x, self.height - 1 - y),
+                    270 => (self.width - 1 - y, x),
+                    _ => (x, y),
+                };
+                pixels.extend_from_slice(&self.pixel(source_x, source_y));
+            }
+        }
+
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn save_png(self: &Self, path: &str) -> Result<(), String> {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("Failed to create {}: {}", path, err)),
+        };
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let result = encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels));
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("Failed to write {}: {}", path, err)),
+        }
+    }
+}
+
+// The current UTC time as YYYYMMDD-HHMMSS-mmm, for naming files.
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format_timestamp(now)
+}
+
+fn format_timestamp(now: Duration) -> String {
+    let seconds = now.as_secs();
+    let (hour, minute, second) = ((seconds / 3600) % 24, (seconds / 60) % 60, seconds % 60);
+
+    // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        now.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [u8; 3] = [0, 0, 0];
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    // The top-left pixel and the one right of it lit, on a black-and-white palette.
+    fn image() -> Image {
+        let mut framebuffer = [0; 256];
+        framebuffer[0] = 0b1100_0000;
+        let palette = Palette::new("test", [0x000000, 0xffffff, 0, 0]);
+        Image::from_framebuffer(&framebuffer, &palette)
+    }
+
+    #[test]
+    fn converts_the_framebuffer_with_the_palette() {
+        let image = image();
+        assert_eq!((image.width, image.height), (64, 32));
+        assert_eq!(image.pixel(0, 0), WHITE);
+        assert_eq!(image.pixel(1, 0), WHITE);
+        assert_eq!(image.pixel(2, 0), BLACK);
+        assert_eq!(image.pixel(0, 1), BLACK);
+    }
+
+    #[test]
+    fn scales_by_whole_factors() {
+        let image = image().scaled(3);
+        assert_eq!((image.width, image.height), (192, 96));
+        assert_eq!(image.pixel(5, 2), WHITE);
+        assert_eq!(image.pixel(6, 0), BLACK);
+        assert_eq!(image.pixel(0, 3), BLACK);
+    }
+
+    #[test]
+    fn rotates_clockwise() {
+        let image = image();
+        let rotated = image.rotated(90);
+        assert_eq!((rotated.width, rotated.height), (32, 64));
+        assert_eq!(rotated.pixel(31, 0), WHITE);
+        assert_eq!(rotated.pixel(31, 1), WHITE);
+        assert_eq!(rotated.pixel(30, 0), BLACK);
+
+        let rotated = image.rotated(180);
+        assert_eq!(rotated.pixel(63, 31), WHITE);
+        assert_eq!(rotated.pixel(62, 31), WHITE);
+        assert_eq!(rotated.pixel(61, 31), BLACK);
+
+        let rotated = image.rotated(270);
+        assert_eq!(rotated.pixel(0, 63), WHITE);
+        assert_eq!(rotated.pixel(0, 62), WHITE);
+        assert_eq!(rotated.pixel(1, 63), BLACK);
+    }
+
+    #[test]
+    fn saves_png() {
+        let path = std::env::temp_dir().join(format!("chip8emu-{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        let image = image().scaled(2);
+        image.save_png(path).unwrap();
+
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(pixels, image.pixels);
+    }
+
+    #[test]
+    fn formats_timestamps_as_utc_dates() {
+        let timestamp = |seconds, millis| {
+            format_timestamp(Duration::from_secs(seconds) + Duration::from_millis(millis))
+        };
+        assert_eq!(timestamp(0, 0), "19700101-000000-000");
+        assert_eq!(timestamp(951782400, 7), "20000229-000000-007");
+        assert_eq!(timestamp(1709251199, 123), "20240229-235959-123");
+    }
+}
@@ -1,97 +1,92 @@
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::EventPump;
-
-#[derive(Clone, Copy)]
-pub enum Key {
-    Key0 = 0,
-    Key1 = 1,
-    Key2 = 2,
-    Key3 = 3,
-    Key4 = 4,
-    Key5 = 5,
-    Key6 = 6,
-    Key7 = 7,
-    Key8 = 8,
-    Key9 = 9,
-    KeyA = 0xa,
-    KeyB = 0xb,
-    KeyC = 0xc,
-    KeyD = 0xd,
-    KeyE = 0xe,
-    KeyF = 0xf,
-}
-
-impl From<u8> for Key {
-    fn from(value: u8) -> Key {
-        match value {
-            0 => Key::Key0,
-            1 => Key::Key1,
-            2 => Key::Key2,
-            3 => Key::Key3,
-            4 => Key::Key4,
-            5 => Key::Key5,
-            6 => Key::Key6,
-            7 => Key::Key7,
-            8 => Key::Key8,
-            9 => Key::Key9,
-            0xa => Key::KeyA,
-            0xb => Key::KeyB,
-            0xc => Key::KeyC,
-            0xd => Key::KeyD,
-            0xe => Key::KeyE,
-            0xf => Key::KeyF,
-            _ => panic!("Invalid Key."),
-        }
-    }
-}
+use std::collections::HashSet;
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum KeyState {
-    KeyUp = 0b01,
-    KeyPressed = 0b10,
-    KeyDown = 0b11,
-}
+use sdl2::controller::GameController;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::{EventPump, GameControllerSubsystem, TimerSubsystem};
 
-impl From<u8> for KeyState {
-    fn from(value: u8) -> KeyState {
-        match value {
-            0b01 => KeyState::KeyUp,
-            0b10 => KeyState::KeyPressed,
-            0b11 => KeyState::KeyDown,
-            _ => panic!("Invalid KeyState value."),
-        }
-    }
-}
+use crate::keymap::{AxisDirection, HostKey, Keymap, AXIS_THRESHOLD};
+use crate::keypad::{Key, Keypad};
+use crate::memory_view::EditorKey;
 
 pub struct Input {
-    chip8_keys: [KeyState; 0x10],
+    pub keypad: Keypad,
+    timer: TimerSubsystem,
+    // Number of host keys currently held down for each CHIP-8 key, as several host keys
+    // can be bound to the same CHIP-8 key.
+    held_host_keys: [u8; 0x10],
+    keymap: Keymap,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    // Controller inputs currently held down, by the joystick id of their controller.
+    held_controller_inputs: HashSet<(u32, HostKey)>,
     pub quit: bool,
     pub step_mode_changed: bool,
     pub step_to_next_instruction: bool,
     pub print_state: bool,
+    pub toggle_fullscreen: bool,
+    pub next_palette: bool,
+    pub screenshot: bool,
+    pub toggle_recording: bool,
+    pub toggle_mute: bool,
+    pub toggle_overlay: bool,
+    pub toggle_memory_view: bool,
+    // Keys for the memory view, including repeats.
+    pub editor_keys: Vec<EditorKey>,
+    // While the memory view has the focus, the keyboard only types into it and doesn't
+    // reach the keypad.
+    pub editor_focused: bool,
+    // Keys pressed while the memory view had the focus, whose release is swallowed too.
+    editor_held_keys: HashSet<Scancode>,
 }
 
 impl Input {
-    pub fn new() -> Input {
+    pub fn new(
+        keymap: Keymap,
+        controller_subsystem: GameControllerSubsystem,
+        timer: TimerSubsystem,
+    ) -> Input {
+        let ticks = timer.ticks();
         Input {
-            chip8_keys: [KeyState::KeyUp; 0x10],
+            keypad: Keypad::new(ticks),
+            timer,
+            held_host_keys: [0; 0x10],
+            keymap,
+            controller_subsystem,
+            controllers: Vec::new(),
+            held_controller_inputs: HashSet::new(),
             quit: false,
             step_mode_changed: false,
             step_to_next_instruction: false,
             print_state: false,
+            toggle_fullscreen: false,
+            next_palette: false,
+            screenshot: false,
+            toggle_recording: false,
+            toggle_mute: false,
+            toggle_overlay: false,
+            toggle_memory_view: false,
+            editor_keys: Vec::new(),
+            editor_focused: false,
+            editor_held_keys: HashSet::new(),
         }
     }
 
     pub fn collect(self: &mut Self, event_pump: &mut EventPump) {
-        for i in 0..self.chip8_keys.len() {
-            self.chip8_keys[i] = KeyState::from(self.chip8_keys[i] as u8 | 1 as u8);
-        }
+        self.keypad.begin_frame(self.timer.ticks());
 
         self.quit = false;
         self.step_mode_changed = false;
         self.step_to_next_instruction = false;
         self.print_state = false;
+        self.toggle_fullscreen = false;
+        self.next_palette = false;
+        self.screenshot = false;
+        self.toggle_recording = false;
+        self.toggle_mute = false;
+        self.toggle_overlay = false;
+        self.toggle_memory_view = false;
+        self.editor_keys.clear();
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
@@ -100,69 +95,166 @@ impl Input {
                     ..
                 } => self.quit = true,
                 Event::KeyDown {
-                    keycode: Some(keycode),
+                    timestamp,
+                    keycode,
+                    scancode,
+                    repeat,
                     ..
-                } => match keycode {
-                    Keycode::P => self.step_mode_changed = true,
-                    Keycode::N => self.step_to_next_instruction = true,
-                    Keycode::L => self.print_state = true,
-                    Keycode::Num1 => self.chip8_keys[0x1] = KeyState::KeyPressed,
-                    Keycode::Num2 => self.chip8_keys[0x2] = KeyState::KeyPressed,
-                    Keycode::Num3 => self.chip8_keys[0x3] = KeyState::KeyPressed,
-                    Keycode::Num4 => self.chip8_keys[0xc] = KeyState::KeyPressed,
-                    Keycode::Q => self.chip8_keys[0x4] = KeyState::KeyPressed,
-                    Keycode::W => self.chip8_keys[0x5] = KeyState::KeyPressed,
-                    Keycode::E => self.chip8_keys[0x6] = KeyState::KeyPressed,
-                    Keycode::R => self.chip8_keys[0xd] = KeyState::KeyPressed,
-                    Keycode::A => self.chip8_keys[0x7] = KeyState::KeyPressed,
-                    Keycode::S => self.chip8_keys[0x8] = KeyState::KeyPressed,
-                    Keycode::D => self.chip8_keys[0x9] = KeyState::KeyPressed,
-                    Keycode::F => self.chip8_keys[0xe] = KeyState::KeyPressed,
-                    Keycode::Z => self.chip8_keys[0xa] = KeyState::KeyPressed,
-                    Keycode::X => self.chip8_keys[0x0] = KeyState::KeyPressed,
-                    Keycode::C => self.chip8_keys[0xb] = KeyState::KeyPressed,
-                    Keycode::V => self.chip8_keys[0xf] = KeyState::KeyPressed,
-                    _ => (),
-                },
+                } => {
+                    match keycode {
+                        Some(Keycode::P) => self.step_mode_changed = true,
+                        Some(Keycode::N) => self.step_to_next_instruction = true,
+                        Some(Keycode::L) => self.print_state = true,
+                        Some(Keycode::M) => self.toggle_mute = true,
+                        Some(Keycode::F1) => self.toggle_overlay = true,
+                        Some(Keycode::F2) => self.next_palette = true,
+                        Some(Keycode::F3) => self.toggle_memory_view = true,
+                        Some(Keycode::F9) => self.toggle_recording = true,
+                        Some(Keycode::F11) => self.toggle_fullscreen = true,
+                        Some(Keycode::F12) => self.screenshot = true,
+                        _ => (),
+                    }
+                    if let Some(editor_key) = keycode.and_then(editor_key) {
+                        self.editor_keys.push(editor_key);
+                    }
+                    if self.editor_focused {
+                        if let Some(scancode) = scancode {
+                            self.editor_held_keys.insert(scancode);
+                        }
+                    } else if let Some(key) = self.keymap.lookup(scancode, keycode) {
+                        if !repeat {
+                            self.host_key_down(key, timestamp);
+                        }
+                    }
+                }
                 Event::KeyUp {
-                    keycode: Some(keycode),
+                    timestamp,
+                    keycode,
+                    scancode,
                     ..
-                } => match keycode {
-                    Keycode::Num1 => self.chip8_keys[0x1] = KeyState::KeyUp,
-                    Keycode::Num2 => self.chip8_keys[0x2] = KeyState::KeyUp,
-                    Keycode::Num3 => self.chip8_keys[0x3] = KeyState::KeyUp,
-                    Keycode::Num4 => self.chip8_keys[0xc] = KeyState::KeyUp,
-                    Keycode::Q => self.chip8_keys[0x4] = KeyState::KeyUp,
-                    Keycode::W => self.chip8_keys[0x5] = KeyState::KeyUp,
-                    Keycode::E => self.chip8_keys[0x6] = KeyState::KeyUp,
-                    Keycode::R => self.chip8_keys[0xd] = KeyState::KeyUp,
-                    Keycode::A => self.chip8_keys[0x7] = KeyState::KeyUp,
-                    Keycode::S => self.chip8_keys[0x8] = KeyState::KeyUp,
-                    Keycode::D => self.chip8_keys[0x9] = KeyState::KeyUp,
-                    Keycode::F => self.chip8_keys[0xe] = KeyState::KeyUp,
-                    Keycode::Z => self.chip8_keys[0xa] = KeyState::KeyUp,
-                    Keycode::X => self.chip8_keys[0x0] = KeyState::KeyUp,
-                    Keycode::C => self.chip8_keys[0xb] = KeyState::KeyUp,
-                    Keycode::V => self.chip8_keys[0xf] = KeyState::KeyUp,
-                    _ => (),
-                },
+                } => {
+                    if let Some(scancode) = scancode {
+                        if self.editor_held_keys.remove(&scancode) {
+                            continue;
+                        }
+                    }
+                    if let Some(key) = self.keymap.lookup(scancode, keycode) {
+                        self.host_key_up(key, timestamp);
+                    }
+                }
+                // SDL also sends this for controllers that are already connected at startup.
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match self.controller_subsystem.open(which) {
+                        Ok(controller) => self.controllers.push(controller),
+                        Err(err) => println!("Failed to open game controller: {}", err),
+                    }
+                }
+                Event::ControllerDeviceRemoved {
+                    timestamp, which, ..
+                } => {
+                    self.controllers.retain(|controller| controller.instance_id() != which);
+                    let held: Vec<HostKey> = self
+                        .held_controller_inputs
+                        .iter()
+                        .filter(|(id, _)| *id == which)
+                        .map(|(_, host_key)| *host_key)
+                        .collect();
+                    for host_key in held {
+                        self.controller_input_changed(which, host_key, false, timestamp);
+                    }
+                }
+                Event::ControllerButtonDown {
+                    timestamp,
+                    which,
+                    button,
+                } => {
+                    let host_key = HostKey::ControllerButton(button);
+                    self.controller_input_changed(which, host_key, true, timestamp);
+                }
+                Event::ControllerButtonUp {
+                    timestamp,
+                    which,
+                    button,
+                } => {
+                    let host_key = HostKey::ControllerButton(button);
+                    self.controller_input_changed(which, host_key, false, timestamp);
+                }
+                Event::ControllerAxisMotion {
+                    timestamp,
+                    which,
+                    axis,
+                    value,
+                } => {
+                    let negative = HostKey::ControllerAxis(axis, AxisDirection::Negative);
+                    let positive = HostKey::ControllerAxis(axis, AxisDirection::Positive);
+                    let negative_down = value <= -AXIS_THRESHOLD;
+                    let positive_down = value >= AXIS_THRESHOLD;
+                    self.controller_input_changed(which, negative, negative_down, timestamp);
+                    self.controller_input_changed(which, positive, positive_down, timestamp);
+                }
                 _ => {}
             }
         }
     }
 
-    pub fn any_key_pressed(self: &Self) -> Option<Key> {
-        match self
-            .chip8_keys
-            .iter()
-            .position(|&k| k == KeyState::KeyPressed)
-        {
-            Some(idx) => Some(Key::from(idx as u8)),
-            None => None,
+    fn controller_input_changed(
+        self: &mut Self,
+        which: u32,
+        host_key: HostKey,
+        down: bool,
+        timestamp: u32,
+    ) {
+        let changed = if down {
+            self.held_controller_inputs.insert((which, host_key))
+        } else {
+            self.held_controller_inputs.remove(&(which, host_key))
+        };
+        if !changed {
+            return;
+        }
+
+        if let Some(key) = self.keymap.get(host_key) {
+            if down {
+                self.host_key_down(key, timestamp);
+            } else {
+                self.host_key_up(key, timestamp);
+            }
         }
     }
 
-    pub fn get_key_state(self: &Self, key: Key) -> KeyState {
-        return self.chip8_keys[key as usize];
+    fn host_key_down(self: &mut Self, key: Key, timestamp: u32) {
+        let held = &mut self.held_host_keys[key as usize];
+        if *held == 0 {
+            self.keypad.push_event(timestamp, key, true);
+        }
+        *held += 1;
+    }
+
+    fn host_key_up(self: &mut Self, key: Key, timestamp: u32) {
+        let held = &mut self.held_host_keys[key as usize];
+        if *held == 1 {
+            self.keypad.push_event(timestamp, key, false);
+        }
+        *held = held.saturating_sub(1);
+    }
+}
+
+fn editor_key(keycode: Keycode) -> Option<EditorKey> {
+    let name = keycode.name();
+    match keycode {
+        Keycode::Up => Some(EditorKey::Up),
+        Keycode::Down => Some(EditorKey::Down),
+        Keycode::Left => Some(EditorKey::Left),
+        Keycode::Right => Some(EditorKey::Right),
+        Keycode::PageUp => Some(EditorKey::PageUp),
+        Keycode::PageDown => Some(EditorKey::PageDown),
+        // Digits and letters are named after their character, keypad digits e.g. "Keypad 1".
+        _ => match name.strip_prefix("Keypad ").unwrap_or(&name) {
+            digit if digit.len() == 1 => match u8::from_str_radix(digit, 16) {
+                Ok(value) => Some(EditorKey::Digit(value)),
+                Err(..) => None,
+            },
+            _ => None,
+        },
     }
 }
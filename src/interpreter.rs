@@ -1,11 +1,20 @@
+use std::collections::VecDeque;
+
 use rand::{rngs::ThreadRng, Rng};
 
-use crate::input::{Input, Key, KeyState};
-use crate::sound::Sound;
-use crate::display::Display;
+use crate::access::{AccessCounts, Finding};
+use crate::breakpoints::{Access, Breakpoints, Stop};
+use crate::disassembler;
+use crate::keypad::{Key, Keypad};
+use crate::profiler::Profiler;
+use crate::symbols::Symbols;
 
-#[derive(Debug)]
-enum Instruction {
+// Size of the CHIP-8 screen in pixels, for the frontends.
+pub const SCREEN_WIDTH: u32 = 64;
+pub const SCREEN_HEIGHT: u32 = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Instruction {
     // For descriptions, see http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
     INVALID,
     SYS,
@@ -58,13 +67,131 @@ pub struct Interpreter {
     program_counter: u16,
     stack_pointer: usize,
     random_number_generator: ThreadRng,
-    previous_status: ExecutionStatus
+    quirks: Quirks,
+    waiting_for_vblank: bool,
+    key_wait: Option<KeyWait>,
+    // Print every instruction as it is executed.
+    trace: bool,
+    // Frames run so far, counted by update_timers.
+    frame_count: u64,
+    // Instructions run so far in the current frame, which stepping can leave unfinished.
+    frame_cycle: u32,
+    // The frame count after the last write to each byte of memory, or 0 if it was never
+    // written to.
+    written_at: [u64; 0xfff],
+    // Only tracked when enabled, as it slows every memory access down.
+    access_counts: Option<AccessCounts>,
+    profiler: Option<Profiler>,
+    // Bytes of the ROM loaded at 0x200.
+    rom_size: usize,
+    // Labels the trace and the debugging tools show instead of addresses.
+    symbols: Symbols,
+    breakpoints: Breakpoints,
+    // Why the last instruction stopped execution, until it is taken.
+    stop: Option<Stop>,
+    // Where a breakpoint stopped execution, so that resuming runs the instruction there.
+    step_over: Option<u16>,
+    // How to undo each of the most recent instructions, oldest first, when enabled.
+    history: Option<VecDeque<Undo>>,
+    history_length: usize,
+    // The undo information of the instruction being executed.
+    undo: Option<Undo>,
+}
+
+// The state before an instruction ran. Registers are small enough to keep whole, while
+// memory and the framebuffer only keep the bytes the instruction changed.
+#[derive(Debug)]
+struct Undo {
+    opcode: u16,
+    program_counter: u16,
+    registers: [u8; 16],
+    memory_register: u16,
+    stack: [u16; 0xf],
+    stack_pointer: usize,
+    delay_timer: u8,
+    sound_timer: u8,
+    frame_count: u64,
+    waiting_for_vblank: bool,
+    key_wait: Option<KeyWait>,
+    // Address, old value and old written_at of every byte written.
+    memory: Vec<(usize, u8, u64)>,
+    // Index and old value of every framebuffer byte that changed.
+    framebuffer: Vec<(u8, u8)>,
+    // Every address read as data, for read watchpoints when running backwards.
+    reads: Vec<usize>,
+}
+
+// Something instructions change, for finding out which one changed it last.
+#[derive(Debug, Clone, Copy)]
+pub enum Location {
+    Register(Register),
+    MemoryRegister,
+    Memory(Address),
+}
+
+// An instruction in the history.
+#[derive(Debug, Clone, Copy)]
+pub struct Change {
+    pub program_counter: u16,
+    pub opcode: u16,
+    // Stepping back this many instructions returns to just before it ran.
+    pub instructions_ago: usize,
+}
+
+// State of an FX0A instruction that is waiting for a key.
+#[derive(Debug, Clone, Copy)]
+struct KeyWait {
+    register: Register,
+    // The key that has been pressed, while we wait for it to be released.
+    pressed_key: Option<Key>,
+}
+
+// Behaviours that differ between CHIP-8 implementations. The names follow Octo's
+// quirk flags, and the defaults match what this interpreter has always done.
+#[derive(Debug, Clone, Copy)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place and ignore VY.
+    pub shift: bool,
+    // FX55/FX65 leave I unchanged.
+    pub load_store: bool,
+    // Arithmetic writes VF before the result, so the result wins when VF is the destination.
+    pub vf_order: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around.
+    pub clip: bool,
+    // DXYN waits for the next frame before execution continues.
+    pub vblank: bool,
+    // BNNN jumps to NNN + VX (with X taken from the address) instead of NNN + V0.
+    pub jump: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0.
+    pub logic: bool,
+    // FX0A completes when the key is released, like on the COSMAC VIP, rather than as
+    // soon as it is pressed.
+    pub key_release: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: true,
+            vf_order: true,
+            clip: false,
+            vblank: false,
+            jump: false,
+            logic: false,
+            key_release: true,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum ExecutionStatus {
     Ok,
-    FramebufferChanged
+    FramebufferChanged,
+    WaitingForVBlank,
+    WaitingForKey,
+    // A breakpoint or watchpoint was hit.
+    Break,
 }
 
 type Address = u16;
@@ -106,7 +233,23 @@ impl Interpreter {
             program_counter: 0,
             stack_pointer: 0,
             random_number_generator: rand::thread_rng(),
-            previous_status: ExecutionStatus::Ok
+            quirks: Quirks::default(),
+            waiting_for_vblank: false,
+            key_wait: None,
+            trace: true,
+            frame_count: 0,
+            frame_cycle: 0,
+            written_at: [0; 0xfff],
+            access_counts: None,
+            profiler: None,
+            rom_size: 0,
+            symbols: Symbols::default(),
+            breakpoints: Breakpoints::default(),
+            stop: None,
+            step_over: None,
+            history: None,
+            history_length: 0,
+            undo: None,
         };
 
         let magic_string = match std::str::from_utf8(&rom_buffer[0..3]) {
@@ -116,15 +259,17 @@ impl Interpreter {
         if magic_string == "C8P" {
             interpreter.memory[0x200..0x200 + (rom_buffer.len() - 3)]
                 .copy_from_slice(&rom_buffer[3..]);
+            interpreter.rom_size = rom_buffer.len() - 3;
         } else {
             interpreter.memory[0x200..0x200 + (rom_buffer.len())].copy_from_slice(&rom_buffer);
+            interpreter.rom_size = rom_buffer.len();
         }
         interpreter.program_counter = 0x200;
 
         interpreter
     }
 
-    fn decode_opcode(opcode: u16) -> Instruction {
+    pub fn decode_opcode(opcode: u16) -> Instruction {
         match (opcode & 0xf000) >> 12 {
             0 => match opcode & 0xfff {
                 0x0E0 => Instruction::CLS,
@@ -228,7 +373,7 @@ impl Interpreter {
         }
     }
 
-    fn execute_instruction(self: &mut Interpreter, instruction: Instruction, input: &Input) -> ExecutionStatus {
+    fn execute_instruction(self: &mut Interpreter, instruction: Instruction, keypad: &Keypad) -> ExecutionStatus {
         self.program_counter += 2;
 
         let mut status = ExecutionStatus::Ok;
@@ -279,46 +424,53 @@ impl Interpreter {
             }
             Instruction::ORRR(register0, register1) => {
                 self.registers[register0] |= self.registers[register1];
+                if self.quirks.logic {
+                    self.registers[0xf] = 0;
+                }
             }
             Instruction::ANDRR(register0, register1) => {
                 self.registers[register0] &= self.registers[register1];
+                if self.quirks.logic {
+                    self.registers[0xf] = 0;
+                }
             }
             Instruction::XORRR(register0, register1) => {
                 self.registers[register0] ^= self.registers[register1];
+                if self.quirks.logic {
+                    self.registers[0xf] = 0;
+                }
             }
             Instruction::ADDRR(register0, register1) => {
                 let sum: u16 = self.registers[register1] as u16 + self.registers[register0] as u16;
-                self.registers[0xf] = if sum > 255 { 1 } else { 0 };
-                self.registers[register0] =
-                    self.registers[register0].wrapping_add(self.registers[register1]);
+                let flag = if sum > 255 { 1 } else { 0 };
+                let result = self.registers[register0].wrapping_add(self.registers[register1]);
+                self.set_result_and_flag(register0, result, flag);
             }
             Instruction::SUBRR(register0, register1) => {
                 let diff: i16 = self.registers[register0] as i16 - self.registers[register1] as i16;
-                self.registers[0xf] = if diff > 0 { 1 } else { 0 };
-                self.registers[register0] =
-                    self.registers[register0].wrapping_sub(self.registers[register1]);
+                // VF is set when there is no borrow, so also when the operands are equal.
+                let flag = if diff >= 0 { 1 } else { 0 };
+                let result = self.registers[register0].wrapping_sub(self.registers[register1]);
+                self.set_result_and_flag(register0, result, flag);
             }
-            Instruction::SHR(register0, _) => {
-                self.registers[0xf] = if self.registers[register0] & 1 == 1 {
-                    1
-                } else {
-                    0
-                };
-                self.registers[register0] >>= 1;
+            Instruction::SHR(register0, register1) => {
+                let source = if self.quirks.shift { register0 } else { register1 };
+                let flag = self.registers[source] & 1;
+                let result = self.registers[source] >> 1;
+                self.set_result_and_flag(register0, result, flag);
             }
             Instruction::SUBN(register0, register1) => {
                 let diff: i16 = self.registers[register1] as i16 - self.registers[register0] as i16;
-                self.registers[0xf] = if diff > 0 { 1 } else { 0 };
-                self.registers[register0] =
-                    self.registers[register1].wrapping_sub(self.registers[register0]);
+                // VF is set when there is no borrow, so also when the operands are equal.
+                let flag = if diff >= 0 { 1 } else { 0 };
+                let result = self.registers[register1].wrapping_sub(self.registers[register0]);
+                self.set_result_and_flag(register0, result, flag);
             }
-            Instruction::SHL(register0, _register1) => {
-                self.registers[0xf] = if self.registers[register0] & 0x80 == 0x80 {
-                    1
-                } else {
-                    0
-                };
-                self.registers[register0] <<= 1;
+            Instruction::SHL(register0, register1) => {
+                let source = if self.quirks.shift { register0 } else { register1 };
+                let flag = self.registers[source] >> 7;
+                let result = self.registers[source] << 1;
+                self.set_result_and_flag(register0, result, flag);
             }
             Instruction::SNERR(register0, register1) => {
                 if self.registers[register0] != self.registers[register1] {
@@ -329,23 +481,42 @@ impl Interpreter {
                 self.memory_register = address;
             }
             Instruction::JP0A(address) => {
-                self.program_counter = address + self.registers[0] as u16;
+                let register = if self.quirks.jump {
+                    ((address & 0xf00) >> 8) as Register
+                } else {
+                    0
+                };
+                self.program_counter = address + self.registers[register] as u16;
             }
             Instruction::RND(register, value) => {
                 let random_number = self.random_number_generator.gen_range(0..=255);
                 self.registers[register] = random_number & value;
             }
             Instruction::DRW(register0, register1, nibble) => {
-                let screen_x = self.registers[register0] as usize;
+                let mut screen_x = self.registers[register0] as usize;
+                let mut screen_y0 = self.registers[register1] as usize;
+                if self.quirks.clip {
+                    // Only the starting position wraps; the sprite itself is cut off at the edges.
+                    screen_x %= 64;
+                    screen_y0 %= 32;
+                }
                 for row in 0..nibble {
-                    let screen_y = (self.registers[register1] + row) as usize;
+                    let screen_y = screen_y0 + row as usize;
+                    if self.quirks.clip && screen_y >= 32 {
+                        break;
+                    }
                     let bit_offset = screen_x % 8;
 
-                    let sprite_byte = self.memory[(self.memory_register + row as u16) as usize];
+                    let sprite_address = (self.memory_register + row as u16) as usize;
+                    let sprite_byte = self.memory[sprite_address];
+                    self.record_read(sprite_address);
                     let sprite_bits: u16 = (sprite_byte as u16) << (8 - bit_offset);
 
                     let fb_byte_idx = (screen_x / 8 + screen_y * 8) % 256;
                     self.framebuffer[fb_byte_idx] ^= (sprite_bits >> 8) as u8;
+                    if self.quirks.clip && screen_x / 8 == 7 {
+                        continue;
+                    }
                     if fb_byte_idx == self.framebuffer.len() - 1 {
                         self.framebuffer[0] ^= sprite_bits as u8;
                     } else {
@@ -353,25 +524,31 @@ impl Interpreter {
                     }
                 }
 
+                if self.quirks.vblank {
+                    self.waiting_for_vblank = true;
+                }
                 status = ExecutionStatus::FramebufferChanged;
             }
             Instruction::SKP(register) => {
-                if input.get_key_state(Key::from(self.registers[register])) != KeyState::KeyUp {
+                if keypad.get_key_state(Key::from(self.registers[register])).is_down() {
                     self.program_counter += 2;
                 }
             }
             Instruction::SKNP(register) => {
-                if input.get_key_state(Key::from(self.registers[register])) == KeyState::KeyUp {
+                if !keypad.get_key_state(Key::from(self.registers[register])).is_down() {
                     self.program_counter += 2;
                 }
             }
             Instruction::LDRDT(register) => {
                 self.registers[register] = self.delay_timer;
             }
-            Instruction::LDRK(register) => match input.any_key_pressed() {
-                Some(key) => self.registers[register as usize] = key as u8,
-                None => self.program_counter -= 2,
-            },
+            Instruction::LDRK(register) => {
+                self.key_wait = Some(KeyWait {
+                    register,
+                    pressed_key: None,
+                });
+                status = self.wait_for_key(keypad);
+            }
             Instruction::LDDTR(register) => {
                 self.delay_timer = self.registers[register];
             }
@@ -391,15 +568,19 @@ impl Interpreter {
                 let hundreds = (value / 100.0).floor();
                 let tens = ((value - hundreds * 100.0) / 10.0).floor();
                 let ones = (value - hundreds * 100.0 - tens * 10.0).floor();
-                self.memory[self.memory_register as usize] = hundreds as u8;
-                self.memory[self.memory_register as usize + 1] = tens as u8;
-                self.memory[self.memory_register as usize + 2] = ones as u8;
+                self.write_memory(self.memory_register as usize, hundreds as u8);
+                self.write_memory(self.memory_register as usize + 1, tens as u8);
+                self.write_memory(self.memory_register as usize + 2, ones as u8);
             }
             Instruction::LDIR(register) => {
                 let num_registers = register + 1 as usize;
                 let mem_start = self.memory_register as usize;
-                let mem_end = (mem_start + num_registers) as usize;
-                self.memory[mem_start..mem_end].copy_from_slice(&self.registers[0..num_registers]);
+                for i in 0..num_registers {
+                    self.write_memory(mem_start + i, self.registers[i]);
+                }
+                if !self.quirks.load_store {
+                    self.memory_register += num_registers as u16;
+                }
             }
             Instruction::LDRI(register) => {
                 let num_registers = register + 1 as usize;
@@ -407,16 +588,105 @@ impl Interpreter {
                 let mem_end = (mem_start + num_registers) as usize;
                 self.registers[0..num_registers as usize]
                     .copy_from_slice(&self.memory[mem_start..mem_end]);
+                for address in mem_start..mem_end {
+                    self.record_read(address);
+                }
+                if !self.quirks.load_store {
+                    self.memory_register += num_registers as u16;
+                }
             }
         }
 
         status
     }
 
-    pub fn execute_next_instruction(self: &mut Self, display: &mut Display, sound: &mut Sound, input: &Input) -> Result<ExecutionStatus, String> {
-        match self.previous_status {
-            ExecutionStatus::FramebufferChanged => display.set_pixels(&self.framebuffer),
-            _ => ()
+    // Writes done by the program. The program counter has already moved past the
+    // instruction doing the write.
+    fn write_memory(self: &mut Self, address: usize, value: u8) {
+        if let Some(undo) = &mut self.undo {
+            undo.memory
+                .push((address, self.memory[address], self.written_at[address]));
+        }
+        self.memory[address] = value;
+        self.written_at[address] = self.frame_count + 1;
+        let program_counter = self.program_counter.wrapping_sub(2);
+        if let Some(access_counts) = &mut self.access_counts {
+            access_counts.write(address, program_counter);
+        }
+        self.watch(address, Access::Write, program_counter);
+    }
+
+    fn record_read(self: &mut Self, address: usize) {
+        if let Some(access_counts) = &mut self.access_counts {
+            access_counts.read(address);
+        }
+        if let Some(undo) = &mut self.undo {
+            undo.reads.push(address);
+        }
+        self.watch(address, Access::Read, self.program_counter.wrapping_sub(2));
+    }
+
+    // Only the first access that hits a watchpoint is kept.
+    fn watch(self: &mut Self, address: usize, access: Access, program_counter: u16) {
+        if self.stop.is_none() {
+            self.stop = self
+                .breakpoints
+                .check_access(address as u16, access, program_counter);
+        }
+    }
+
+    fn set_result_and_flag(self: &mut Self, register: Register, result: u8, flag: u8) {
+        if self.quirks.vf_order {
+            self.registers[0xf] = flag;
+            self.registers[register] = result;
+        } else {
+            self.registers[register] = result;
+            self.registers[0xf] = flag;
+        }
+    }
+
+    // Execution halts while FX0A waits for a key. Timers keep running in the meantime, as
+    // they are driven by update_timers.
+    fn wait_for_key(self: &mut Self, keypad: &Keypad) -> ExecutionStatus {
+        let mut key_wait = match self.key_wait {
+            Some(key_wait) => key_wait,
+            None => return ExecutionStatus::Ok,
+        };
+
+        if key_wait.pressed_key.is_none() {
+            key_wait.pressed_key = keypad.any_key_pressed();
+        }
+        let completed_key = match key_wait.pressed_key {
+            Some(key) if !self.quirks.key_release => Some(key),
+            Some(key) if !keypad.get_key_state(key).is_down() => Some(key),
+            Some(_) => None,
+            // A key that was pressed and released within a single frame.
+            None => keypad.any_key_released(),
+        };
+
+        match completed_key {
+            Some(key) => {
+                self.registers[key_wait.register] = key as u8;
+                self.key_wait = None;
+                ExecutionStatus::Ok
+            }
+            None => {
+                self.key_wait = Some(key_wait);
+                ExecutionStatus::WaitingForKey
+            }
+        }
+    }
+
+    pub fn set_quirks(self: &mut Self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Called once per frame, at 60 Hz. Returns whether the buzzer sounds during this frame.
+    pub fn update_timers(self: &mut Self) -> bool {
+        self.waiting_for_vblank = false;
+        self.frame_count += 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
         }
 
         if self.delay_timer > 0 {
@@ -424,30 +694,385 @@ impl Interpreter {
         }
 
         if self.sound_timer == 0 {
-            sound.stop();
+            false
         } else {
-            sound.play();
             self.sound_timer -= 1;
+            true
+        }
+    }
+
+    // Runs up to `cycles` instructions, stopping early when the program waits for the next
+    // frame or for a key, then updates the timers. Returns whether the buzzer sounds.
+    // Runs the rest of the current frame, which is all of it unless it was stepped into.
+    pub fn run_frame(self: &mut Self, keypad: &mut Keypad, cycles: u32) -> Result<bool, String> {
+        while self.frame_cycle < cycles {
+            keypad.advance(self.frame_cycle, cycles);
+            self.frame_cycle += 1;
+            match self.execute_next_instruction(keypad)? {
+                ExecutionStatus::WaitingForVBlank
+                | ExecutionStatus::WaitingForKey
+                | ExecutionStatus::Break => break,
+                _ => (),
+            }
+        }
+
+        self.frame_cycle = 0;
+        Ok(self.update_timers())
+    }
+
+    // Runs a single instruction of the current frame, e.g. when stepping through a program,
+    // and only ends the frame, ticking the timers, once `cycles` instructions have run or
+    // the program waits for the next frame. Returns whether the buzzer sounds if the frame
+    // ended, or None.
+    pub fn step(self: &mut Self, keypad: &mut Keypad, cycles: u32) -> Result<Option<bool>, String> {
+        // Steps don't happen in emulated time, so they see every key event collected so far.
+        keypad.advance(cycles.saturating_sub(1), cycles);
+        let frame_over = match self.execute_next_instruction(keypad)? {
+            ExecutionStatus::WaitingForVBlank | ExecutionStatus::WaitingForKey => true,
+            // Nothing ran, the next step runs the instruction at the breakpoint.
+            ExecutionStatus::Break => false,
+            _ => {
+                self.frame_cycle += 1;
+                self.frame_cycle >= cycles
+            }
+        };
+        if !frame_over {
+            return Ok(None);
+        }
+
+        self.frame_cycle = 0;
+        Ok(Some(self.update_timers()))
+    }
+
+    pub fn set_trace(self: &mut Self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn framebuffer(self: &Self) -> &[u8; 256] {
+        &self.framebuffer
+    }
+
+    pub fn enable_access_counts(self: &mut Self) {
+        if self.access_counts.is_none() {
+            self.access_counts = Some(AccessCounts::new(self.memory.len()));
+        }
+    }
+
+    pub fn access_counts(self: &Self) -> Option<&AccessCounts> {
+        self.access_counts.as_ref()
+    }
+
+    // Anomalies in memory accesses found since the last call, if access counts are enabled.
+    pub fn take_findings(self: &mut Self) -> Vec<Finding> {
+        match &mut self.access_counts {
+            Some(access_counts) => access_counts.take_findings(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn enable_profiler(self: &mut Self) {
+        if self.profiler.is_none() {
+            self.profiler = Some(Profiler::new(self.memory.len()));
+        }
+    }
+
+    pub fn profiler(self: &Self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn breakpoints(self: &Self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(self: &mut Self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    // The breakpoint or watchpoint that stopped execution, if one did since the last call.
+    pub fn take_stop(self: &mut Self) -> Option<Stop> {
+        self.stop.take()
+    }
+
+    // Keeps undo information for up to `length` instructions, or none if it is 0.
+    pub fn enable_history(self: &mut Self, length: usize) {
+        self.history = if length > 0 { Some(VecDeque::new()) } else { None };
+        self.history_length = length;
+    }
+
+    fn snapshot(self: &Self, opcode: u16) -> Undo {
+        Undo {
+            opcode,
+            program_counter: self.program_counter,
+            registers: self.registers,
+            memory_register: self.memory_register,
+            stack: self.stack,
+            stack_pointer: self.stack_pointer,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            frame_count: self.frame_count,
+            waiting_for_vblank: self.waiting_for_vblank,
+            key_wait: self.key_wait,
+            memory: Vec::new(),
+            framebuffer: Vec::new(),
+            reads: Vec::new(),
+        }
+    }
+
+    fn restore(self: &mut Self, undo: &Undo) {
+        self.program_counter = undo.program_counter;
+        self.registers = undo.registers;
+        self.memory_register = undo.memory_register;
+        self.stack = undo.stack;
+        self.stack_pointer = undo.stack_pointer;
+        self.delay_timer = undo.delay_timer;
+        self.sound_timer = undo.sound_timer;
+        self.frame_count = undo.frame_count;
+        self.waiting_for_vblank = undo.waiting_for_vblank;
+        self.key_wait = undo.key_wait;
+        for &(address, value, written_at) in undo.memory.iter().rev() {
+            self.memory[address] = value;
+            self.written_at[address] = written_at;
+        }
+        for &(index, value) in &undo.framebuffer {
+            self.framebuffer[index as usize] = value;
+        }
+    }
+
+    // Undoes the last instruction. Random numbers, access counts and the profiler aren't
+    // rewound, and neither are memory edits made while paused.
+    pub fn step_back(self: &mut Self) -> Result<(), String> {
+        let undo = self
+            .history
+            .as_mut()
+            .and_then(|history| history.pop_back())
+            .ok_or_else(|| "No earlier instructions in the history.".to_string())?;
+        self.restore(&undo);
+        self.step_over = None;
+        Ok(())
+    }
+
+    // Steps back until a breakpoint would have stopped before the instruction, or a
+    // watchpoint after it, or the history runs out. Returns how many instructions were
+    // undone, and the breakpoint or watchpoint if one was found.
+    pub fn reverse_continue(self: &mut Self) -> (usize, Option<Stop>) {
+        let mut breakpoints = std::mem::take(&mut self.breakpoints);
+        // Conditions that hold now only stop once they didn't hold.
+        breakpoints.check(self);
+
+        let mut steps = 0;
+        let mut stop = None;
+        while let Some(undo) = self.history.as_mut().and_then(|history| history.pop_back()) {
+            self.restore(&undo);
+            steps += 1;
+            let breakpoint = breakpoints.check(self);
+            let reads = undo.reads.iter().map(|&address| (address, Access::Read));
+            let writes = undo
+                .memory
+                .iter()
+                .map(|&(address, ..)| (address, Access::Write));
+            stop = reads
+                .chain(writes)
+                .find_map(|(address, access)| {
+                    breakpoints.check_access(address as u16, access, undo.program_counter)
+                })
+                .or(breakpoint);
+            if stop.is_some() {
+                break;
+            }
+        }
+
+        self.breakpoints = breakpoints;
+        self.step_over = match stop {
+            Some(Stop::Breakpoint {
+                program_counter, ..
+            }) => Some(program_counter),
+            _ => None,
+        };
+        (steps, stop)
+    }
+
+    // The last instruction in the history that wrote to the memory location, or changed the
+    // register.
+    pub fn last_change(self: &Self, location: Location) -> Option<Change> {
+        let history = self.history.as_ref()?;
+        // What an instruction left behind is what the next one started with.
+        let mut after = (self.registers, self.memory_register);
+        for (index, undo) in history.iter().rev().enumerate() {
+            let changed = match location {
+                Location::Register(register) => undo.registers[register] != after.0[register],
+                Location::MemoryRegister => undo.memory_register != after.1,
+                Location::Memory(address) => undo
+                    .memory
+                    .iter()
+                    .any(|&(written, ..)| written == address as usize),
+            };
+            if changed {
+                return Some(Change {
+                    program_counter: undo.program_counter,
+                    opcode: undo.opcode,
+                    instructions_ago: index + 1,
+                });
+            }
+            after = (undo.registers, undo.memory_register);
         }
+        None
+    }
+
+    pub fn set_symbols(self: &mut Self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(self: &Self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Where the ROM is in memory.
+    pub fn rom_range(self: &Self) -> std::ops::Range<u16> {
+        0x200..(0x200 + self.rom_size) as u16
+    }
+
+    pub fn memory(self: &Self) -> &[u8] {
+        &self.memory
+    }
+
+    // For editing memory from the debugger. Edits are highlighted like writes, but aren't
+    // counted as accesses by the program.
+    pub fn set_memory(self: &mut Self, address: u16, value: u8) -> Result<(), String> {
+        if address as usize >= self.memory.len() {
+            return Err(format!("Address {:03X} is out of memory.", address));
+        }
+        self.memory[address as usize] = value;
+        self.written_at[address as usize] = self.frame_count + 1;
+        Ok(())
+    }
+
+    // Whether the byte at the given address was written to during the last `frames` frames.
+    pub fn recently_written(self: &Self, address: u16, frames: u64) -> bool {
+        match self.written_at.get(address as usize) {
+            Some(&0) | None => false,
+            // Edits made while paused aren't undone by stepping back, so they can be stamped
+            // with a later frame than the current one.
+            Some(&written_at) => (self.frame_count + 1).saturating_sub(written_at) <= frames,
+        }
+    }
+
+    pub fn registers(self: &Self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    pub fn memory_register(self: &Self) -> u16 {
+        self.memory_register
+    }
+
+    pub fn program_counter(self: &Self) -> u16 {
+        self.program_counter
+    }
+
+    // The return addresses currently on the stack, oldest first.
+    pub fn stack(self: &Self) -> &[u16] {
+        &self.stack[1..=self.stack_pointer]
+    }
+
+    pub fn delay_timer(self: &Self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(self: &Self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn execute_next_instruction(self: &mut Self, keypad: &Keypad) -> Result<ExecutionStatus, String> {
+        if self.waiting_for_vblank {
+            return Ok(ExecutionStatus::WaitingForVBlank);
+        }
+
+        if self.key_wait.is_some() {
+            return Ok(self.wait_for_key(keypad));
+        }
+
+        // Breakpoints stop before the instruction runs, including the first one of a run.
+        if !self.breakpoints.is_empty() && self.step_over != Some(self.program_counter) {
+            let mut breakpoints = std::mem::take(&mut self.breakpoints);
+            let stop = breakpoints.check(self);
+            self.breakpoints = breakpoints;
+            if stop.is_some() {
+                self.stop = stop;
+                self.step_over = Some(self.program_counter);
+                return Ok(ExecutionStatus::Break);
+            }
+        }
+        self.step_over = None;
 
         let opcode_address = self.program_counter as usize;
         let opcode: u16 =
             ((self.memory[opcode_address] as u16) << 8) | (self.memory[opcode_address + 1] as u16);
         let instruction = Interpreter::decode_opcode(opcode);
-        if true {
-            println!(
-                "{}: 0x{:04X} => {:?}",
-                self.program_counter, opcode, instruction
-            );
+        if let Some(access_counts) = &mut self.access_counts {
+            access_counts.execute(opcode_address);
+            access_counts.execute(opcode_address + 1);
+        }
+        if self.trace {
+            if self.symbols.is_empty() {
+                println!(
+                    "{}: 0x{:04X} => {:?}",
+                    self.program_counter, opcode, instruction
+                );
+            } else {
+                if let Some(label) = self.symbols.label(self.program_counter) {
+                    println!("{}:", label);
+                }
+                println!(
+                    "{}: 0x{:04X} => {:?}  {}",
+                    self.program_counter,
+                    opcode,
+                    instruction,
+                    disassembler::disassemble_with_symbols(opcode, &self.symbols)
+                );
+            }
         }
 
         if let Instruction::INVALID = instruction {
             return Err("Invalid instruction.".to_string());
         }
 
-        self.previous_status = self.execute_instruction(instruction, input);
+        self.undo = self.history.as_ref().map(|_| self.snapshot(opcode));
+        let framebuffer = match (&self.undo, instruction) {
+            (Some(..), Instruction::CLS | Instruction::DRW(..)) => Some(self.framebuffer),
+            _ => None,
+        };
+        let status = self.execute_instruction(instruction, keypad);
+        if let Some(mut undo) = self.undo.take() {
+            if let Some(framebuffer) = framebuffer {
+                undo.framebuffer = framebuffer
+                    .iter()
+                    .zip(self.framebuffer.iter())
+                    .enumerate()
+                    .filter(|(_, (before, after))| before != after)
+                    .map(|(index, (before, _))| (index as u8, *before))
+                    .collect();
+            }
+            if let Some(history) = &mut self.history {
+                if history.len() == self.history_length {
+                    history.pop_front();
+                }
+                history.push_back(undo);
+            }
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(opcode_address as u16, &instruction, self.program_counter);
+        }
 
-        Ok(self.previous_status.clone())
+        if self.stop.is_some() {
+            // A watchpoint stopped execution, but conditions still have to see the change,
+            // so that they don't stop again for it once execution resumes. Breakpoints at
+            // the next address are checked again then.
+            let mut breakpoints = std::mem::take(&mut self.breakpoints);
+            breakpoints.check(self);
+            self.breakpoints = breakpoints;
+            return Ok(ExecutionStatus::Break);
+        }
+
+        Ok(status)
     }
 
     pub fn print_state(self: &Self) {
@@ -475,3 +1100,158 @@ Delay timer: {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD I, #20A; LD V0, [I]; LD [I], V0; JP #206, followed by a data byte at 20A.
+    const PROGRAM: [u8; 11] = [
+        0xa2, 0x0a, 0xf0, 0x65, 0xf0, 0x55, 0x12, 0x06, 0x00, 0x00, 0x42,
+    ];
+
+    fn interpreter() -> Interpreter {
+        let mut interpreter = Interpreter::new(&PROGRAM);
+        interpreter.set_trace(false);
+        interpreter.enable_history(100);
+        interpreter
+    }
+
+    // LD V3, K; ADD V1, 1; JP #204
+    const KEY_WAIT: [u8; 6] = [0xf3, 0x0a, 0x71, 0x01, 0x12, 0x04];
+
+    // Runs 16 ms frames of 10 instructions, one per list of key events given as
+    // (timestamp, key, down), the way the frontends poll and replay them.
+    fn run_key_frames(interpreter: &mut Interpreter, events: &[&[(u32, u8, bool)]]) {
+        let mut keypad = Keypad::new(0);
+        for (frame, frame_events) in events.iter().enumerate() {
+            keypad.begin_frame(16 * (frame as u32 + 1));
+            for &(timestamp, key, down) in frame_events.iter() {
+                keypad.push_event(timestamp, Key::from(key), down);
+            }
+            interpreter.run_frame(&mut keypad, 10).unwrap();
+        }
+    }
+
+    fn key_wait_interpreter(quirks: Quirks) -> Interpreter {
+        let mut interpreter = Interpreter::new(&KEY_WAIT);
+        interpreter.set_trace(false);
+        interpreter.set_quirks(quirks);
+        interpreter
+    }
+
+    #[test]
+    fn key_wait_completes_when_the_key_is_released() {
+        let press: &[&[(u32, u8, bool)]] = &[&[], &[(20, 0x5, true)], &[], &[], &[]];
+        let mut interpreter = key_wait_interpreter(Quirks::default());
+        run_key_frames(&mut interpreter, press);
+        assert_eq!(interpreter.registers()[1], 0);
+
+        let mut interpreter = key_wait_interpreter(Quirks::default());
+        run_key_frames(&mut interpreter, &[press, &[&[(90, 0x5, false)], &[], &[]]].concat());
+        assert_eq!(interpreter.registers()[3], 0x5);
+        assert_eq!(interpreter.registers()[1], 1);
+    }
+
+    #[test]
+    fn key_wait_sees_a_tap_within_a_frame() {
+        let mut interpreter = key_wait_interpreter(Quirks::default());
+        run_key_frames(&mut interpreter, &[&[], &[(20, 0xa, true), (21, 0xa, false)], &[], &[]]);
+        assert_eq!(interpreter.registers()[3], 0xa);
+        assert_eq!(interpreter.registers()[1], 1);
+    }
+
+    #[test]
+    fn key_wait_completes_on_press_without_the_release_quirk() {
+        let mut interpreter = key_wait_interpreter(Quirks {
+            key_release: false,
+            ..Quirks::default()
+        });
+        run_key_frames(&mut interpreter, &[&[], &[(20, 0x7, true)], &[], &[]]);
+        assert_eq!(interpreter.registers()[3], 0x7);
+        assert_eq!(interpreter.registers()[1], 1);
+    }
+
+    #[test]
+    fn timers_run_while_waiting_for_a_key() {
+        // LD V0, 3; LD DT, V0; LD V3, K; ADD V1, 1
+        let mut interpreter = Interpreter::new(&[0x60, 0x03, 0xf0, 0x15, 0xf3, 0x0a, 0x71, 0x01]);
+        interpreter.set_trace(false);
+        run_key_frames(&mut interpreter, &[&[], &[], &[]]);
+        assert_eq!(interpreter.delay_timer(), 0);
+        assert_eq!(interpreter.registers()[1], 0);
+    }
+
+    #[test]
+    fn steps_only_tick_the_timers_at_the_end_of_a_frame() {
+        // LD V0, 5; LD DT, V0; and ADD V1, 1 six times
+        let mut interpreter = Interpreter::new(&[
+            0x60, 0x05, 0xf0, 0x15, 0x71, 0x01, 0x71, 0x01, 0x71, 0x01, 0x71, 0x01, 0x71, 0x01,
+            0x71, 0x01,
+        ]);
+        interpreter.set_trace(false);
+        let mut keypad = Keypad::new(0);
+        for _ in 0..3 {
+            assert_eq!(interpreter.step(&mut keypad, 4).unwrap(), None);
+        }
+        assert_eq!(interpreter.delay_timer(), 5);
+        assert_eq!(interpreter.step(&mut keypad, 4).unwrap(), Some(false));
+        assert_eq!(interpreter.delay_timer(), 4);
+
+        // A frame that was stepped into only runs its remaining instructions.
+        interpreter.step(&mut keypad, 4).unwrap();
+        interpreter.run_frame(&mut keypad, 4).unwrap();
+        assert_eq!(interpreter.program_counter(), 0x210);
+        assert_eq!(interpreter.registers()[1], 6);
+        assert_eq!(interpreter.delay_timer(), 3);
+    }
+
+    #[test]
+    fn subtraction_sets_vf_without_borrow() {
+        // LD V0, 5; LD V1, 5; SUB V0, V1; LD V2, VF; LD V0, 5; SUBN V0, V1; LD V3, VF
+        let mut interpreter = Interpreter::new(&[
+            0x60, 0x05, 0x61, 0x05, 0x80, 0x15, 0x82, 0xf0, 0x60, 0x05, 0x80, 0x17, 0x83, 0xf0,
+        ]);
+        interpreter.set_trace(false);
+        interpreter.run_frame(&mut Keypad::new(0), 7).unwrap();
+        assert_eq!(interpreter.registers()[2], 1);
+        assert_eq!(interpreter.registers()[3], 1);
+    }
+
+    #[test]
+    fn edits_survive_stepping_back() {
+        let mut interpreter = interpreter();
+        let mut keypad = Keypad::new(0);
+        interpreter.run_frame(&mut keypad, 1).unwrap();
+        interpreter.run_frame(&mut keypad, 1).unwrap();
+        interpreter.set_memory(0x300, 1).unwrap();
+        interpreter.step_back().unwrap();
+        interpreter.step_back().unwrap();
+        assert!(interpreter.recently_written(0x300, 60));
+        assert!(!interpreter.recently_written(0x301, 60));
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_read_watchpoints() {
+        let mut interpreter = interpreter();
+        interpreter.breakpoints_mut().add_watchpoint(0x20a..=0x20a, true, false);
+        let mut keypad = Keypad::new(0);
+        interpreter.run_frame(&mut keypad, 10).unwrap();
+        let stop = interpreter.take_stop();
+        assert!(matches!(stop, Some(Stop::Watchpoint { access: Access::Read, .. })));
+        assert_eq!(interpreter.program_counter(), 0x204);
+
+        interpreter.run_frame(&mut keypad, 10).unwrap();
+        let (steps, stop) = interpreter.reverse_continue();
+        assert!(matches!(
+            stop,
+            Some(Stop::Watchpoint {
+                access: Access::Read,
+                program_counter: 0x202,
+                ..
+            })
+        ));
+        assert!(steps > 1);
+        assert_eq!(interpreter.program_counter(), 0x202);
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::fs;
+
+use sdl2::controller::{Axis, Button};
+use sdl2::keyboard::{Keycode, Scancode};
+
+use crate::keypad::Key;
+
+// A host key is either a physical key position (scancode), which is what we want for a
+// keypad so that it keeps its shape on any layout, a layout-dependent keycode, or a game
+// controller input. Stick axes count as pressed in one direction past AXIS_THRESHOLD.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HostKey {
+    Scancode(Scancode),
+    Keycode(Keycode),
+    ControllerButton(Button),
+    ControllerAxis(Axis, AxisDirection),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AxisDirection {
+    Negative,
+    Positive,
+}
+
+pub const AXIS_THRESHOLD: i16 = 16384;
+
+pub struct Keymap {
+    bindings: HashMap<HostKey, Key>,
+}
+
+impl Default for Keymap {
+    // The usual layout, with the left-hand 4x4 block of the keyboard standing in for the keypad:
+    //   1 2 3 4      1 2 3 C
+    //   Q W E R  =>  4 5 6 D
+    //   A S D F      7 8 9 E
+    //   Z X C V      A 0 B F
+    // Game controllers move with the D-pad or the left stick on 2/4/6/8, which most games
+    // use as arrows, and press 5, 0, A and B with the A, B, X and Y buttons.
+    fn default() -> Keymap {
+        let mut keymap = Keymap::empty();
+        let layout = [
+            (Scancode::Num1, 0x1),
+            (Scancode::Num2, 0x2),
+            (Scancode::Num3, 0x3),
+            (Scancode::Num4, 0xc),
+            (Scancode::Q, 0x4),
+            (Scancode::W, 0x5),
+            (Scancode::E, 0x6),
+            (Scancode::R, 0xd),
+            (Scancode::A, 0x7),
+            (Scancode::S, 0x8),
+            (Scancode::D, 0x9),
+            (Scancode::F, 0xe),
+            (Scancode::Z, 0xa),
+            (Scancode::X, 0x0),
+            (Scancode::C, 0xb),
+            (Scancode::V, 0xf),
+        ];
+        for (scancode, key) in layout.iter() {
+            keymap.bind(HostKey::Scancode(*scancode), Key::from(*key));
+        }
+        let controller_layout = [
+            (HostKey::ControllerButton(Button::DPadUp), 0x2),
+            (HostKey::ControllerButton(Button::DPadLeft), 0x4),
+            (HostKey::ControllerButton(Button::DPadRight), 0x6),
+            (HostKey::ControllerButton(Button::DPadDown), 0x8),
+            (
+                HostKey::ControllerAxis(Axis::LeftY, AxisDirection::Negative),
+                0x2,
+            ),
+            (
+                HostKey::ControllerAxis(Axis::LeftX, AxisDirection::Negative),
+                0x4,
+            ),
+            (
+                HostKey::ControllerAxis(Axis::LeftX, AxisDirection::Positive),
+                0x6,
+            ),
+            (
+                HostKey::ControllerAxis(Axis::LeftY, AxisDirection::Positive),
+                0x8,
+            ),
+            (HostKey::ControllerButton(Button::A), 0x5),
+            (HostKey::ControllerButton(Button::B), 0x0),
+            (HostKey::ControllerButton(Button::X), 0xa),
+            (HostKey::ControllerButton(Button::Y), 0xb),
+        ];
+        for (host_key, key) in controller_layout.iter() {
+            keymap.bind(*host_key, Key::from(*key));
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    pub fn empty() -> Keymap {
+        Keymap {
+            bindings: HashMap::new(),
+        }
+    }
+
+    // Keymap files have one binding per line, in the form `<CHIP-8 key> = <host key>`, e.g.
+    //   C = 4
+    //   0 = X
+    //   0 = Keypad 0
+    //   5 = keycode:W
+    //   5 = button:dpup
+    //   7 = axis:leftx-
+    // Host keys are SDL scancode names unless prefixed with `keycode:` (SDL keycode name),
+    // `button:` (SDL game controller button name) or `axis:` (SDL game controller axis name
+    // followed by the direction, `+` or `-`). A CHIP-8 key can appear on several lines to
+    // bind more than one host key to it. `#` starts a comment.
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        let mut keymap = Keymap::empty();
+        for (line_number, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment_start) => &line[..comment_start],
+                None => line,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, host_key) = match line.split_once('=') {
+                Some((key, host_key)) => (key.trim(), host_key.trim()),
+                None => {
+                    return Err(format!(
+                        "Keymap line {}: expected `<key> = <host key>`.",
+                        line_number + 1
+                    ))
+                }
+            };
+            let key = match u8::from_str_radix(key, 16) {
+                Ok(key) if key <= 0xf => Key::from(key),
+                _ => {
+                    return Err(format!(
+                        "Keymap line {}: invalid CHIP-8 key {}.",
+                        line_number + 1,
+                        key
+                    ))
+                }
+            };
+            let host_key = match parse_host_key(host_key) {
+                Some(host_key) => host_key,
+                None => {
+                    return Err(format!(
+                        "Keymap line {}: unknown host key {}.",
+                        line_number + 1,
+                        host_key
+                    ))
+                }
+            };
+            keymap.bind(host_key, key);
+        }
+
+        Ok(keymap)
+    }
+
+    pub fn load(path: &str) -> Result<Keymap, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Keymap::parse(&text),
+            Err(err) => Err(format!("Failed to read keymap {}: {}", path, err)),
+        }
+    }
+
+    pub fn bind(self: &mut Self, host_key: HostKey, key: Key) {
+        self.bindings.insert(host_key, key);
+    }
+
+    // Replaces the bindings of every CHIP-8 key that `other` binds, leaving the rest alone.
+    // This is how per-ROM keymaps only need to mention the keys a game actually uses.
+    pub fn override_with(self: &mut Self, other: &Keymap) {
+        self.bindings
+            .retain(|_, key| !other.bindings.values().any(|other_key| other_key == key));
+        for (host_key, key) in other.bindings.iter() {
+            self.bindings.insert(*host_key, *key);
+        }
+    }
+
+    pub fn lookup(
+        self: &Self,
+        scancode: Option<Scancode>,
+        keycode: Option<Keycode>,
+    ) -> Option<Key> {
+        if let Some(scancode) = scancode {
+            if let Some(key) = self.bindings.get(&HostKey::Scancode(scancode)) {
+                return Some(*key);
+            }
+        }
+        match keycode {
+            Some(keycode) => self.get(HostKey::Keycode(keycode)),
+            None => None,
+        }
+    }
+
+    // Terminals report characters rather than keys, so a character stands for the key that
+    // types it on a US layout, as well as for the keycode it is.
+    pub fn lookup_char(self: &Self, c: char) -> Option<Key> {
+        let c = c.to_ascii_lowercase();
+        self.lookup(
+            Scancode::from_name(&c.to_string()),
+            Keycode::from_i32(c as i32),
+        )
+    }
+
+    pub fn get(self: &Self, host_key: HostKey) -> Option<Key> {
+        self.bindings.get(&host_key).copied()
+    }
+}
+
+fn parse_host_key(name: &str) -> Option<HostKey> {
+    if let Some(keycode) = name.strip_prefix("keycode:") {
+        return Keycode::from_name(keycode.trim()).map(HostKey::Keycode);
+    }
+    if let Some(button) = name.strip_prefix("button:") {
+        return Button::from_string(button.trim()).map(HostKey::ControllerButton);
+    }
+    if let Some(axis) = name.strip_prefix("axis:") {
+        let axis = axis.trim();
+        let (axis, direction) = if let Some(axis) = axis.strip_suffix('+') {
+            (axis, AxisDirection::Positive)
+        } else if let Some(axis) = axis.strip_suffix('-') {
+            (axis, AxisDirection::Negative)
+        } else {
+            return None;
+        };
+        return Axis::from_string(axis).map(|axis| HostKey::ControllerAxis(axis, direction));
+    }
+    Scancode::from_name(name).map(HostKey::Scancode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_kind_of_host_key() {
+        let keymap = Keymap::parse(
+            "# comment\n\nC = 4\n0 = Keypad 0  # trailing comment\n5 = keycode:W\na = button:dpup\n7 = axis:leftx-\n7 = axis:lefty+\n",
+        )
+        .unwrap();
+        assert_eq!(
+            keymap.lookup(Some(Scancode::Num4), None),
+            Some(Key::from(0xc))
+        );
+        assert_eq!(
+            keymap.lookup(Some(Scancode::Kp0), None),
+            Some(Key::from(0x0))
+        );
+        assert_eq!(keymap.lookup(None, Some(Keycode::W)), Some(Key::from(0x5)));
+        assert_eq!(
+            keymap.get(HostKey::ControllerButton(Button::DPadUp)),
+            Some(Key::from(0xa))
+        );
+        for (axis, direction) in [
+            (Axis::LeftX, AxisDirection::Negative),
+            (Axis::LeftY, AxisDirection::Positive),
+        ] {
+            assert_eq!(
+                keymap.get(HostKey::ControllerAxis(axis, direction)),
+                Some(Key::from(0x7))
+            );
+        }
+        assert_eq!(keymap.lookup(Some(Scancode::Q), Some(Keycode::Q)), None);
+    }
+
+    #[test]
+    fn scancodes_take_precedence_over_keycodes() {
+        let keymap = Keymap::parse("1 = Q\n2 = keycode:A").unwrap();
+        // An AZERTY keyboard types A with the key where QWERTY has Q.
+        assert_eq!(
+            keymap.lookup(Some(Scancode::Q), Some(Keycode::A)),
+            Some(Key::from(0x1))
+        );
+        assert_eq!(
+            keymap.lookup(Some(Scancode::W), Some(Keycode::A)),
+            Some(Key::from(0x2))
+        );
+    }
+
+    #[test]
+    fn reports_invalid_lines() {
+        for (text, err) in [
+            ("1 Q", "Keymap line 1: expected `<key> = <host key>`."),
+            ("\n10 = Q", "Keymap line 2: invalid CHIP-8 key 10."),
+            ("1 = Nope", "Keymap line 1: unknown host key Nope."),
+            (
+                "1 = axis:leftx",
+                "Keymap line 1: unknown host key axis:leftx.",
+            ),
+            (
+                "1 = button:nope",
+                "Keymap line 1: unknown host key button:nope.",
+            ),
+        ] {
+            assert_eq!(Keymap::parse(text).err().as_deref(), Some(err), "{}", text);
+        }
+    }
+
+    #[test]
+    fn overriding_rebinds_only_the_keys_mentioned() {
+        let mut keymap = Keymap::default();
+        keymap.override_with(&Keymap::parse("5 = Up\n5 = keycode:Space").unwrap());
+        assert_eq!(
+            keymap.lookup(Some(Scancode::Up), None),
+            Some(Key::from(0x5))
+        );
+        assert_eq!(
+            keymap.lookup(None, Some(Keycode::Space)),
+            Some(Key::from(0x5))
+        );
+        // The default binding of 5 is gone, the others are kept.
+        assert_eq!(keymap.lookup(Some(Scancode::W), None), None);
+        assert_eq!(keymap.lookup(Some(Scancode::Q), None), Some(Key::from(0x4)));
+    }
+
+    #[test]
+    fn controllers_are_bound_by_default() {
+        let keymap = Keymap::default();
+        let bindings = [
+            (HostKey::ControllerButton(Button::DPadUp), 0x2),
+            (HostKey::ControllerButton(Button::DPadRight), 0x6),
+            (
+                HostKey::ControllerAxis(Axis::LeftX, AxisDirection::Negative),
+                0x4,
+            ),
+            (
+                HostKey::ControllerAxis(Axis::LeftY, AxisDirection::Positive),
+                0x8,
+            ),
+            (HostKey::ControllerButton(Button::A), 0x5),
+            (HostKey::ControllerButton(Button::Y), 0xb),
+        ];
+        for (host_key, key) in bindings {
+            assert_eq!(keymap.get(host_key), Some(Key::from(key)));
+        }
+        assert_eq!(keymap.get(HostKey::ControllerButton(Button::Start)), None);
+        assert_eq!(
+            keymap.get(HostKey::ControllerAxis(
+                Axis::RightX,
+                AxisDirection::Positive
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn overriding_a_key_drops_its_controller_bindings() {
+        let mut keymap = Keymap::default();
+        keymap.override_with(&Keymap::parse("2 = button:a").unwrap());
+        assert_eq!(
+            keymap.get(HostKey::ControllerButton(Button::A)),
+            Some(Key::from(0x2))
+        );
+        assert_eq!(keymap.get(HostKey::ControllerButton(Button::DPadUp)), None);
+        assert_eq!(
+            keymap.get(HostKey::ControllerAxis(
+                Axis::LeftY,
+                AxisDirection::Negative
+            )),
+            None
+        );
+        assert_eq!(
+            keymap.get(HostKey::ControllerButton(Button::DPadLeft)),
+            Some(Key::from(0x4))
+        );
+    }
+
+    #[test]
+    fn characters_find_their_keys() {
+        let mut keymap = Keymap::default();
+        keymap.override_with(&Keymap::parse("5 = keycode:+\n6 = K").unwrap());
+        assert_eq!(keymap.lookup_char('q'), Some(Key::from(0x4)));
+        assert_eq!(keymap.lookup_char('V'), Some(Key::from(0xf)));
+        assert_eq!(keymap.lookup_char('+'), Some(Key::from(0x5)));
+        assert_eq!(keymap.lookup_char('k'), Some(Key::from(0x6)));
+        assert_eq!(keymap.lookup_char('w'), None);
+        assert_eq!(keymap.lookup_char('\0'), None);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Key0 = 0,
+    Key1 = 1,
+    Key2 = 2,
+    Key3 = 3,
+    Key4 = 4,
+    Key5 = 5,
+    Key6 = 6,
+    Key7 = 7,
+    Key8 = 8,
+    Key9 = 9,
+    KeyA = 0xa,
+    KeyB = 0xb,
+    KeyC = 0xc,
+    KeyD = 0xd,
+    KeyE = 0xe,
+    KeyF = 0xf,
+}
+
+impl From<u8> for Key {
+    fn from(value: u8) -> Key {
+        match value {
+            0 => Key::Key0,
+            1 => Key::Key1,
+            2 => Key::Key2,
+            3 => Key::Key3,
+            4 => Key::Key4,
+            5 => Key::Key5,
+            6 => Key::Key6,
+            7 => Key::Key7,
+            8 => Key::Key8,
+            9 => Key::Key9,
+            0xa => Key::KeyA,
+            0xb => Key::KeyB,
+            0xc => Key::KeyC,
+            0xd => Key::KeyD,
+            0xe => Key::KeyE,
+            0xf => Key::KeyF,
+            _ => panic!("Invalid Key."),
+        }
+    }
+}
+
+// The low bit is set on every advance, which turns the edge states (pressed, released)
+// into their steady counterparts (down, up) one instruction after the edge.
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyState {
+    KeyReleased = 0b00,
+    KeyUp = 0b01,
+    KeyPressed = 0b10,
+    KeyDown = 0b11,
+}
+
+impl KeyState {
+    pub fn is_down(self: &Self) -> bool {
+        *self == KeyState::KeyPressed || *self == KeyState::KeyDown
+    }
+}
+
+impl From<u8> for KeyState {
+    fn from(value: u8) -> KeyState {
+        match value {
+            0b00 => KeyState::KeyReleased,
+            0b01 => KeyState::KeyUp,
+            0b10 => KeyState::KeyPressed,
+            0b11 => KeyState::KeyDown,
+            _ => panic!("Invalid KeyState value."),
+        }
+    }
+}
+
+// A change of a CHIP-8 key, with the timestamp (in milliseconds) of the host event.
+struct KeyEvent {
+    timestamp: u32,
+    key: Key,
+    down: bool,
+}
+
+// Key events are not applied as soon as they are collected. They are queued and replayed
+// by `advance` at the instruction boundaries that correspond to their timestamps, spreading
+// the events of one frame over the instructions executed in the next one. That way a quick
+// tap is seen by the instructions that run while it lasts, rather than being lost between
+// two polls. Frontends feed it with `begin_frame` and `push_event`.
+pub struct Keypad {
+    chip8_keys: [KeyState; 0x10],
+    events: VecDeque<KeyEvent>,
+    // Host time at the previous and at the latest poll.
+    frame_start: u32,
+    frame_end: u32,
+}
+
+impl Keypad {
+    pub fn new(now: u32) -> Keypad {
+        Keypad {
+            chip8_keys: [KeyState::KeyUp; 0x10],
+            events: VecDeque::new(),
+            frame_start: now,
+            frame_end: now,
+        }
+    }
+
+    // Called each time the frontend polls its input, before pushing the new events.
+    pub fn begin_frame(self: &mut Self, now: u32) {
+        self.frame_start = self.frame_end;
+        self.frame_end = now;
+    }
+
+    pub fn push_event(self: &mut Self, timestamp: u32, key: Key, down: bool) {
+        self.events.push_back(KeyEvent {
+            timestamp,
+            key,
+            down,
+        });
+    }
+
+    // Brings the CHIP-8 keys up to date for the given instruction out of the ones executed
+    // this frame. Each key changes at most once per call, so that a press and release that
+    // fall between the same two instructions are still seen by one of them.
+    pub fn advance(self: &mut Self, instruction: u32, instructions_per_frame: u32) {
+        for i in 0..self.chip8_keys.len() {
+            self.chip8_keys[i] = KeyState::from(self.chip8_keys[i] as u8 | 1);
+        }
+
+        let frame_duration = self.frame_end.wrapping_sub(self.frame_start) as u64;
+        // The last instruction of the frame sees everything collected up to this frame.
+        let offset =
+            frame_duration * (instruction as u64 + 1) / instructions_per_frame.max(1) as u64;
+        let now = self.frame_start.wrapping_add(offset as u32);
+
+        let mut changed = [false; 0x10];
+        while let Some(event) = self.events.front() {
+            if event.timestamp > now || changed[event.key as usize] {
+                break;
+            }
+            let key = event.key as usize;
+            self.chip8_keys[key] = if event.down {
+                KeyState::KeyPressed
+            } else {
+                KeyState::KeyReleased
+            };
+            changed[key] = true;
+            self.events.pop_front();
+        }
+    }
+
+    // Applies every queued event at once, for when no instructions run, e.g. while paused.
+    // Keys end up settled, so that the program doesn't see presses and releases it missed
+    // once it resumes.
+    pub fn settle(self: &mut Self) {
+        for event in self.events.drain(..) {
+            self.chip8_keys[event.key as usize] = if event.down {
+                KeyState::KeyDown
+            } else {
+                KeyState::KeyUp
+            };
+        }
+    }
+
+    pub fn any_key_pressed(self: &Self) -> Option<Key> {
+        match self
+            .chip8_keys
+            .iter()
+            .position(|&k| k == KeyState::KeyPressed)
+        {
+            Some(idx) => Some(Key::from(idx as u8)),
+            None => None,
+        }
+    }
+
+    pub fn any_key_released(self: &Self) -> Option<Key> {
+        self.chip8_keys
+            .iter()
+            .position(|&k| k == KeyState::KeyReleased)
+            .map(|idx| Key::from(idx as u8))
+    }
+
+    pub fn get_key_state(self: &Self, key: Key) -> KeyState {
+        return self.chip8_keys[key as usize];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The state of every key after each of the 10 instructions of a 100 ms frame.
+    fn replay(events: &[(u32, u8, bool)], keys: &[u8]) -> Vec<Vec<u8>> {
+        let mut keypad = Keypad::new(0);
+        keypad.begin_frame(100);
+        for &(timestamp, key, down) in events {
+            keypad.push_event(timestamp, Key::from(key), down);
+        }
+        (0..10)
+            .map(|instruction| {
+                keypad.advance(instruction, 10);
+                keys.iter()
+                    .map(|&key| keypad.get_key_state(Key::from(key)) as u8)
+                    .collect()
+            })
+            .collect()
+    }
+
+    const UP: u8 = KeyState::KeyUp as u8;
+    const PRESSED: u8 = KeyState::KeyPressed as u8;
+    const DOWN: u8 = KeyState::KeyDown as u8;
+    const RELEASED: u8 = KeyState::KeyReleased as u8;
+
+    #[test]
+    fn events_are_replayed_at_their_time_in_the_frame() {
+        let states = replay(
+            &[(15, 0x1, true), (55, 0x2, true), (72, 0x1, false)],
+            &[1, 2],
+        );
+        assert_eq!(states[0], [UP, UP]);
+        assert_eq!(states[1], [PRESSED, UP]);
+        assert_eq!(states[2], [DOWN, UP]);
+        assert_eq!(states[5], [DOWN, PRESSED]);
+        assert_eq!(states[7], [RELEASED, DOWN]);
+        assert_eq!(states[9], [UP, DOWN]);
+    }
+
+    #[test]
+    fn quick_taps_are_seen_by_an_instruction_each() {
+        let states = replay(&[(41, 0x3, true), (42, 0x3, false), (43, 0x3, true)], &[3]);
+        let states: Vec<u8> = states.iter().map(|state| state[0]).collect();
+        assert_eq!(
+            states,
+            [UP, UP, UP, UP, PRESSED, RELEASED, PRESSED, DOWN, DOWN, DOWN]
+        );
+    }
+
+    #[test]
+    fn every_event_of_the_frame_is_applied_by_its_last_instruction() {
+        let states = replay(&[(100, 0xf, true)], &[0xf]);
+        assert_eq!(states[8], [UP]);
+        assert_eq!(states[9], [PRESSED]);
+    }
+
+    #[test]
+    fn later_events_wait_for_earlier_ones() {
+        // Events are replayed in the order they happened, so one held back behind a change
+        // of the same key also holds back those of other keys.
+        let states = replay(
+            &[(30, 0x4, true), (30, 0x4, false), (30, 0x5, true)],
+            &[4, 5],
+        );
+        assert_eq!(states[2], [PRESSED, UP]);
+        assert_eq!(states[3], [RELEASED, PRESSED]);
+        assert_eq!(states[4], [UP, DOWN]);
+    }
+
+    #[test]
+    fn settling_applies_everything_at_once() {
+        let mut keypad = Keypad::new(0);
+        keypad.push_event(5, Key::Key1, true);
+        keypad.push_event(6, Key::Key2, true);
+        keypad.push_event(7, Key::Key2, false);
+        keypad.settle();
+        assert!(keypad.get_key_state(Key::Key1) == KeyState::KeyDown);
+        assert!(keypad.get_key_state(Key::Key2) == KeyState::KeyUp);
+        assert_eq!(keypad.any_key_pressed(), None);
+        assert_eq!(keypad.any_key_released(), None);
+    }
+}
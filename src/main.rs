@@ -1,15 +1,46 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
+use std::path::Path;
 
 extern crate sdl2;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+pub mod disassembler;
+pub mod access;
+pub mod assembler;
+pub mod breakpoints;
+pub mod cfg;
+pub mod console;
+pub mod coverage;
+pub mod decompiler;
 pub mod display;
+pub mod font;
+pub mod headless;
+pub mod image;
 pub mod input;
 pub mod interpreter;
+pub mod keymap;
+pub mod keypad;
+pub mod memory_view;
+pub mod octo;
+pub mod octo_compiler;
+pub mod overlay;
+pub mod palette;
+pub mod profiler;
+pub mod recorder;
 pub mod sound;
+pub mod symbols;
+pub mod tui;
+pub mod wav;
+
+use keymap::Keymap;
+use octo::OctoOptions;
+use palette::Palette;
+use recorder::{Recorder, RecordingFormat};
+use symbols::Symbols;
+use wav::WavWriter;
 
 fn load_bytes_from_file(path: &String) -> Result<Vec<u8>, String> {
     let mut bytes: Vec<u8> = Vec::new();
@@ -22,37 +53,467 @@ fn load_bytes_from_file(path: &String) -> Result<Vec<u8>, String> {
     Ok(bytes)
 }
 
+// Loads a ROM, unpacking it first if it is an Octo cartridge. Cartridges can carry their
+// own options, which are returned alongside the program.
+fn load_rom(path: &String) -> Result<(Vec<u8>, Option<OctoOptions>), String> {
+    let bytes = load_bytes_from_file(path)?;
+    if octo::is_cartridge(&bytes) {
+        let cartridge = octo::load_cartridge(&bytes)?;
+        return Ok((cartridge.program, cartridge.options));
+    }
+
+    Ok((bytes, None))
+}
+
+fn load_octo_options(path: &String) -> Result<OctoOptions, String> {
+    let bytes = load_bytes_from_file(path)?;
+    match String::from_utf8(bytes) {
+        Ok(json) => OctoOptions::from_json(&json),
+        Err(..) => Err("Octo options are not valid UTF-8.".to_string()),
+    }
+}
+
+// The keymap is the default one, or the one given on the command line, with bindings from
+// a `.keymap` file next to the ROM (e.g. `game.ch8.keymap`) taking precedence.
+fn load_keymap(keymap_path: &Option<String>, rom_path: &String) -> Result<Keymap, String> {
+    let mut keymap = match keymap_path {
+        Some(path) => Keymap::load(path)?,
+        None => Keymap::default(),
+    };
+
+    let rom_keymap_path = format!("{}.keymap", rom_path);
+    if Path::new(&rom_keymap_path).exists() {
+        keymap.override_with(&Keymap::load(&rom_keymap_path)?);
+    }
+
+    Ok(keymap)
+}
+
+// The symbols given on the command line, or else the ones in a `.sym` file next to the ROM
+// (e.g. `game.ch8.sym`), if there is one.
+fn load_symbols(symbols_path: &Option<String>, rom_path: &String) -> Result<Symbols, String> {
+    if let Some(path) = symbols_path {
+        return Symbols::load(path);
+    }
+
+    let rom_symbols_path = format!("{}.sym", rom_path);
+    if Path::new(&rom_symbols_path).exists() {
+        return Symbols::load(&rom_symbols_path);
+    }
+
+    Ok(Symbols::default())
+}
+
+// Writes the ROM along with its symbols, which end up where `load_symbols` looks for them.
+fn assemble(source_path: &str, rom_path: &str) -> Result<(), String> {
+    let source = fs::read_to_string(source_path)
+        .map_err(|e| format!("Reading {}: {}", source_path, e))?;
+    let assembly = assembler::assemble(&source).map_err(|e| format!("{}: {}", source_path, e))?;
+    fs::write(rom_path, &assembly.rom).map_err(|e| format!("Writing {}: {}", rom_path, e))?;
+    let symbols_path = format!("{}.sym", rom_path);
+    fs::write(&symbols_path, assembly.symbols.to_text())
+        .map_err(|e| format!("Writing {}: {}", symbols_path, e))?;
+    println!(
+        "Assembled {} bytes into {} with symbols {}",
+        assembly.rom.len(),
+        rom_path,
+        symbols_path
+    );
+    Ok(())
+}
+
+// Recordings keep the scale the screen had when they were started.
+fn start_recording(display: &display::Display, path: &str) -> Result<Recorder, String> {
+    let scale = display.scale()?;
+    let frame = display.image(scale);
+    let recorder = Recorder::start(path, scale, frame.width, frame.height)?;
+    println!("Recording to {}", recorder.path());
+    Ok(recorder)
+}
+
+fn stop_recording(recorder: Recorder) {
+    let path = recorder.path().to_string();
+    match recorder.finish() {
+        Ok(()) => println!("Saved recording {}", path),
+        Err(err) => println!("Failed to save recording: {}", err),
+    }
+}
+
+fn start_audio_recording(sound: &sound::Sound, path: &str) -> Result<WavWriter, String> {
+    let wav = WavWriter::create(path, sound.sample_rate())?;
+    println!("Recording audio to {}", wav.path());
+    Ok(wav)
+}
+
+fn stop_audio_recording(wav: WavWriter) {
+    let path = wav.path().to_string();
+    match wav.finish() {
+        Ok(()) => println!("Saved audio {}", path),
+        Err(err) => println!("Failed to save audio: {}", err),
+    }
+}
+
+fn report_findings(interpreter: &mut interpreter::Interpreter) {
+    for finding in interpreter.take_findings() {
+        println!("{}", finding);
+    }
+}
+
+// Analyses of the program's execution, written when the emulator exits.
+#[derive(Default)]
+struct Reports {
+    heatmap_path: Option<String>,
+    profile_path: Option<String>,
+    coverage_path: Option<String>,
+}
+
+impl Reports {
+    fn enable(self: &Self, interpreter: &mut interpreter::Interpreter) {
+        if self.heatmap_path.is_some() || self.coverage_path.is_some() {
+            interpreter.enable_access_counts();
+        }
+        if self.profile_path.is_some() {
+            interpreter.enable_profiler();
+        }
+    }
+
+    fn save(self: &Self, interpreter: &interpreter::Interpreter) {
+        // Heatmaps are scaled up, as one pixel per address is hard to make out.
+        let access_counts = interpreter.access_counts();
+        if let (Some(path), Some(access_counts)) = (&self.heatmap_path, access_counts) {
+            match access_counts.heatmap().scaled(8).save_png(path) {
+                Ok(()) => println!("Saved heatmap {}", path),
+                Err(err) => println!("Failed to save heatmap: {}", err),
+            }
+        }
+        if let (Some(path), Some(profiler)) = (&self.profile_path, interpreter.profiler()) {
+            match fs::write(path, profiler.report(interpreter.memory())) {
+                Ok(()) => println!("Saved profile {}", path),
+                Err(err) => println!("Failed to save profile {}: {}", path, err),
+            }
+        }
+        if let (Some(path), Some(access_counts)) = (&self.coverage_path, access_counts) {
+            let report = coverage::report(
+                interpreter.memory(),
+                interpreter.rom_range(),
+                access_counts,
+            );
+            match fs::write(path, report) {
+                Ok(()) => println!("Saved coverage {}", path),
+                Err(err) => println!("Failed to save coverage {}: {}", path, err),
+            }
+        }
+    }
+}
+
 fn main() {
     let mut step_mode = false;
+    let mut octo_options_path: Option<String> = None;
+    let mut keymap_path: Option<String> = None;
+    let mut cycles_per_frame: u32 = 1;
+    let mut key_wait_press = false;
+    let mut fullscreen = false;
+    let mut scaling = display::Scaling::Integer;
+    let mut persistence = display::Persistence::Off;
+    // Where screenshots and recordings started with the hotkeys are saved.
+    let mut capture_directory = ".".to_string();
+    let mut record_path: Option<String> = None;
+    let mut record_format = RecordingFormat::Gif;
+    let mut record_audio_path: Option<String> = None;
+    // Run this many frames without a window, audio device or input, then exit.
+    let mut headless_frames: Option<u64> = None;
+    let mut palettes = Palette::built_in();
+    let mut palette_index = 0;
+    let mut palette_chosen = false;
+    let mut tickrate_chosen = false;
+    let mut use_tui = false;
+    let mut use_console = false;
+    let mut reports = Reports::default();
+    // Analyse the ROM's control flow, write it as DOT and exit without running anything.
+    let mut cfg_path: Option<String> = None;
+    // Lift the ROM into pseudocode, write it and exit without running anything.
+    let mut decompile_path: Option<String> = None;
+    let mut symbols_path: Option<String> = None;
+    let mut history_length = console::HISTORY_LENGTH;
+    let mut tone = sound::Tone::default();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args = env::args().skip(1);
 
     let mut rom_path: String = "".to_string();
-    for arg in args.into_iter() {
+    while let Some(arg) = args.next() {
         if arg == "--step" {
             step_mode = true;
+        } else if arg == "--octo-options" {
+            octo_options_path = Some(args.next().expect("--octo-options requires a path."));
+        } else if arg == "--keymap" {
+            keymap_path = Some(args.next().expect("--keymap requires a path."));
+        } else if arg == "--key-wait-press" {
+            key_wait_press = true;
+        } else if arg == "--fullscreen" {
+            fullscreen = true;
+        } else if arg == "--scaling" {
+            let name = args.next().expect("--scaling requires either integer or aspect.");
+            scaling = display::Scaling::from_name(&name).unwrap();
+        } else if arg == "--phosphor" {
+            let decay: f32 = args
+                .next()
+                .and_then(|decay| decay.parse().ok())
+                // Pixels would never fade with a decay of 1.
+                .filter(|decay| (0.0..1.0).contains(decay))
+                .expect("--phosphor requires a decay from 0 up to but excluding 1.");
+            persistence = display::Persistence::Decay(decay);
+        } else if arg == "--flicker-filter" {
+            persistence = display::Persistence::TwoFrames;
+        } else if arg == "--capture-dir" {
+            capture_directory = args.next().expect("--capture-dir requires a path.");
+        } else if arg == "--record" {
+            record_path = Some(args.next().expect("--record requires a .gif or .y4m path."));
+        } else if arg == "--record-audio" {
+            record_audio_path = Some(args.next().expect("--record-audio requires a .wav path."));
+        } else if arg == "--headless" {
+            headless_frames = Some(
+                args.next()
+                    .and_then(|frames| frames.parse().ok())
+                    .expect("--headless requires a number of frames."),
+            );
+        } else if arg == "--record-format" {
+            record_format = match args.next().as_deref() {
+                Some("gif") => RecordingFormat::Gif,
+                Some("y4m") => RecordingFormat::Y4m,
+                _ => panic!("--record-format requires either gif or y4m."),
+            };
+        } else if arg == "--palette" {
+            // Can be given several times. The first one is used at startup; all of them
+            // can then be cycled through at runtime.
+            let name_or_path = args.next().expect("--palette requires a name or path.");
+            let palette = Palette::find(&name_or_path).unwrap();
+            let index = match palettes.iter().position(|p| p.name == palette.name) {
+                Some(index) => {
+                    palettes[index] = palette;
+                    index
+                }
+                None => {
+                    palettes.push(palette);
+                    palettes.len() - 1
+                }
+            };
+            if !palette_chosen {
+                palette_index = index;
+                palette_chosen = true;
+            }
+        } else if arg == "--waveform" {
+            let name = args.next().expect("--waveform requires a waveform name.");
+            tone.waveform = sound::Waveform::from_name(&name).unwrap();
+        } else if arg == "--frequency" {
+            tone.frequency = args
+                .next()
+                .and_then(|frequency| frequency.parse().ok())
+                .expect("--frequency requires a frequency in Hz.");
+        } else if arg == "--volume" {
+            let volume: f32 = args
+                .next()
+                .and_then(|volume| volume.parse().ok())
+                .expect("--volume requires a volume between 0 and 1.");
+            tone.volume = volume.clamp(0.0, 1.0);
+        } else if arg == "--heatmap" {
+            reports.heatmap_path = Some(args.next().expect("--heatmap requires a .png path."));
+        } else if arg == "--cfg" {
+            cfg_path = Some(args.next().expect("--cfg requires a .dot path."));
+        } else if arg == "--symbols" {
+            symbols_path = Some(args.next().expect("--symbols requires a path."));
+        } else if arg == "--assemble" {
+            let source_path = args.next().expect("--assemble requires a source path.");
+            let output_path = args.next().expect("--assemble requires a ROM path.");
+            assemble(&source_path, &output_path).unwrap();
+            return;
+        } else if arg == "--decompile" {
+            decompile_path = Some(args.next().expect("--decompile requires a path."));
+        } else if arg == "--coverage" {
+            reports.coverage_path = Some(args.next().expect("--coverage requires a path."));
+        } else if arg == "--profile" {
+            reports.profile_path = Some(args.next().expect("--profile requires a path."));
+        } else if arg == "--console" {
+            use_console = true;
+        } else if arg == "--history" {
+            history_length = args
+                .next()
+                .and_then(|length| length.parse().ok())
+                .expect("--history requires a number of instructions.");
+        } else if arg == "--tui" {
+            use_tui = true;
+        } else if arg == "--tickrate" {
+            cycles_per_frame = args
+                .next()
+                .and_then(|tickrate| tickrate.parse().ok())
+                .expect("--tickrate requires a number of instructions per frame.");
+            tickrate_chosen = true;
         } else {
             rom_path = arg;
         }
     }
 
+    let (rom, cartridge_options) = load_rom(&rom_path).unwrap();
+    // Options given on the command line take precedence over the ones embedded in a cartridge.
+    let octo_options = match octo_options_path {
+        Some(path) => Some(load_octo_options(&path).unwrap()),
+        None => cartridge_options,
+    };
+
+    let mut interpreter = interpreter::Interpreter::new(rom.as_slice());
+    interpreter.set_symbols(load_symbols(&symbols_path, &rom_path).unwrap());
+
+    let mut quirks = interpreter::Quirks {
+        key_release: !key_wait_press,
+        ..Default::default()
+    };
+    let mut rotation = 0;
+    if let Some(options) = &octo_options {
+        options.apply_quirks(&mut quirks);
+
+        if let Some(tickrate) = options.tickrate {
+            if !tickrate_chosen {
+                cycles_per_frame = tickrate;
+            }
+        }
+        if let Some(palette) = options.palette() {
+            palettes.push(palette);
+            if !palette_chosen {
+                palette_index = palettes.len() - 1;
+            }
+        }
+        if let Some(screen_rotation) = options.screen_rotation {
+            rotation = screen_rotation;
+        }
+    }
+    interpreter.set_quirks(quirks);
+    reports.enable(&mut interpreter);
+
+    if let Some(path) = &cfg_path {
+        let graph = cfg::ControlFlowGraph::analyse(interpreter.memory(), 0x200);
+        print!("{}", graph.summary(interpreter.rom_range()));
+        fs::write(path, graph.to_dot(interpreter.memory())).unwrap();
+        println!("Saved control-flow graph {}", path);
+        return;
+    }
+
+    if let Some(path) = &decompile_path {
+        let graph = cfg::ControlFlowGraph::analyse(interpreter.memory(), 0x200);
+        let pseudocode = decompiler::decompile(
+            interpreter.memory(),
+            &graph,
+            interpreter.rom_range(),
+            &quirks,
+        );
+        fs::write(path, pseudocode).unwrap();
+        println!("Saved pseudocode {}", path);
+        return;
+    }
+
+    if let Some(frames) = headless_frames {
+        interpreter.set_trace(false);
+        let outputs = headless::Outputs {
+            audio_path: record_audio_path.as_deref(),
+            video_path: record_path.as_deref(),
+            palette: &palettes[palette_index],
+            rotation,
+        };
+        headless::run(&mut interpreter, cycles_per_frame, frames, tone, &outputs).unwrap();
+        report_findings(&mut interpreter);
+        reports.save(&interpreter);
+        return;
+    }
+
+    if use_tui {
+        // The terminal is the screen, so instructions can't be traced to it.
+        interpreter.set_trace(false);
+        let keymap = load_keymap(&keymap_path, &rom_path).unwrap();
+        tui::run(
+            &mut interpreter,
+            cycles_per_frame,
+            &palettes[palette_index],
+            &keymap,
+        )
+        .unwrap();
+        report_findings(&mut interpreter);
+        reports.save(&interpreter);
+        return;
+    }
+
     let sdl_context = sdl2::init().unwrap();
-    let mut display = display::Display::new(&sdl_context).unwrap();
-    let mut sound = sound::Sound::new(&sdl_context).unwrap();
-    let mut input = input::Input::new();
+    let mut display = match display::Display::new(&sdl_context) {
+        Ok(display) => display,
+        Err(err) => {
+            println!("Warning: no video output ({}), running without a window.", err);
+            display::Display::null()
+        }
+    };
+    display.set_scaling(scaling);
+    display.set_persistence(persistence);
+    display.set_rotation(rotation).unwrap();
+    display.set_palette(palettes[palette_index].clone());
+    if fullscreen {
+        display.toggle_fullscreen().unwrap();
+    }
+    let mut sound = match sound::Sound::new(&sdl_context, tone) {
+        Ok(sound) => sound,
+        Err(err) => {
+            println!("Warning: no audio output ({}), running without sound.", err);
+            sound::Sound::null(tone)
+        }
+    };
+    let mut input = input::Input::new(
+        load_keymap(&keymap_path, &rom_path).unwrap(),
+        sdl_context.game_controller().unwrap(),
+        sdl_context.timer().unwrap(),
+    );
 
-    let mut interpreter =
-        interpreter::Interpreter::new(load_bytes_from_file(&rom_path).unwrap().as_slice());
+    let mut recorder: Option<Recorder> = None;
+    if let Some(path) = &record_path {
+        recorder = Some(start_recording(&display, path).unwrap());
+    }
+    let mut audio_recorder: Option<WavWriter> = None;
+    if let Some(path) = &record_audio_path {
+        audio_recorder = Some(start_audio_recording(&sound, path).unwrap());
+    }
 
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut step_mode_active = step_mode;
+    let mut overlay_visible = false;
+    let mut memory_view: Option<memory_view::MemoryView> = None;
+    let console = if use_console {
+        // The instruction trace would drown out the console.
+        interpreter.set_trace(false);
+        interpreter.enable_history(history_length);
+        println!("{}", console::HELP);
+        Some(console::Console::start())
+    } else {
+        None
+    };
+    // Instructions requested by the console's step command.
+    let mut pending_steps: u32 = 0;
     let mut next_instruction;
+    // Frames follow the clock rather than sleeping a whole frame on top of the work, which
+    // would run below 60 Hz and leave the audio device short of samples.
+    let frame_duration = Duration::from_secs(1) / sound::FRAMES_PER_SECOND;
+    let mut next_frame = Instant::now() + frame_duration;
     'running: loop {
         next_instruction = !step_mode_active;
+        if step_mode_active && pending_steps > 0 {
+            next_instruction = true;
+            pending_steps -= 1;
+        }
 
+        input.editor_focused = overlay_visible && memory_view.is_some();
         input.collect(&mut event_pump);
 
         if input.quit {
+            if let Some(recorder) = recorder.take() {
+                stop_recording(recorder);
+            }
+            if let Some(wav) = audio_recorder.take() {
+                stop_audio_recording(wav);
+            }
+            reports.save(&interpreter);
             break 'running;
         }
 
@@ -69,15 +530,247 @@ fn main() {
             next_instruction = !step_mode_active || !next_instruction;
         }
 
+        if input.toggle_fullscreen {
+            display.toggle_fullscreen().unwrap();
+        }
+
+        if input.next_palette {
+            palette_index = (palette_index + 1) % palettes.len();
+            display.set_palette(palettes[palette_index].clone());
+            println!("Palette: {}", palettes[palette_index].name);
+        }
+
+        if input.screenshot {
+            match display.save_screenshot(&capture_directory) {
+                Ok((native_path, scaled_path)) => {
+                    println!("Saved screenshots {} and {}", native_path, scaled_path)
+                }
+                Err(err) => println!("Failed to save screenshot: {}", err),
+            }
+        }
+
+        // Recordings started with the hotkey get their audio in a WAV file next to them.
+        if input.toggle_recording {
+            match recorder.take() {
+                Some(recorder) => {
+                    stop_recording(recorder);
+                    if let Some(wav) = audio_recorder.take() {
+                        stop_audio_recording(wav);
+                    }
+                }
+                None => {
+                    let name = format!("{}/recording-{}", capture_directory, image::timestamp());
+                    let path = format!("{}.{}", name, record_format.extension());
+                    match start_recording(&display, &path) {
+                        Ok(started) => recorder = Some(started),
+                        Err(err) => println!("Failed to start recording: {}", err),
+                    }
+                    if audio_recorder.is_none() {
+                        match start_audio_recording(&sound, &format!("{}.wav", name)) {
+                            Ok(started) => audio_recorder = Some(started),
+                            Err(err) => println!("Failed to start recording audio: {}", err),
+                        }
+                    }
+                }
+            }
+        }
+
+        if input.toggle_mute {
+            if sound.toggle_mute() {
+                println!("Sound muted");
+            } else {
+                println!("Sound unmuted");
+            }
+        }
+
+        if input.toggle_overlay {
+            overlay_visible = !overlay_visible;
+        }
+
+        if input.toggle_memory_view {
+            memory_view = match memory_view {
+                Some(..) => None,
+                None => {
+                    overlay_visible = true;
+                    Some(memory_view::MemoryView::default())
+                }
+            };
+        }
+
+        if let Some(view) = &mut memory_view {
+            if overlay_visible {
+                // Memory can only be edited while paused, so that edits don't race the program.
+                for key in input.editor_keys.drain(..) {
+                    if let Err(err) = view.handle_key(key, &mut interpreter, step_mode_active) {
+                        println!("{}", err);
+                    }
+                }
+            }
+        }
+
+        while let Some(command) = console
+            .as_ref()
+            .and_then(|console| console.next_command(interpreter.symbols()))
+        {
+            match command {
+                Ok(console::Command::Help) => println!("{}", console::HELP),
+                Ok(console::Command::Pause) => {
+                    step_mode_active = true;
+                    next_instruction = false;
+                }
+                Ok(console::Command::Continue) => step_mode_active = false,
+                Ok(console::Command::Step(count)) => {
+                    if step_mode_active {
+                        pending_steps += count;
+                    } else {
+                        println!("Pause before stepping.");
+                    }
+                }
+                Ok(console::Command::Registers) => console::print_registers(&interpreter),
+                Ok(console::Command::Memory { address, length }) => {
+                    console::print_memory(&interpreter, address, length)
+                }
+                Ok(console::Command::Write { address, bytes }) => {
+                    if !step_mode_active {
+                        println!("Pause before changing memory.");
+                        continue;
+                    }
+                    for (offset, byte) in bytes.iter().enumerate() {
+                        let target = address.wrapping_add(offset as u16);
+                        if let Err(err) = interpreter.set_memory(target, *byte) {
+                            println!("{}", err);
+                            break;
+                        }
+                    }
+                }
+                Ok(console::Command::Break { address, condition }) => {
+                    let number = interpreter
+                        .breakpoints_mut()
+                        .add_breakpoint(address, condition);
+                    println!("Breakpoint {} set.", number);
+                }
+                Ok(console::Command::Watch {
+                    start,
+                    end,
+                    read,
+                    write,
+                }) => {
+                    let number = interpreter
+                        .breakpoints_mut()
+                        .add_watchpoint(start..=end, read, write);
+                    println!("Watchpoint {} set.", number);
+                }
+                Ok(console::Command::ListBreakpoints) => {
+                    for line in interpreter.breakpoints().list() {
+                        println!("{}", line);
+                    }
+                }
+                Ok(console::Command::Delete(number)) => {
+                    if let Err(err) = interpreter.breakpoints_mut().remove(number) {
+                        println!("{}", err);
+                    }
+                }
+                Ok(console::Command::Back(count)) => {
+                    if !step_mode_active {
+                        println!("Pause before stepping back.");
+                        continue;
+                    }
+                    for _ in 0..count {
+                        if let Err(err) = interpreter.step_back() {
+                            println!("{}", err);
+                            break;
+                        }
+                    }
+                    display.set_pixels(interpreter.framebuffer());
+                    console::print_instruction(&interpreter);
+                }
+                Ok(console::Command::ReverseContinue) => {
+                    if !step_mode_active {
+                        println!("Pause before stepping back.");
+                        continue;
+                    }
+                    pending_steps = 0;
+                    match interpreter.reverse_continue() {
+                        (steps, Some(stop)) => println!("{}, {} instructions back", stop, steps),
+                        (steps, None) => {
+                            println!("Reached the start of the history, {} instructions back", steps)
+                        }
+                    }
+                    display.set_pixels(interpreter.framebuffer());
+                    console::print_instruction(&interpreter);
+                }
+                Ok(console::Command::LastChange(location)) => {
+                    console::print_last_change(&interpreter, location)
+                }
+                Err(err) => println!("{}", err),
+            }
+        }
+
         if input.print_state {
             interpreter.print_state();
         }
 
         if next_instruction {
-            interpreter.execute_next_instruction(&mut display, &mut sound, &input).unwrap();
+            // In step mode, a step is a single instruction, and the frame, with its timers,
+            // sound and recorded video, only ends once all its instructions have run.
+            let buzzer = if step_mode_active {
+                interpreter.step(&mut input.keypad, cycles_per_frame).unwrap()
+            } else {
+                Some(interpreter.run_frame(&mut input.keypad, cycles_per_frame).unwrap())
+            };
+            report_findings(&mut interpreter);
+            if let Some(stop) = interpreter.take_stop() {
+                println!("{}", stop);
+                step_mode_active = true;
+                pending_steps = 0;
+            }
+            display.set_pixels(interpreter.framebuffer());
+
+            if let Some(buzzer) = buzzer {
+                sound.push_frame(buzzer);
+                if let Some(wav) = &mut audio_recorder {
+                    if let Err(err) = wav.write_samples(sound.last_frame()) {
+                        println!("Stopped recording audio: {}", err);
+                        audio_recorder = None;
+                    }
+                }
+
+                // Only emulated frames are recorded, which keeps recordings at 60 Hz even
+                // when the emulator is paused or slowed down.
+                if let Some(active_recorder) = &mut recorder {
+                    let frame = display.image(active_recorder.scale());
+                    if let Err(err) = active_recorder.add_frame(&frame) {
+                        println!("Stopped recording: {}", err);
+                        recorder = None;
+                    }
+                }
+            }
+        } else {
+            // Nothing consumes key events while paused, so they are applied here rather
+            // than replayed once the program resumes.
+            input.keypad.settle();
+        }
+
+        // Refreshed every frame, so that it also follows key presses while paused.
+        if overlay_visible {
+            display.set_overlay(Some(overlay::Overlay::new(
+                &interpreter,
+                &input.keypad,
+                memory_view.as_ref(),
+            )));
+        } else {
+            display.set_overlay(None);
         }
 
         display.present();
-        ::std::thread::sleep(Duration::from_micros(16666));
+        let now = Instant::now();
+        if next_frame > now {
+            ::std::thread::sleep(next_frame - now);
+        } else if now - next_frame > frame_duration * 4 {
+            // After a stall, e.g. while the window is dragged, start afresh rather than
+            // racing through the missed frames.
+            next_frame = now;
+        }
+        next_frame += frame_duration;
     }
 }
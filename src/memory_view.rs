@@ -0,0 +1,259 @@
+use crate::interpreter::Interpreter;
+use crate::overlay::{Highlight, Line, Span};
+
+const BYTES_PER_ROW: u16 = 8;
+const ROWS: u16 = 16;
+// The built-in font sprites are at the start of memory.
+const FONT_END: u16 = 0x50;
+// Bytes written during this many frames are highlighted.
+const RECENT_FRAMES: u64 = 60;
+
+pub enum EditorKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Digit(u8),
+}
+
+// A hex view of the whole address space, scrolled by moving a cursor around. While the
+// emulator is paused, typing two hex digits overwrites the byte under the cursor.
+pub struct MemoryView {
+    cursor: u16,
+    // The address of the first row shown.
+    top: u16,
+    // The first digit typed for the byte under the cursor.
+    pending_digit: Option<u8>,
+}
+
+impl Default for MemoryView {
+    fn default() -> MemoryView {
+        MemoryView {
+            cursor: 0x200,
+            top: 0x200,
+            pending_digit: None,
+        }
+    }
+}
+
+impl MemoryView {
+    pub fn handle_key(
+        self: &mut Self,
+        key: EditorKey,
+        interpreter: &mut Interpreter,
+        editable: bool,
+    ) -> Result<(), String> {
+        let page = (BYTES_PER_ROW * ROWS) as i32;
+        let delta = match key {
+            EditorKey::Up => -(BYTES_PER_ROW as i32),
+            EditorKey::Down => BYTES_PER_ROW as i32,
+            EditorKey::Left => -1,
+            EditorKey::Right => 1,
+            EditorKey::PageUp => -page,
+            EditorKey::PageDown => page,
+            EditorKey::Digit(digit) => {
+                if !editable {
+                    return Ok(());
+                }
+                match self.pending_digit.take() {
+                    None => self.pending_digit = Some(digit),
+                    Some(high) => {
+                        interpreter.set_memory(self.cursor, (high << 4) | digit)?;
+                        self.move_cursor(1, interpreter.memory().len());
+                    }
+                }
+                return Ok(());
+            }
+        };
+        self.pending_digit = None;
+        self.move_cursor(delta, interpreter.memory().len());
+
+        Ok(())
+    }
+
+    fn move_cursor(self: &mut Self, delta: i32, memory_size: usize) {
+        self.cursor = (self.cursor as i32 + delta).clamp(0, memory_size as i32 - 1) as u16;
+
+        let row_start = self.cursor - self.cursor % BYTES_PER_ROW;
+        if row_start < self.top {
+            self.top = row_start;
+        } else if row_start >= self.top + BYTES_PER_ROW * ROWS {
+            self.top = row_start + BYTES_PER_ROW - BYTES_PER_ROW * ROWS;
+        }
+    }
+
+    pub fn lines(self: &Self, interpreter: &Interpreter) -> Vec<Line> {
+        let memory = interpreter.memory();
+        let program_counter = interpreter.program_counter();
+        let memory_register = interpreter.memory_register();
+
+        let mut lines = vec![vec![Span::new(
+            format!("MEMORY {:03X}", self.cursor),
+            Highlight::Normal,
+        )]];
+        for row in 0..ROWS {
+            let row_address = self.top + row * BYTES_PER_ROW;
+            if row_address as usize >= memory.len() {
+                break;
+            }
+
+            let mut line = vec![Span::new(
+                format!("{:03X}:", row_address),
+                Highlight::Normal,
+            )];
+            for address in row_address..row_address + BYTES_PER_ROW {
+                let byte = match memory.get(address as usize) {
+                    Some(byte) => *byte,
+                    None => break,
+                };
+                let text = match self.pending_digit {
+                    Some(digit) if address == self.cursor => format!("{:X}_", digit),
+                    _ => format!("{:02X}", byte),
+                };
+                let highlight = if address == self.cursor {
+                    Highlight::Cursor
+                } else if address == program_counter || address == program_counter + 1 {
+                    Highlight::ProgramCounter
+                } else if address == memory_register {
+                    Highlight::MemoryRegister
+                } else if interpreter.recently_written(address, RECENT_FRAMES) {
+                    Highlight::Written
+                } else if address < FONT_END {
+                    Highlight::Font
+                } else {
+                    Highlight::Normal
+                };
+                line.push(Span::new(" ".to_string(), Highlight::Normal));
+                line.push(Span::new(text, highlight));
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interpreter() -> Interpreter {
+        let mut interpreter = Interpreter::new(&[0x12, 0x00, 0x00, 0xe0]);
+        interpreter.set_trace(false);
+        interpreter
+    }
+
+    fn text(line: &Line) -> String {
+        line.iter().map(|span| span.text.as_str()).collect()
+    }
+
+    #[test]
+    fn typing_two_digits_overwrites_a_byte() {
+        let mut interpreter = interpreter();
+        let mut view = MemoryView::default();
+        view.handle_key(EditorKey::Right, &mut interpreter, true)
+            .unwrap();
+        view.handle_key(EditorKey::Digit(0xa), &mut interpreter, true)
+            .unwrap();
+        assert_eq!(
+            text(&view.lines(&interpreter)[1]),
+            "200: 12 A_ 00 E0 00 00 00 00"
+        );
+        assert_eq!(interpreter.memory()[0x201], 0x00);
+
+        view.handle_key(EditorKey::Digit(0x5), &mut interpreter, true)
+            .unwrap();
+        assert_eq!(interpreter.memory()[0x201], 0xa5);
+        assert!(interpreter.recently_written(0x201, RECENT_FRAMES));
+        // The cursor moves on to the next byte.
+        assert_eq!(text(&view.lines(&interpreter)[0]), "MEMORY 202");
+    }
+
+    #[test]
+    fn edits_need_the_emulator_paused() {
+        let mut interpreter = interpreter();
+        let mut view = MemoryView::default();
+        for digit in [0x1, 0x2] {
+            view.handle_key(EditorKey::Digit(digit), &mut interpreter, false)
+                .unwrap();
+        }
+        assert_eq!(interpreter.memory()[0x200], 0x12);
+        assert_eq!(interpreter.memory()[0x201], 0x00);
+    }
+
+    #[test]
+    fn moving_drops_a_half_typed_byte() {
+        let mut interpreter = interpreter();
+        let mut view = MemoryView::default();
+        view.handle_key(EditorKey::Digit(0xf), &mut interpreter, true)
+            .unwrap();
+        view.handle_key(EditorKey::Down, &mut interpreter, true)
+            .unwrap();
+        view.handle_key(EditorKey::Digit(0x3), &mut interpreter, true)
+            .unwrap();
+        assert_eq!(interpreter.memory()[0x200], 0x12);
+        assert_eq!(interpreter.memory()[0x208], 0x00);
+        assert_eq!(
+            text(&view.lines(&interpreter)[2]),
+            "208: 3_ 00 00 00 00 00 00 00"
+        );
+    }
+
+    #[test]
+    fn the_cursor_scrolls_the_view_and_stays_in_memory() {
+        let mut interpreter = interpreter();
+        let mut view = MemoryView::default();
+        view.handle_key(EditorKey::PageDown, &mut interpreter, true)
+            .unwrap();
+        let lines = view.lines(&interpreter);
+        assert_eq!(text(&lines[0]), "MEMORY 280");
+        assert!(text(&lines[1]).starts_with("208:"));
+        assert!(text(&lines[16]).starts_with("280:"));
+
+        for _ in 0..40 {
+            view.handle_key(EditorKey::PageDown, &mut interpreter, true)
+                .unwrap();
+        }
+        let last = interpreter.memory().len() - 1;
+        assert_eq!(
+            text(&view.lines(&interpreter)[0]),
+            format!("MEMORY {:03X}", last)
+        );
+
+        for _ in 0..40 {
+            view.handle_key(EditorKey::PageUp, &mut interpreter, true)
+                .unwrap();
+        }
+        view.handle_key(EditorKey::Left, &mut interpreter, true)
+            .unwrap();
+        let lines = view.lines(&interpreter);
+        assert_eq!(text(&lines[0]), "MEMORY 000");
+        assert!(text(&lines[1]).starts_with("000:"));
+    }
+
+    #[test]
+    fn highlights_what_each_byte_is() {
+        let mut interpreter = interpreter();
+        let mut view = MemoryView::default();
+        view.handle_key(EditorKey::Down, &mut interpreter, true)
+            .unwrap();
+        let lines = view.lines(&interpreter);
+        // Every byte is preceded by a space.
+        let highlight = |row: usize, column: usize| lines[row][2 + column * 2].highlight;
+        assert!(highlight(1, 0) == Highlight::ProgramCounter);
+        assert!(highlight(1, 1) == Highlight::ProgramCounter);
+        assert!(highlight(1, 2) == Highlight::Normal);
+        assert!(highlight(2, 0) == Highlight::Cursor);
+
+        for _ in 0..4 {
+            view.handle_key(EditorKey::PageUp, &mut interpreter, true)
+                .unwrap();
+        }
+        let lines = view.lines(&interpreter);
+        assert!(lines[1][2].highlight == Highlight::Cursor);
+        assert!(lines[1][4].highlight == Highlight::Font);
+        assert!(lines[11][2].highlight == Highlight::Normal);
+    }
+}
@@ -0,0 +1,307 @@
+use serde_json::Value;
+
+use crate::interpreter::Quirks;
+use crate::octo_compiler;
+use crate::palette::{Palette, BACKGROUND, BLEND, FILL, FILL2};
+
+// Options exported by Octo (https://github.com/JohnEarnest/Octo). Every field is optional
+// in the JSON, so anything missing is left as None and the emulator default is kept.
+#[derive(Debug, Clone, Default)]
+pub struct OctoOptions {
+    pub tickrate: Option<u32>,
+    pub background_color: Option<u32>,
+    pub fill_color: Option<u32>,
+    pub fill_color2: Option<u32>,
+    pub blend_color: Option<u32>,
+    pub screen_rotation: Option<u32>,
+    pub shift_quirks: Option<bool>,
+    pub load_store_quirks: Option<bool>,
+    pub vf_order_quirks: Option<bool>,
+    pub clip_quirks: Option<bool>,
+    pub vblank_quirks: Option<bool>,
+    pub jump_quirks: Option<bool>,
+    pub logic_quirks: Option<bool>,
+}
+
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    pub options: Option<OctoOptions>,
+}
+
+impl OctoOptions {
+    pub fn from_json(json: &str) -> Result<OctoOptions, String> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(err) => return Err(format!("Failed to parse Octo options: {}", err)),
+        };
+        OctoOptions::from_value(&value)
+    }
+
+    fn from_value(value: &Value) -> Result<OctoOptions, String> {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => return Err("Octo options must be a JSON object.".to_string()),
+        };
+
+        let mut options = OctoOptions::default();
+        for (name, field) in object.iter() {
+            match name.as_str() {
+                "tickrate" => options.tickrate = Some(parse_number(name, field)?),
+                "backgroundColor" => options.background_color = Some(parse_color(name, field)?),
+                "fillColor" => options.fill_color = Some(parse_color(name, field)?),
+                "fillColor2" => options.fill_color2 = Some(parse_color(name, field)?),
+                "blendColor" => options.blend_color = Some(parse_color(name, field)?),
+                "screenRotation" => options.screen_rotation = Some(parse_number(name, field)?),
+                "shiftQuirks" => options.shift_quirks = Some(parse_bool(name, field)?),
+                "loadStoreQuirks" => options.load_store_quirks = Some(parse_bool(name, field)?),
+                "vfOrderQuirks" => options.vf_order_quirks = Some(parse_bool(name, field)?),
+                "clipQuirks" => options.clip_quirks = Some(parse_bool(name, field)?),
+                "vBlankQuirks" => options.vblank_quirks = Some(parse_bool(name, field)?),
+                "jumpQuirks" => options.jump_quirks = Some(parse_bool(name, field)?),
+                "logicQuirks" => options.logic_quirks = Some(parse_bool(name, field)?),
+                // Octo also exports options we have no use for (buzzColor, fontStyle,
+                // touchInputMode, ...), those are silently ignored.
+                _ => (),
+            }
+        }
+
+        if let Some(rotation) = options.screen_rotation {
+            if rotation % 90 != 0 || rotation >= 360 {
+                return Err(format!("Unsupported screen rotation: {}.", rotation));
+            }
+        }
+
+        Ok(options)
+    }
+
+    pub fn apply_quirks(self: &Self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift_quirks {
+            quirks.shift = shift;
+        }
+        if let Some(load_store) = self.load_store_quirks {
+            quirks.load_store = load_store;
+        }
+        if let Some(vf_order) = self.vf_order_quirks {
+            quirks.vf_order = vf_order;
+        }
+        if let Some(clip) = self.clip_quirks {
+            quirks.clip = clip;
+        }
+        if let Some(vblank) = self.vblank_quirks {
+            quirks.vblank = vblank;
+        }
+        if let Some(jump) = self.jump_quirks {
+            quirks.jump = jump;
+        }
+        if let Some(logic) = self.logic_quirks {
+            quirks.logic = logic;
+        }
+    }
+
+    // The palette made of the colours in the options, with missing ones taken from the
+    // default palette. None if the options don't set any colour.
+    pub fn palette(self: &Self) -> Option<Palette> {
+        let colors = [
+            (BACKGROUND, self.background_color),
+            (FILL, self.fill_color),
+            (FILL2, self.fill_color2),
+            (BLEND, self.blend_color),
+        ];
+        if colors.iter().all(|(_, color)| color.is_none()) {
+            return None;
+        }
+
+        let mut palette = Palette {
+            name: "octo options".to_string(),
+            ..Palette::default()
+        };
+        for (index, color) in colors.iter() {
+            if let Some(color) = color {
+                palette.colors[*index] = *color;
+            }
+        }
+        Some(palette)
+    }
+}
+
+fn parse_number(name: &str, field: &Value) -> Result<u32, String> {
+    // Octo writes some numbers as strings, so accept both.
+    let number = match field {
+        Value::Number(number) => number.as_u64(),
+        Value::String(string) => string.trim().parse::<u64>().ok(),
+        _ => None,
+    };
+    match number {
+        Some(number) if number <= u32::MAX as u64 => Ok(number as u32),
+        _ => Err(format!("Invalid value for Octo option {}.", name)),
+    }
+}
+
+fn parse_bool(name: &str, field: &Value) -> Result<bool, String> {
+    match field {
+        Value::Bool(value) => Ok(*value),
+        Value::String(string) if string == "true" => Ok(true),
+        Value::String(string) if string == "false" => Ok(false),
+        _ => Err(format!("Invalid value for Octo option {}.", name)),
+    }
+}
+
+fn parse_color(name: &str, field: &Value) -> Result<u32, String> {
+    let string = match field.as_str() {
+        Some(string) => string.trim_start_matches('#'),
+        None => return Err(format!("Invalid value for Octo option {}.", name)),
+    };
+    match u32::from_str_radix(string, 16) {
+        Ok(color) if string.len() == 6 => Ok(color),
+        _ => Err(format!("Invalid colour for Octo option {}: {}.", name, string)),
+    }
+}
+
+pub fn is_cartridge(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+}
+
+// Octo cartridges hide their payload in the low two bits of every pixel's palette index,
+// four pixels per byte with the most significant bits first, across all frames of the GIF.
+// The payload starts with a 32-bit big-endian length followed by a JSON object holding
+// the program and the options it was exported with. The program is Octo source, which is
+// compiled here, or a compiled ROM as a byte array or a hex string.
+pub fn load_cartridge(bytes: &[u8]) -> Result<Cartridge, String> {
+    let mut decode_options = gif::DecodeOptions::new();
+    decode_options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = match decode_options.read_info(bytes) {
+        Ok(decoder) => decoder,
+        Err(err) => return Err(format!("Failed to decode cartridge: {}", err)),
+    };
+
+    let mut crumbs: Vec<u8> = Vec::new();
+    loop {
+        match decoder.read_next_frame() {
+            Ok(Some(frame)) => crumbs.extend(frame.buffer.iter().map(|index| index & 0b11)),
+            Ok(None) => break,
+            Err(err) => return Err(format!("Failed to decode cartridge: {}", err)),
+        }
+    }
+
+    let data: Vec<u8> = crumbs
+        .chunks_exact(4)
+        .map(|c| (c[0] << 6) | (c[1] << 4) | (c[2] << 2) | c[3])
+        .collect();
+    if data.len() < 4 {
+        return Err("Cartridge does not contain a payload.".to_string());
+    }
+
+    let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if length > data.len() - 4 {
+        return Err("Cartridge payload is truncated.".to_string());
+    }
+    let payload = match std::str::from_utf8(&data[4..4 + length]) {
+        Ok(payload) => payload,
+        Err(..) => return Err("Cartridge payload is not valid UTF-8.".to_string()),
+    };
+    let value: Value = match serde_json::from_str(payload) {
+        Ok(value) => value,
+        Err(err) => return Err(format!("Failed to parse cartridge payload: {}", err)),
+    };
+
+    let program = match value.get("program") {
+        Some(program) => parse_program(program)?,
+        None => return Err("Cartridge payload does not contain a program.".to_string()),
+    };
+    let options = match value.get("options") {
+        Some(options) => Some(OctoOptions::from_value(options)?),
+        None => None,
+    };
+
+    Ok(Cartridge { program, options })
+}
+
+fn parse_program(program: &Value) -> Result<Vec<u8>, String> {
+    match program {
+        Value::Array(bytes) => bytes
+            .iter()
+            .map(|byte| match byte.as_u64() {
+                Some(byte) if byte <= 0xff => Ok(byte as u8),
+                _ => Err("Cartridge program contains an invalid byte.".to_string()),
+            })
+            .collect(),
+        Value::String(hex) => {
+            let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+            let pairs = digits.chunks_exact(2);
+            if !pairs.remainder().is_empty() || !digits.iter().all(|c| c.is_ascii_hexdigit()) {
+                // Cartridges saved by Octo itself hold the source.
+                return octo_compiler::compile(hex)
+                    .map_err(|e| format!("Failed to compile cartridge program: {}", e));
+            }
+            Ok(pairs
+                .map(|pair| (pair[0].to_digit(16).unwrap() << 4 | pair[1].to_digit(16).unwrap()) as u8)
+                .collect())
+        }
+        _ => Err("Cartridge program has an unexpected format.".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single-frame GIF carrying the payload the way Octo does.
+    fn cartridge(payload: &str) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(payload.as_bytes());
+        let mut pixels: Vec<u8> = data
+            .iter()
+            .flat_map(|byte| [6, 4, 2, 0].map(|shift| (byte >> shift) & 0b11))
+            .collect();
+        let width = 64;
+        let height = pixels.len().div_ceil(width);
+        pixels.resize(width * height, 0);
+
+        let palette = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+        let mut bytes = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut bytes, width as u16, height as u16, &palette).unwrap();
+            let frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, &pixels, None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn loads_hex_string_program() {
+        let bytes = cartridge(r#"{"program": "00E0 1200", "options": {"tickrate": 20}}"#);
+        assert!(is_cartridge(&bytes));
+        let cartridge = load_cartridge(&bytes).unwrap();
+        assert_eq!(cartridge.program, vec![0x00, 0xe0, 0x12, 0x00]);
+        assert_eq!(cartridge.options.unwrap().tickrate, Some(20));
+    }
+
+    #[test]
+    fn loads_byte_array_program() {
+        let cartridge = load_cartridge(&cartridge(r#"{"program": [0, 224, 18, 0]}"#)).unwrap();
+        assert_eq!(cartridge.program, vec![0x00, 0xe0, 0x12, 0x00]);
+        assert!(cartridge.options.is_none());
+    }
+
+    #[test]
+    fn compiles_source_program() {
+        let bytes = cartridge(r#"{"program": ": main\n  clear\n  loop again"}"#);
+        let cartridge = load_cartridge(&bytes).unwrap();
+        assert_eq!(cartridge.program, vec![0x00, 0xe0, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn reports_source_errors() {
+        let bytes = cartridge(r#"{"program": ": main\n  hires"}"#);
+        let err = load_cartridge(&bytes).err().unwrap();
+        assert!(err.starts_with("Failed to compile cartridge program: line 2:"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        let bytes = cartridge(r#"{"program": [0, 224"#);
+        let err = load_cartridge(&bytes).err().unwrap();
+        assert!(err.starts_with("Failed to parse cartridge"), "{}", err);
+    }
+}
@@ -0,0 +1,1049 @@
+use std::collections::{HashMap, VecDeque};
+
+// Where programs are loaded, and where the memory they can fill ends.
+const ORIGIN: u16 = 0x200;
+const MEMORY_SIZE: usize = 0x1000;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+    calls: u32,
+}
+
+// Instruction operands that refer to labels defined further down, filled in at the end.
+enum Fixup {
+    // The low 12 bits of the opcode at the address.
+    Address,
+    // The `v0 := NN; v1 := NN` pair emitted by :unpack.
+    Unpack,
+    // The 16 bits at the address, emitted by :pointer.
+    Pointer,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+    Key,
+    NotKey,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Value(u8),
+}
+
+#[derive(Clone, Copy)]
+struct Condition {
+    register: u8,
+    comparison: Comparison,
+    operand: Option<Operand>,
+}
+
+struct Loop {
+    start: u16,
+    // Jumps out of the loop emitted by `while`, which lead past the `again`.
+    exits: Vec<u16>,
+}
+
+// Compiles Octo (https://github.com/JohnEarnest/Octo) assembly into a CHIP-8 program loaded
+// at 0x200, which is what cartridges exported by Octo hold. The whole CHIP-8 language is
+// supported, including macros, :calc and the comparison pseudo-ops, but not the SCHIP and
+// XO-CHIP extensions this interpreter can't run. As in Octo, the program starts with a
+// jump to `main` unless `main` comes first.
+pub fn compile(source: &str) -> Result<Vec<u8>, String> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source)?,
+        memory: vec![0; MEMORY_SIZE],
+        here: ORIGIN,
+        end: ORIGIN,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::from([
+            ("compare-temp".to_string(), 0xf),
+            ("unpack-hi".to_string(), 0x0),
+            ("unpack-lo".to_string(), 0x1),
+        ]),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+        main_jump: true,
+        line: 0,
+    };
+    compiler.run().map_err(|e| match compiler.line {
+        0 => e,
+        line => format!("line {}: {}", line, e),
+    })?;
+    Ok(compiler.memory[ORIGIN as usize..compiler.end as usize].to_vec())
+}
+
+fn tokenize(source: &str) -> Result<VecDeque<Token>, String> {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') {
+                break;
+            }
+            // Strings can hold spaces.
+            let length = if let Some(string) = rest.strip_prefix('"') {
+                match string.find('"') {
+                    Some(end) => end + 2,
+                    None => return Err(format!("line {}: Unterminated string.", index + 1)),
+                }
+            } else {
+                rest.find(char::is_whitespace).unwrap_or(rest.len())
+            };
+            tokens.push_back(Token {
+                text: rest[..length].to_string(),
+                line: index + 1,
+            });
+            rest = &rest[length..];
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    match u8::from_str_radix(digit, 16) {
+        Ok(register) if digit.len() == 1 => Some(register),
+        _ => None,
+    }
+}
+
+// Instructions of the SCHIP and XO-CHIP extensions.
+fn is_extension(text: &str) -> bool {
+    matches!(
+        text,
+        "hires"
+            | "lores"
+            | "scroll-down"
+            | "scroll-up"
+            | "scroll-left"
+            | "scroll-right"
+            | "exit"
+            | "saveflags"
+            | "loadflags"
+            | "plane"
+            | "audio"
+            | "pitch"
+            | "bighex"
+            | "long"
+    )
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    memory: Vec<u8>,
+    here: u16,
+    // Where the program written so far ends.
+    end: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(u16, Fixup, Token)>,
+    loops: Vec<Loop>,
+    // Jumps emitted by `begin` and `else`, to be pointed at the matching `else` or `end`.
+    branches: Vec<u16>,
+    // Whether the program starts with a jump to main.
+    main_jump: bool,
+    // The line of the last token read, for errors.
+    line: usize,
+}
+
+impl Compiler {
+    fn run(self: &mut Self) -> Result<(), String> {
+        // Room for the jump to main.
+        self.instruction(0x0000)?;
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        self.line = 0;
+
+        if self.main_jump {
+            match self.labels.get("main") {
+                Some(&main) => self.patch(ORIGIN, 0x1000 | main),
+                None => return Err("The program has no main label.".to_string()),
+            }
+        }
+        if !self.loops.is_empty() {
+            return Err("A loop is missing its again.".to_string());
+        }
+        if !self.branches.is_empty() {
+            return Err("A begin is missing its end.".to_string());
+        }
+        for (address, fixup, token) in std::mem::take(&mut self.fixups) {
+            let value = match self.labels.get(&token.text) {
+                Some(&value) => value,
+                None => {
+                    self.line = token.line;
+                    return Err(format!("Undefined name: {}", token.text));
+                }
+            };
+            let address = address as usize;
+            match fixup {
+                Fixup::Address => {
+                    self.memory[address] |= (value >> 8) as u8;
+                    self.memory[address + 1] = value as u8;
+                }
+                Fixup::Unpack => {
+                    self.memory[address + 1] |= (value >> 8) as u8;
+                    self.memory[address + 3] = value as u8;
+                }
+                Fixup::Pointer => {
+                    self.memory[address] = (value >> 8) as u8;
+                    self.memory[address + 1] = value as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next(self: &mut Self) -> Result<Token, String> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token)
+            }
+            None => Err("Unexpected end of program.".to_string()),
+        }
+    }
+
+    fn peek(self: &Self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(self: &mut Self, text: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token.text == text {
+            Ok(())
+        } else {
+            Err(format!("Expected {}, found {}.", text, token.text))
+        }
+    }
+
+    fn byte(self: &mut Self, value: u8) -> Result<(), String> {
+        if self.here as usize >= MEMORY_SIZE {
+            return Err("The program doesn't fit in memory.".to_string());
+        }
+        self.memory[self.here as usize] = value;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn instruction(self: &mut Self, opcode: u16) -> Result<(), String> {
+        self.byte((opcode >> 8) as u8)?;
+        self.byte(opcode as u8)
+    }
+
+    fn patch(self: &mut Self, address: u16, opcode: u16) {
+        self.memory[address as usize] = (opcode >> 8) as u8;
+        self.memory[address as usize + 1] = opcode as u8;
+    }
+
+    fn register(self: &mut Self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.as_register(&token.text)
+            .ok_or_else(|| format!("Expected a register, found {}.", token.text))
+    }
+
+    fn as_register(self: &Self, text: &str) -> Option<u8> {
+        parse_register(text).or_else(|| self.aliases.get(text).copied())
+    }
+
+    fn peek_register(self: &Self) -> bool {
+        self.peek()
+            .is_some_and(|text| self.as_register(text).is_some())
+    }
+
+    // A number, constant or (if known) label.
+    fn value(self: &mut Self, token: &Token) -> Result<Option<f64>, String> {
+        if token.text == "{" {
+            return Err("Expected a value, found {.".to_string());
+        }
+        Ok(parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&label| label as f64)))
+    }
+
+    fn short_value(self: &mut Self) -> Result<u8, String> {
+        let token = self.next()?;
+        match self.value(&token)? {
+            Some(value) if (-128.0..256.0).contains(&value) => Ok(value as i64 as u8),
+            Some(..) => Err(format!("Value doesn't fit in a byte: {}", token.text)),
+            None => Err(format!("Undefined name: {}", token.text)),
+        }
+    }
+
+    fn tiny_value(self: &mut Self) -> Result<u16, String> {
+        let token = self.next()?;
+        match self.value(&token)? {
+            Some(value) if (0.0..16.0).contains(&value) => Ok(value as u16),
+            Some(..) => Err(format!("Value doesn't fit in a nibble: {}", token.text)),
+            None => Err(format!("Undefined name: {}", token.text)),
+        }
+    }
+
+    // Emits an instruction taking a 12-bit address, which may be a label defined later.
+    fn address_instruction(self: &mut Self, opcode: u16) -> Result<(), String> {
+        let token = self.next()?;
+        match self.value(&token)? {
+            Some(value) if (0.0..4096.0).contains(&value) => {
+                self.instruction(opcode | value as u16)
+            }
+            Some(..) => Err(format!("Not an address: {}", token.text)),
+            None => self.forward_reference(token, Fixup::Address, opcode),
+        }
+    }
+
+    fn forward_reference(
+        self: &mut Self,
+        token: Token,
+        fixup: Fixup,
+        opcode: u16,
+    ) -> Result<(), String> {
+        if parse_register(&token.text).is_some() || token.text.starts_with(':') {
+            return Err(format!("Not an address: {}", token.text));
+        }
+        self.fixups.push((self.here, fixup, token));
+        self.instruction(opcode)
+    }
+
+    fn define_label(self: &mut Self, name: &Token, address: u16) -> Result<(), String> {
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(format!("Name defined twice: {}", name.text));
+        }
+        if parse_number(&name.text).is_some() || parse_register(&name.text).is_some() {
+            return Err(format!("Invalid name: {}", name.text));
+        }
+        self.labels.insert(name.text.clone(), address);
+        Ok(())
+    }
+
+    fn statement(self: &mut Self) -> Result<(), String> {
+        let token = self.next()?;
+        let text = token.text.as_str();
+        if let Some(register) = self.as_register(text) {
+            return self.assignment(register);
+        }
+        if self.macros.contains_key(text) {
+            return self.expand(&token);
+        }
+        match text {
+            ":" => {
+                let name = self.next()?;
+                // A program starting with main doesn't need the jump to it.
+                if name.text == "main" && self.end == ORIGIN + 2 && self.labels.is_empty() {
+                    self.main_jump = false;
+                    self.here = ORIGIN;
+                    self.end = ORIGIN;
+                }
+                self.define_label(&name, self.here)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                match self.value(&value)? {
+                    Some(value) => self.define_constant(&name, value)?,
+                    None => return Err(format!("Undefined name: {}", value.text)),
+                }
+            }
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.calc()?;
+                self.define_constant(&name, value)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = if self.peek() == Some("{") {
+                    match self.calc()? {
+                        value if (0.0..16.0).contains(&value) => value as u8,
+                        _ => return Err("Not a register.".to_string()),
+                    }
+                } else {
+                    self.register()?
+                };
+                self.aliases.insert(name.text, register);
+            }
+            ":unpack" => {
+                if self.peek() == Some("long") {
+                    return Err("XO-CHIP's :unpack long isn't supported.".to_string());
+                }
+                let nibble = self.tiny_value()?;
+                let name = self.next()?;
+                let high = 0x6000 | (self.aliases["unpack-hi"] as u16) << 8 | nibble << 4;
+                let low = 0x6000 | (self.aliases["unpack-lo"] as u16) << 8;
+                match self.value(&name)? {
+                    Some(value) if (0.0..4096.0).contains(&value) => {
+                        let value = value as u16;
+                        self.instruction(high | value >> 8)?;
+                        self.instruction(low | (value & 0xff))?;
+                    }
+                    Some(..) => return Err(format!("Not an address: {}", name.text)),
+                    None => {
+                        self.forward_reference(name, Fixup::Unpack, high)?;
+                        self.instruction(low)?;
+                    }
+                }
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":org" => {
+                let token = self.next()?;
+                match self.value(&token)? {
+                    Some(value) if (ORIGIN as f64..MEMORY_SIZE as f64).contains(&value) => {
+                        self.here = value as u16;
+                    }
+                    _ => return Err(format!("Not an address in the program: {}", token.text)),
+                }
+            }
+            ":call" => self.address_instruction(0x2000)?,
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc()? as i64 as u8
+                } else {
+                    self.short_value()?
+                };
+                self.byte(value)?;
+            }
+            ":pointer" => {
+                if self.peek() == Some("{") {
+                    let value = self.calc()? as i64 as u16;
+                    self.instruction(value)?;
+                } else {
+                    let token = self.next()?;
+                    match self.value(&token)? {
+                        Some(value) => self.instruction(value as i64 as u16)?,
+                        None => self.forward_reference(token, Fixup::Pointer, 0)?,
+                    }
+                }
+            }
+            ":assert" => {
+                let message = match self.peek() {
+                    Some(text) if text.starts_with('"') => Some(self.next()?.text),
+                    _ => None,
+                };
+                if self.calc()? == 0.0 {
+                    return Err(match message {
+                        Some(message) => format!("Assertion failed: {}", message.trim_matches('"')),
+                        None => "Assertion failed.".to_string(),
+                    });
+                }
+            }
+            ":macro" => self.define_macro()?,
+            // Debugger annotations.
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ":stringmode" => return Err(":stringmode isn't supported.".to_string()),
+            ";" | "return" => self.instruction(0x00ee)?,
+            "clear" => self.instruction(0x00e0)?,
+            "bcd" => {
+                let x = self.register()? as u16;
+                self.instruction(0xf033 | x << 8)?;
+            }
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek() == Some("-") {
+                    return Err(format!("XO-CHIP's {} range isn't supported.", text));
+                }
+                let opcode = if text == "save" { 0xf055 } else { 0xf065 };
+                self.instruction(opcode | x << 8)?;
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.tiny_value()?;
+                self.instruction(0xd000 | x << 8 | y << 4 | n)?;
+            }
+            "jump" => self.address_instruction(0x1000)?,
+            "jump0" => self.address_instruction(0xb000)?,
+            "native" => self.address_instruction(0x0000)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let opcode = if text == "delay" { 0xf015 } else { 0xf018 };
+                self.instruction(opcode | x << 8)?;
+            }
+            "i" => {
+                let operator = self.next()?;
+                match operator.text.as_str() {
+                    ":=" if self.peek() == Some("hex") => {
+                        self.next()?;
+                        let x = self.register()? as u16;
+                        self.instruction(0xf029 | x << 8)?;
+                    }
+                    ":=" if self.peek().is_some_and(is_extension) => {
+                        let token = self.next()?;
+                        return Err(format!("i := {} isn't supported.", token.text));
+                    }
+                    ":=" => self.address_instruction(0xa000)?,
+                    "+=" => {
+                        let x = self.register()? as u16;
+                        self.instruction(0xf01e | x << 8)?;
+                    }
+                    _ => return Err(format!("Unknown operator for i: {}", operator.text)),
+                }
+            }
+            "if" => {
+                let condition = self.condition()?;
+                let keyword = self.next()?;
+                match keyword.text.as_str() {
+                    "then" => self.skip_unless(&condition)?,
+                    "begin" => {
+                        self.skip_unless(&condition.negated())?;
+                        self.branches.push(self.here);
+                        self.instruction(0x1000)?;
+                    }
+                    _ => return Err(format!("Expected then or begin, found {}.", keyword.text)),
+                }
+            }
+            "else" => {
+                let branch = self.branches.pop().ok_or("else without a begin.")?;
+                self.branches.push(self.here);
+                self.instruction(0x1000)?;
+                self.patch(branch, 0x1000 | self.here);
+            }
+            "end" => {
+                let branch = self.branches.pop().ok_or("end without a begin.")?;
+                self.patch(branch, 0x1000 | self.here);
+            }
+            "loop" => self.loops.push(Loop {
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err("while outside of a loop.".to_string());
+                }
+                let condition = self.condition()?;
+                self.skip_unless(&condition.negated())?;
+                let exit = self.here;
+                self.loops.last_mut().unwrap().exits.push(exit);
+                self.instruction(0x1000)?;
+            }
+            "again" => {
+                let finished = self.loops.pop().ok_or("again without a loop.")?;
+                self.instruction(0x1000 | finished.start)?;
+                for exit in finished.exits {
+                    self.patch(exit, 0x1000 | self.here);
+                }
+            }
+            _ if is_extension(text) => {
+                return Err(format!("SCHIP and XO-CHIP's {} isn't supported.", text));
+            }
+            // A label on its own is a subroutine call, possibly to one defined later, and a
+            // number on its own is data.
+            _ => match self.labels.get(text) {
+                Some(&address) => self.instruction(0x2000 | address)?,
+                None => match parse_number(text).or_else(|| self.constants.get(text).copied()) {
+                    Some(value) if (-128.0..256.0).contains(&value) => {
+                        self.byte(value as i64 as u8)?;
+                    }
+                    Some(..) => return Err(format!("Value doesn't fit in a byte: {}", text)),
+                    None if text.starts_with(':') => {
+                        return Err(format!("Unknown directive: {}", text));
+                    }
+                    None => self.forward_reference(token, Fixup::Address, 0x2000)?,
+                },
+            },
+        }
+        Ok(())
+    }
+
+    fn define_constant(self: &mut Self, name: &Token, value: f64) -> Result<(), String> {
+        if self.labels.contains_key(&name.text) {
+            return Err(format!("Name defined twice: {}", name.text));
+        }
+        if parse_number(&name.text).is_some() || parse_register(&name.text).is_some() {
+            return Err(format!("Invalid name: {}", name.text));
+        }
+        self.constants.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn assignment(self: &mut Self, register: u8) -> Result<(), String> {
+        let x = (register as u16) << 8;
+        let operator = self.next()?;
+        let operator = operator.text.as_str();
+        if operator == ":=" {
+            match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.short_value()? as u16;
+                    return self.instruction(0xc000 | x | mask);
+                }
+                Some("key") => {
+                    self.next()?;
+                    return self.instruction(0xf00a | x);
+                }
+                Some("delay") => {
+                    self.next()?;
+                    return self.instruction(0xf007 | x);
+                }
+                _ => (),
+            }
+        }
+
+        if self.peek_register() {
+            let y = (self.register()? as u16) << 4;
+            let operation = match operator {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xe,
+                _ => return Err(format!("Unknown operator: {}", operator)),
+            };
+            return self.instruction(0x8000 | x | y | operation);
+        }
+
+        let value = self.short_value()? as u16;
+        match operator {
+            ":=" => self.instruction(0x6000 | x | value),
+            "+=" => self.instruction(0x7000 | x | value),
+            "-=" => self.instruction(0x7000 | x | (value as u8).wrapping_neg() as u16),
+            _ => Err(format!("Operator {} needs a register.", operator)),
+        }
+    }
+
+    fn condition(self: &mut Self) -> Result<Condition, String> {
+        let register = self.register()?;
+        let operator = self.next()?;
+        let comparison = match operator.text.as_str() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessOrEqual,
+            ">=" => Comparison::GreaterOrEqual,
+            "key" => Comparison::Key,
+            "-key" => Comparison::NotKey,
+            _ => return Err(format!("Unknown comparison: {}", operator.text)),
+        };
+        let operand = match comparison {
+            Comparison::Key | Comparison::NotKey => None,
+            _ if self.peek_register() => Some(Operand::Register(self.register()?)),
+            _ => Some(Operand::Value(self.short_value()?)),
+        };
+        Ok(Condition {
+            register,
+            comparison,
+            operand,
+        })
+    }
+
+    // Emits a skip over the next instruction for when the condition doesn't hold. The
+    // ordering comparisons subtract into the compare-temp register, VF by default, and
+    // look at the borrow flag, so like in Octo they need the flag to be written last.
+    fn skip_unless(self: &mut Self, condition: &Condition) -> Result<(), String> {
+        let x = (condition.register as u16) << 8;
+        let temp = self.aliases["compare-temp"] as u16;
+        let operand = match condition.operand {
+            Some(Operand::Register(y)) => (true, y as u16),
+            Some(Operand::Value(value)) => (false, value as u16),
+            None => (false, 0),
+        };
+        let (skip_not_equal, skip_equal) = match operand {
+            (true, y) => (0x9000 | x | y << 4, 0x5000 | x | y << 4),
+            (false, value) => (0x4000 | x | value, 0x3000 | x | value),
+        };
+        let load_temp = match operand {
+            (true, y) => 0x8000 | temp << 8 | y << 4,
+            (false, value) => 0x6000 | temp << 8 | value,
+        };
+        let (subtraction, flag) = match condition.comparison {
+            Comparison::Equal => return self.instruction(skip_not_equal),
+            Comparison::NotEqual => return self.instruction(skip_equal),
+            Comparison::Key => return self.instruction(0xe0a1 | x),
+            Comparison::NotKey => return self.instruction(0xe09e | x),
+            // temp - x doesn't borrow when x <= operand.
+            Comparison::Greater => (0x5, 1),
+            Comparison::LessOrEqual => (0x5, 0),
+            // x - temp doesn't borrow when x >= operand.
+            Comparison::Less => (0x7, 1),
+            Comparison::GreaterOrEqual => (0x7, 0),
+        };
+        self.instruction(load_temp)?;
+        self.instruction(0x8000 | temp << 8 | (condition.register as u16) << 4 | subtraction)?;
+        self.instruction(0x3000 | temp << 8 | flag)
+    }
+
+    fn define_macro(self: &mut Self) -> Result<(), String> {
+        let name = self.next()?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            arguments.push(token.text);
+        }
+        let body = self.block()?;
+        self.macros.insert(
+            name.text,
+            Macro {
+                arguments,
+                body,
+                calls: 0,
+            },
+        );
+        Ok(())
+    }
+
+    // The tokens up to the } matching a { that was just read.
+    fn block(self: &mut Self) -> Result<Vec<Token>, String> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => (),
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand(self: &mut Self, name: &Token) -> Result<(), String> {
+        let count = self.macros[&name.text].arguments.len();
+        let mut values = HashMap::new();
+        for index in 0..count {
+            let value = self.next()?;
+            values.insert(self.macros[&name.text].arguments[index].clone(), value.text);
+        }
+        let definition = self.macros.get_mut(&name.text).unwrap();
+        values.insert("CALLS".to_string(), definition.calls.to_string());
+        definition.calls += 1;
+        for token in definition.body.iter().rev() {
+            let text = values.get(&token.text).unwrap_or(&token.text);
+            self.tokens.push_front(Token {
+                text: text.clone(),
+                line: name.line,
+            });
+        }
+        Ok(())
+    }
+
+    // A { expression } of :calc and friends. As in Octo, operators have no precedence and
+    // group from the right, so `2 * 3 + 1` is 8, and parentheses group explicitly.
+    fn calc(self: &mut Self) -> Result<f64, String> {
+        self.expect("{")?;
+        let tokens = self.block()?;
+        let mut position = 0;
+        let value = self.calc_expression(&tokens, &mut position)?;
+        match tokens.get(position) {
+            None => Ok(value),
+            Some(token) => Err(format!("Unexpected {} in expression.", token.text)),
+        }
+    }
+
+    fn calc_expression(self: &Self, tokens: &[Token], position: &mut usize) -> Result<f64, String> {
+        let left = self.calc_term(tokens, position)?;
+        let operator = match tokens.get(*position) {
+            None => return Ok(left),
+            Some(token) if token.text == ")" => return Ok(left),
+            Some(token) => token.text.as_str(),
+        };
+        *position += 1;
+        let right = self.calc_expression(tokens, position)?;
+        let integer = |value: f64| value as i64;
+        let boolean = |value: bool| if value { 1.0 } else { 0.0 };
+        Ok(match operator {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (integer(left) & integer(right)) as f64,
+            "|" => (integer(left) | integer(right)) as f64,
+            "^" => (integer(left) ^ integer(right)) as f64,
+            "<<" => (integer(left) << integer(right)) as f64,
+            ">>" => (integer(left) >> integer(right)) as f64,
+            "<" => boolean(left < right),
+            ">" => boolean(left > right),
+            "<=" => boolean(left <= right),
+            ">=" => boolean(left >= right),
+            "==" => boolean(left == right),
+            "!=" => boolean(left != right),
+            _ => return Err(format!("Unknown operator in expression: {}", operator)),
+        })
+    }
+
+    fn calc_term(self: &Self, tokens: &[Token], position: &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*position).ok_or("Incomplete expression.")?;
+        *position += 1;
+        let text = token.text.as_str();
+        if text == "(" {
+            let value = self.calc_expression(tokens, position)?;
+            match tokens.get(*position) {
+                Some(token) if token.text == ")" => *position += 1,
+                _ => return Err("Missing ) in expression.".to_string()),
+            }
+            return Ok(value);
+        }
+        let unary: Option<fn(f64) -> f64> = match text {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64) as f64),
+            "!" => Some(|value| if value == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term(tokens, position)?));
+        }
+        if text == "@" {
+            let address = self.calc_term(tokens, position)? as i64;
+            return match self.memory.get(address as usize) {
+                Some(&byte) if address >= 0 => Ok(byte as f64),
+                _ => Err(format!("Address out of memory: {}", address)),
+            };
+        }
+        match text {
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => parse_number(text)
+                .or_else(|| self.constants.get(text).copied())
+                .or_else(|| self.labels.get(text).map(|&label| label as f64))
+                .ok_or_else(|| format!("Undefined name in expression: {}", text)),
+        }
+    }
+}
+
+impl Condition {
+    fn negated(self: &Self) -> Condition {
+        let comparison = match self.comparison {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterOrEqual,
+            Comparison::GreaterOrEqual => Comparison::Less,
+            Comparison::Greater => Comparison::LessOrEqual,
+            Comparison::LessOrEqual => Comparison::Greater,
+            Comparison::Key => Comparison::NotKey,
+            Comparison::NotKey => Comparison::Key,
+        };
+        Condition {
+            comparison,
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{Interpreter, Quirks};
+    use crate::keypad::Keypad;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        compile(source).unwrap()
+    }
+
+    #[test]
+    fn starts_with_main_or_jumps_to_it() {
+        assert_eq!(bytes(": main clear loop again"), [0x00, 0xe0, 0x12, 0x02]);
+        assert_eq!(
+            bytes(": sub v0 := 1 ; : main sub"),
+            [0x12, 0x06, 0x60, 0x01, 0x00, 0xee, 0x22, 0x02]
+        );
+    }
+
+    #[test]
+    fn resolves_labels_defined_later() {
+        let source = "
+            : main
+              draw
+              jump main
+            : draw
+              i := sprite
+              sprite v0 v1 1
+              return
+            : sprite
+              0xFF
+        ";
+        assert_eq!(
+            bytes(source),
+            [0x22, 0x04, 0x12, 0x00, 0xa2, 0x0a, 0xd0, 0x11, 0x00, 0xee, 0xff]
+        );
+    }
+
+    #[test]
+    fn compiles_branches_and_loops() {
+        assert_eq!(
+            bytes(": main if v0 == 5 then v1 := 1 if v0 != v2 begin v1 := 2 else v1 := 3 end"),
+            [0x40, 0x05, 0x61, 0x01, 0x90, 0x20, 0x12, 0x0c, 0x61, 0x02, 0x12, 0x0e, 0x61, 0x03]
+        );
+        assert_eq!(
+            bytes(": main loop v0 += 1 while v0 != 10 again"),
+            [0x70, 0x01, 0x40, 0x0a, 0x12, 0x08, 0x12, 0x00]
+        );
+        assert_eq!(
+            bytes(": main if v3 key then clear if v3 -key then clear"),
+            [0xe3, 0xa1, 0x00, 0xe0, 0xe3, 0x9e, 0x00, 0xe0]
+        );
+    }
+
+    // Runs `if v1 <comparison> then v2 := 1` and tells whether v2 was set.
+    fn holds(value: u8, comparison: &str, operand: &str, with_begin: bool) -> bool {
+        let body = if with_begin {
+            format!("if v1 {} {} begin v2 := 1 end", comparison, operand)
+        } else {
+            format!("if v1 {} {} then v2 := 1", comparison, operand)
+        };
+        let source = format!(": main v1 := {} v3 := 5 {} loop again", value, body);
+        let mut interpreter = Interpreter::new(&bytes(&source));
+        interpreter.set_trace(false);
+        interpreter.set_quirks(Quirks {
+            vf_order: false,
+            ..Default::default()
+        });
+        interpreter.run_frame(&mut Keypad::new(0), 20).unwrap();
+        interpreter.registers()[2] == 1
+    }
+
+    #[test]
+    fn comparison_pseudo_ops_compare() {
+        for with_begin in [false, true] {
+            for operand in ["5", "v3"] {
+                for value in [4, 5, 6] {
+                    let cases = [
+                        ("==", value == 5),
+                        ("!=", value != 5),
+                        ("<", value < 5),
+                        (">", value > 5),
+                        ("<=", value <= 5),
+                        (">=", value >= 5),
+                    ];
+                    for (comparison, expected) in cases {
+                        assert_eq!(
+                            holds(value, comparison, operand, with_begin),
+                            expected,
+                            "{} {} {}",
+                            value,
+                            comparison,
+                            operand
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn evaluates_directives() {
+        let source = "
+            : main
+              :const five 5
+              :alias x v3
+              x := five
+              :calc eight { 2 * 3 + 1 }
+              :byte eight
+              :byte { ( 2 * 3 ) + 1 }
+              :unpack 0xA data
+              i := target
+            :next target
+              v0 := 0
+            :org 0x230
+            : data
+              -1
+        ";
+        assert_eq!(
+            bytes(source)[..14],
+            [0x63, 0x05, 0x08, 0x07, 0x60, 0xa2, 0x61, 0x30, 0xa2, 0x0b, 0x60, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(bytes(source)[0x30], 0xff);
+    }
+
+    #[test]
+    fn expands_macros() {
+        let source = "
+            :macro bump register amount { register += amount }
+            : main
+              bump v2 1
+              bump v3 2
+        ";
+        assert_eq!(bytes(source), [0x72, 0x01, 0x73, 0x02]);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        for (source, error) in [
+            (": main\n  jump nowhere", "line 2: Undefined name: nowhere"),
+            (
+                ": main\n  v0 := 256",
+                "line 2: Value doesn't fit in a byte: 256",
+            ),
+            (
+                ": main\n\n  hires",
+                "line 3: SCHIP and XO-CHIP's hires isn't supported.",
+            ),
+            (": main loop", "A loop is missing its again."),
+            (": main : main", "line 1: Name defined twice: main"),
+            ("clear", "The program has no main label."),
+        ] {
+            assert_eq!(compile(source).err().as_deref(), Some(error), "{}", source);
+        }
+    }
+}
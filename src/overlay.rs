@@ -0,0 +1,202 @@
+use crate::disassembler;
+use crate::interpreter::Interpreter;
+use crate::keypad::{Key, Keypad};
+use crate::memory_view::MemoryView;
+
+// Instructions shown before and after the current one.
+const DISASSEMBLY_CONTEXT: u16 = 7;
+
+// The keys in the order they are laid out on the COSMAC VIP keypad.
+const KEYPAD_LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xc],
+    [0x4, 0x5, 0x6, 0xd],
+    [0x7, 0x8, 0x9, 0xe],
+    [0xa, 0x0, 0xb, 0xf],
+];
+
+// What a piece of text stands for, which the display shows with its own colour.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Normal,
+    ProgramCounter,
+    MemoryRegister,
+    Font,
+    Written,
+    Cursor,
+}
+
+pub struct Span {
+    pub text: String,
+    pub highlight: Highlight,
+}
+
+impl Span {
+    pub fn new(text: String, highlight: Highlight) -> Span {
+        Span { text, highlight }
+    }
+}
+
+pub type Line = Vec<Span>;
+
+// Interpreter state as columns of text, drawn over the screen by the display. The second
+// column is either the disassembly around the PC or, when it is open, the memory view.
+pub struct Overlay {
+    pub columns: Vec<Vec<Line>>,
+}
+
+impl Overlay {
+    pub fn new(
+        interpreter: &Interpreter,
+        keypad: &Keypad,
+        memory_view: Option<&MemoryView>,
+    ) -> Overlay {
+        let state = state_lines(interpreter, keypad)
+            .into_iter()
+            .map(|line| vec![Span::new(line, Highlight::Normal)])
+            .collect();
+        let details = match memory_view {
+            Some(memory_view) => memory_view.lines(interpreter),
+            None => disassembly_lines(interpreter),
+        };
+        Overlay {
+            columns: vec![state, details],
+        }
+    }
+}
+
+fn state_lines(interpreter: &Interpreter, keypad: &Keypad) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "PC {:03X}  I {:03X}",
+            interpreter.program_counter(),
+            interpreter.memory_register()
+        ),
+        format!(
+            "DT {:02X}   ST {:02X}",
+            interpreter.delay_timer(),
+            interpreter.sound_timer()
+        ),
+    ];
+
+    let registers = interpreter.registers();
+    for row in 0..4 {
+        let line: Vec<String> = (row * 4..row * 4 + 4)
+            .map(|register| format!("V{:X} {:02X}", register, registers[register]))
+            .collect();
+        lines.push(line.join(" "));
+    }
+
+    let stack = interpreter.stack();
+    lines.push(format!("STACK {}", stack.len()));
+    for entries in stack.chunks(4) {
+        let line: Vec<String> = entries
+            .iter()
+            .map(|address| format!("{:03X}", address))
+            .collect();
+        lines.push(line.join(" "));
+    }
+
+    lines.push("KEYS".to_string());
+    for row in KEYPAD_LAYOUT {
+        let line: Vec<String> = row
+            .iter()
+            .map(|&key| {
+                if keypad.get_key_state(Key::from(key)).is_down() {
+                    format!("{:X}", key)
+                } else {
+                    ".".to_string()
+                }
+            })
+            .collect();
+        lines.push(line.join(" "));
+    }
+
+    lines
+}
+
+fn disassembly_lines(interpreter: &Interpreter) -> Vec<Line> {
+    let memory = interpreter.memory();
+    let program_counter = interpreter.program_counter();
+    let start = program_counter.saturating_sub(DISASSEMBLY_CONTEXT * 2);
+    let end = program_counter + (DISASSEMBLY_CONTEXT + 1) * 2;
+
+    let symbols = interpreter.symbols();
+
+    let mut lines = Vec::new();
+    for address in (start..end)
+        .step_by(2)
+        .filter(|&address| (address as usize) < memory.len())
+    {
+        if let Some(label) = symbols.label(address) {
+            lines.push(vec![Span::new(format!("{}:", label), Highlight::Normal)]);
+        }
+        let opcode = disassembler::read_opcode(memory, address);
+        let (marker, highlight) = if address == program_counter {
+            ('>', Highlight::ProgramCounter)
+        } else {
+            (' ', Highlight::Normal)
+        };
+        let text = format!(
+            "{}{:03X} {:04X} {}",
+            marker,
+            address,
+            opcode,
+            disassembler::disassemble_at(memory, address, symbols)
+        );
+        lines.push(vec![Span::new(text, highlight)]);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(line: &Line) -> String {
+        line.iter().map(|span| span.text.as_str()).collect()
+    }
+
+    // CALL #204; JP #202; LD V3, #AB; LD ST, V3; RET, stopped after LD V3, #AB.
+    fn overlay(memory_view: Option<&MemoryView>) -> Overlay {
+        let mut interpreter =
+            Interpreter::new(&[0x22, 0x04, 0x12, 0x02, 0x63, 0xab, 0xf3, 0x18, 0x00, 0xee]);
+        interpreter.set_trace(false);
+        let mut keypad = Keypad::new(0);
+        interpreter.run_frame(&mut keypad, 2).unwrap();
+        keypad.push_event(0, Key::Key5, true);
+        keypad.push_event(0, Key::KeyF, true);
+        keypad.settle();
+        Overlay::new(&interpreter, &keypad, memory_view)
+    }
+
+    #[test]
+    fn shows_registers_stack_and_keys() {
+        let overlay = overlay(None);
+        let state: Vec<String> = overlay.columns[0].iter().map(text).collect();
+        assert_eq!(state[0], "PC 206  I 000");
+        assert_eq!(state[1], "DT 00   ST 00");
+        assert_eq!(state[2], "V0 00 V1 00 V2 00 V3 AB");
+        assert_eq!(state[6..8], ["STACK 1", "202"]);
+        assert_eq!(
+            state[8..],
+            ["KEYS", ". . . .", ". 5 . .", ". . . .", ". . . F"]
+        );
+    }
+
+    #[test]
+    fn shows_the_disassembly_around_the_program_counter() {
+        let overlay = overlay(None);
+        let lines: Vec<String> = overlay.columns[1].iter().map(text).collect();
+        let current = lines.iter().position(|line| line.starts_with('>')).unwrap();
+        assert_eq!(lines[current], ">206 F318 LD ST, V3");
+        assert_eq!(lines[current - 1], " 204 63AB LD V3, #AB");
+        assert_eq!(lines.len(), 2 * DISASSEMBLY_CONTEXT as usize + 1);
+        assert!(overlay.columns[1][current][0].highlight == Highlight::ProgramCounter);
+    }
+
+    #[test]
+    fn shows_the_memory_view_instead_when_open() {
+        let overlay = overlay(Some(&MemoryView::default()));
+        assert_eq!(text(&overlay.columns[1][0]), "MEMORY 200");
+    }
+}
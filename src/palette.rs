@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::Path;
+
+pub const BACKGROUND: usize = 0;
+pub const FILL: usize = 1;
+pub const FILL2: usize = 2;
+pub const BLEND: usize = 3;
+
+// Colours are indexed by the bits a pixel has set in each plane: background (no plane),
+// fill (first plane), fill2 (second plane) and blend (both planes). Single-plane programs
+// only ever use the first two.
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [u32; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new("default", [0x222222, 0x00cc11, 0x0066cc, 0xeeeeee])
+    }
+}
+
+impl Palette {
+    pub fn new(name: &str, colors: [u32; 4]) -> Palette {
+        Palette {
+            name: name.to_string(),
+            colors,
+        }
+    }
+
+    pub fn built_in() -> Vec<Palette> {
+        vec![
+            Palette::default(),
+            Palette::new("octo", [0x996600, 0xffcc00, 0xff6600, 0x662200]),
+            Palette::new("black-and-white", [0x000000, 0xffffff, 0xaaaaaa, 0x555555]),
+            Palette::new("amber", [0x1a0f00, 0xffb000, 0xcc6600, 0xfff0c0]),
+            // Dark blue and yellow stay distinguishable with the common colour vision deficiencies.
+            Palette::new("high-contrast", [0x000033, 0xffff00, 0x00ccff, 0xffffff]),
+        ]
+    }
+
+    // Palette files have one colour per line, in the form `<name> = #RRGGBB`, where the
+    // name is one of background, fill, fill2 or blend, plus an optional `name = <name>`.
+    // Colours that aren't given keep their value from the default palette. `#` at the
+    // start of a line starts a comment.
+    pub fn parse(text: &str, default_name: &str) -> Result<Palette, String> {
+        let mut palette = Palette {
+            name: default_name.to_string(),
+            ..Palette::default()
+        };
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => {
+                    return Err(format!(
+                        "Palette line {}: expected `<name> = <value>`.",
+                        line_number + 1
+                    ))
+                }
+            };
+            let index = match name {
+                "name" => {
+                    palette.name = value.to_string();
+                    continue;
+                }
+                "background" => BACKGROUND,
+                "fill" => FILL,
+                "fill2" => FILL2,
+                "blend" => BLEND,
+                _ => {
+                    return Err(format!(
+                        "Palette line {}: unknown colour {}.",
+                        line_number + 1,
+                        name
+                    ))
+                }
+            };
+            palette.colors[index] = match parse_color(value) {
+                Some(color) => color,
+                None => {
+                    return Err(format!(
+                        "Palette line {}: invalid colour {}.",
+                        line_number + 1,
+                        value
+                    ))
+                }
+            };
+        }
+
+        Ok(palette)
+    }
+
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let default_name = match Path::new(path).file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => path.to_string(),
+        };
+        match fs::read_to_string(path) {
+            Ok(text) => Palette::parse(&text, &default_name),
+            Err(err) => Err(format!("Failed to read palette {}: {}", path, err)),
+        }
+    }
+
+    // Looks the palette up among the built-in ones first, then loads it as a file.
+    pub fn find(name_or_path: &str) -> Result<Palette, String> {
+        match Palette::built_in()
+            .into_iter()
+            .find(|p| p.name == name_or_path)
+        {
+            Some(palette) => Ok(palette),
+            None => Palette::load(name_or_path),
+        }
+    }
+}
+
+fn parse_color(value: &str) -> Option<u32> {
+    let hex = value.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_colours_over_the_default_palette() {
+        let palette = Palette::parse(
+            "# Game Boy\nname = green\n  background = #0F380F\nfill=#9bbc0f\n",
+            "file",
+        )
+        .unwrap();
+        assert_eq!(palette.name, "green");
+        assert_eq!(palette.colors, [0x0f380f, 0x9bbc0f, 0x0066cc, 0xeeeeee]);
+        assert_eq!(Palette::parse("", "file").unwrap().name, "file");
+    }
+
+    #[test]
+    fn reports_invalid_lines() {
+        for (text, err) in [
+            (
+                "fill #ffffff",
+                "Palette line 1: expected `<name> = <value>`.",
+            ),
+            (
+                "\nforeground = #ffffff",
+                "Palette line 2: unknown colour foreground.",
+            ),
+            ("fill = ffffff", "Palette line 1: invalid colour ffffff."),
+            ("fill = #fff", "Palette line 1: invalid colour #fff."),
+            ("fill = #gggggg", "Palette line 1: invalid colour #gggggg."),
+        ] {
+            assert_eq!(
+                Palette::parse(text, "file").err().as_deref(),
+                Some(err),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn finds_built_in_palettes_before_files() {
+        assert_eq!(Palette::find("amber").unwrap().colors[FILL], 0xffb000);
+        let err = Palette::find("no-such-palette").err().unwrap();
+        assert!(
+            err.starts_with("Failed to read palette no-such-palette:"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::disassembler;
+use crate::interpreter::Instruction;
+
+// Entries listed in each section of the report.
+const REPORT_LENGTH: usize = 20;
+
+// A backward jump, which is how loops look in CHIP-8 code.
+#[derive(Debug, Default)]
+struct LoopStats {
+    taken: u64,
+}
+
+#[derive(Debug, Default)]
+struct SubroutineStats {
+    calls: u64,
+    // Instructions executed between the call and its return, including nested calls.
+    instructions: u64,
+    max_instructions: u64,
+}
+
+// Counts executed instructions, by address and by kind, along with loops and subroutine
+// calls, and the number of instructions per frame.
+#[derive(Debug)]
+pub struct Profiler {
+    address_counts: Vec<u64>,
+    instruction_counts: HashMap<&'static str, u64>,
+    total: u64,
+    loops: HashMap<(u16, u16), LoopStats>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    // The subroutines currently running, with the instruction count when they were called.
+    call_stack: Vec<(u16, u64)>,
+    frames: u64,
+    frame_instructions: u64,
+    max_frame_instructions: u64,
+}
+
+impl Profiler {
+    pub fn new(memory_size: usize) -> Profiler {
+        Profiler {
+            address_counts: vec![0; memory_size],
+            instruction_counts: HashMap::new(),
+            total: 0,
+            loops: HashMap::new(),
+            subroutines: HashMap::new(),
+            call_stack: Vec::new(),
+            frames: 0,
+            frame_instructions: 0,
+            max_frame_instructions: 0,
+        }
+    }
+
+    // Called for every instruction, with the program counter before and after executing it.
+    pub fn record(self: &mut Self, address: u16, instruction: &Instruction, next_address: u16) {
+        if let Some(count) = self.address_counts.get_mut(address as usize) {
+            *count += 1;
+        }
+        *self
+            .instruction_counts
+            .entry(instruction_name(instruction))
+            .or_insert(0) += 1;
+        self.total += 1;
+        self.frame_instructions += 1;
+
+        match instruction {
+            Instruction::JP(..) | Instruction::JP0A(..) if next_address <= address => {
+                self.loops.entry((next_address, address)).or_default().taken += 1;
+            }
+            Instruction::CALL(..) => self.call_stack.push((next_address, self.total)),
+            Instruction::RET => {
+                if let Some((subroutine, called_at)) = self.call_stack.pop() {
+                    let stats = self.subroutines.entry(subroutine).or_default();
+                    let instructions = self.total - called_at;
+                    stats.calls += 1;
+                    stats.instructions += instructions;
+                    stats.max_instructions = stats.max_instructions.max(instructions);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn end_frame(self: &mut Self) {
+        self.frames += 1;
+        self.max_frame_instructions = self.max_frame_instructions.max(self.frame_instructions);
+        self.frame_instructions = 0;
+    }
+
+    // The memory is used to disassemble the hottest addresses.
+    pub fn report(self: &Self, memory: &[u8]) -> String {
+        let mut report = String::new();
+        let share = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+
+        let _ = writeln!(
+            report,
+            "{} instructions over {} frames, {:.1} per frame on average, at most {}.",
+            self.total,
+            self.frames,
+            self.total as f64 / self.frames.max(1) as f64,
+            self.max_frame_instructions
+        );
+
+        let _ = writeln!(report, "\nHottest addresses:");
+        let mut addresses: Vec<(usize, u64)> = self
+            .address_counts
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (address, count) in addresses.iter().take(REPORT_LENGTH) {
+            let opcode = disassembler::read_opcode(memory, *address as u16);
+            let _ = writeln!(
+                report,
+                "  {:03X}  {:04X}  {:<16} {:>10} {:5.1}%",
+                address,
+                opcode,
+                disassembler::disassemble(opcode),
+                count,
+                share(*count)
+            );
+        }
+
+        let _ = writeln!(report, "\nInstructions by kind:");
+        let mut instructions: Vec<(&&str, &u64)> = self.instruction_counts.iter().collect();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (name, count) in instructions {
+            let _ = writeln!(report, "  {:<6} {:>10} {:5.1}%", name, count, share(*count));
+        }
+
+        // Loops are ranked by the instructions executed inside them.
+        let _ = writeln!(report, "\nHot loops:");
+        let mut loops: Vec<((u16, u16), u64, u64)> = self
+            .loops
+            .iter()
+            .map(|(&(start, end), stats)| {
+                let range = start as usize..=(end as usize).min(self.address_counts.len() - 1);
+                let instructions = self.address_counts[range].iter().sum();
+                ((start, end), stats.taken, instructions)
+            })
+            .collect();
+        loops.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        for ((start, end), taken, instructions) in loops.iter().take(REPORT_LENGTH) {
+            let _ = writeln!(
+                report,
+                "  {:03X}-{:03X}  repeated {:>8} times {:>10} instructions {:5.1}%",
+                start,
+                end,
+                taken,
+                instructions,
+                share(*instructions)
+            );
+        }
+
+        let _ = writeln!(report, "\nSubroutines (instructions include nested calls):");
+        let mut subroutines: Vec<(&u16, &SubroutineStats)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        for (address, stats) in subroutines.iter().take(REPORT_LENGTH) {
+            let _ = writeln!(
+                report,
+                "  {:03X}  {:>8} calls {:>10} instructions {:5.1}%  {:.1} per call, at most {}",
+                address,
+                stats.calls,
+                stats.instructions,
+                share(stats.instructions),
+                stats.instructions as f64 / stats.calls as f64,
+                stats.max_instructions
+            );
+        }
+
+        report
+    }
+}
+
+// The variant's name, e.g. LDRV.
+fn instruction_name(instruction: &Instruction) -> &'static str {
+    match instruction {
+        Instruction::INVALID => "INVALID",
+        Instruction::SYS => "SYS",
+        Instruction::CLS => "CLS",
+        Instruction::RET => "RET",
+        Instruction::JP(..) => "JP",
+        Instruction::CALL(..) => "CALL",
+        Instruction::SERV(..) => "SERV",
+        Instruction::SNERV(..) => "SNERV",
+        Instruction::SERR(..) => "SERR",
+        Instruction::LDRV(..) => "LDRV",
+        Instruction::ADDRV(..) => "ADDRV",
+        Instruction::LDRR(..) => "LDRR",
+        Instruction::ORRR(..) => "ORRR",
+        Instruction::ANDRR(..) => "ANDRR",
+        Instruction::XORRR(..) => "XORRR",
+        Instruction::ADDRR(..) => "ADDRR",
+        Instruction::SUBRR(..) => "SUBRR",
+        Instruction::SHR(..) => "SHR",
+        Instruction::SUBN(..) => "SUBN",
+        Instruction::SHL(..) => "SHL",
+        Instruction::SNERR(..) => "SNERR",
+        Instruction::LDI(..) => "LDI",
+        Instruction::JP0A(..) => "JP0A",
+        Instruction::RND(..) => "RND",
+        Instruction::DRW(..) => "DRW",
+        Instruction::SKP(..) => "SKP",
+        Instruction::SKNP(..) => "SKNP",
+        Instruction::LDRDT(..) => "LDRDT",
+        Instruction::LDRK(..) => "LDRK",
+        Instruction::LDDTR(..) => "LDDTR",
+        Instruction::LDSTR(..) => "LDSTR",
+        Instruction::ADDI(..) => "ADDI",
+        Instruction::LDF(..) => "LDF",
+        Instruction::LDB(..) => "LDB",
+        Instruction::LDIR(..) => "LDIR",
+        Instruction::LDRI(..) => "LDRI",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::keypad::Keypad;
+
+    // Calls a subroutine five times in a loop, then loops forever.
+    const PROGRAM: [u8; 12] = [
+        0x22, 0x0a, // 200: call 20A
+        0x70, 0x01, // 202: v0 += 1
+        0x30, 0x05, // 204: if v0 == 5 skip
+        0x12, 0x00, // 206: jump 200
+        0x12, 0x08, // 208: jump 208
+        0x00, 0xee, // 20A: return
+    ];
+
+    fn report() -> String {
+        let mut interpreter = Interpreter::new(&PROGRAM);
+        interpreter.set_trace(false);
+        interpreter.enable_profiler();
+        let mut keypad = Keypad::new(0);
+        for _ in 0..3 {
+            interpreter.run_frame(&mut keypad, 10).unwrap();
+        }
+        interpreter.profiler().unwrap().report(interpreter.memory())
+    }
+
+    #[test]
+    fn reports_addresses_instructions_loops_and_subroutines() {
+        assert_eq!(
+            report(),
+            "30 instructions over 3 frames, 10.0 per frame on average, at most 10.
+
+Hottest addresses:
+  208  1208  JP #208                   6  20.0%
+  200  220A  CALL #20A                 5  16.7%
+  202  7001  ADD V0, #01               5  16.7%
+  204  3005  SE V0, #05                5  16.7%
+  20A  00EE  RET                       5  16.7%
+  206  1200  JP #200                   4  13.3%
+
+Instructions by kind:
+  JP             10  33.3%
+  ADDRV           5  16.7%
+  CALL            5  16.7%
+  RET             5  16.7%
+  SERV            5  16.7%
+
+Hot loops:
+  200-206  repeated        4 times         19 instructions  63.3%
+  208-208  repeated        6 times          6 instructions  20.0%
+
+Subroutines (instructions include nested calls):
+  20A         5 calls          5 instructions  16.7%  1.0 per call, at most 1
+"
+        );
+    }
+
+    #[test]
+    fn nested_calls_count_towards_their_callers() {
+        let mut profiler = Profiler::new(0x1000);
+        profiler.record(0x200, &Instruction::CALL(0x300), 0x300);
+        profiler.record(0x300, &Instruction::CALL(0x400), 0x400);
+        profiler.record(0x400, &Instruction::LDRV(0, 1), 0x402);
+        profiler.record(0x402, &Instruction::RET, 0x302);
+        profiler.record(0x302, &Instruction::RET, 0x202);
+        // A return without a call is ignored.
+        profiler.record(0x202, &Instruction::RET, 0x000);
+        profiler.end_frame();
+
+        let report = profiler.report(&[0; 0x1000]);
+        assert!(report.contains("\n  300         1 calls          4 instructions  66.7%"));
+        assert!(report.contains("\n  400         1 calls          2 instructions  33.3%"));
+        // Nothing jumped backwards, so there are no loops.
+        assert!(report.contains("Hot loops:\n\nSubroutines"));
+    }
+}
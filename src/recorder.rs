@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::image::Image;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    Gif,
+    // Uncompressed YUV 4:4:4, see https://wiki.multimedia.cx/index.php/YUV4MPEG2
+    Y4m,
+}
+
+impl RecordingFormat {
+    pub fn from_path(path: &str) -> Result<RecordingFormat, String> {
+        let lowercase = path.to_lowercase();
+        if lowercase.ends_with(".gif") {
+            Ok(RecordingFormat::Gif)
+        } else if lowercase.ends_with(".y4m") {
+            Ok(RecordingFormat::Y4m)
+        } else {
+            Err(format!(
+                "Unknown recording format for {}, expected .gif or .y4m.",
+                path
+            ))
+        }
+    }
+
+    pub fn extension(self: &Self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::Y4m => "y4m",
+        }
+    }
+}
+
+enum Encoder {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Y4m(BufWriter<File>),
+}
+
+// Records one image per emulated frame. Timing comes from the number of frames, at 60 per
+// second, so the recording plays at emulation speed whatever the wall clock did.
+pub struct Recorder {
+    path: String,
+    encoder: Encoder,
+    scale: u32,
+    width: u32,
+    height: u32,
+    frame_count: u64,
+    // GIF delays are in hundredths of a second, which 60 Hz doesn't divide evenly. Identical
+    // frames are merged, so the last frame is kept until the next different one shows up,
+    // and delays are rounded so that they add up to the right total.
+    pending_gif_frame: Option<(Vec<u8>, Vec<u8>)>,
+    written_centiseconds: u64,
+}
+
+impl Recorder {
+    pub fn start(path: &str, scale: u32, width: u32, height: u32) -> Result<Recorder, String> {
+        let format = RecordingFormat::from_path(path)?;
+        let file = match File::create(path) {
+            Ok(file) => BufWriter::new(file),
+            Err(err) => return Err(format!("Failed to create {}: {}", path, err)),
+        };
+
+        let encoder = match format {
+            RecordingFormat::Gif => {
+                let mut encoder = match gif::Encoder::new(file, width as u16, height as u16, &[]) {
+                    Ok(encoder) => encoder,
+                    Err(err) => return Err(format!("Failed to write {}: {}", path, err)),
+                };
+                if let Err(err) = encoder.set_repeat(gif::Repeat::Infinite) {
+                    return Err(format!("Failed to write {}: {}", path, err));
+                }
+                Encoder::Gif(encoder)
+            }
+            RecordingFormat::Y4m => {
+                let mut file = file;
+                let header = format!("YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444\n", width, height);
+                if let Err(err) = file.write_all(header.as_bytes()) {
+                    return Err(format!("Failed to write {}: {}", path, err));
+                }
+                Encoder::Y4m(file)
+            }
+        };
+
+        Ok(Recorder {
+            path: path.to_string(),
+            encoder,
+            scale,
+            width,
+            height,
+            frame_count: 0,
+            pending_gif_frame: None,
+            written_centiseconds: 0,
+        })
+    }
+
+    pub fn path(self: &Self) -> &str {
+        &self.path
+    }
+
+    pub fn scale(self: &Self) -> u32 {
+        self.scale
+    }
+
+    pub fn add_frame(self: &mut Self, image: &Image) -> Result<(), String> {
+        if image.width != self.width || image.height != self.height {
+            return Err("Recorded frames must all have the same size.".to_string());
+        }
+
+        let result = match &mut self.encoder {
+            Encoder::Gif(_) => {
+                let (palette, indices) = to_indexed(image);
+                let unchanged = match &self.pending_gif_frame {
+                    Some((pending_palette, pending_indices)) => {
+                        *pending_palette == palette && *pending_indices == indices
+                    }
+                    None => false,
+                };
+                if unchanged {
+                    Ok(())
+                } else {
+                    let result = self.write_pending_gif_frame();
+                    self.pending_gif_frame = Some((palette, indices));
+                    result
+                }
+            }
+            Encoder::Y4m(file) => write_y4m_frame(file, image),
+        };
+        self.frame_count += 1;
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("Failed to write {}: {}", self.path, err)),
+        }
+    }
+
+    fn write_pending_gif_frame(self: &mut Self) -> Result<(), String> {
+        let (palette, indices) = match self.pending_gif_frame.take() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let encoder = match &mut self.encoder {
+            Encoder::Gif(encoder) => encoder,
+            _ => return Ok(()),
+        };
+
+        // The pending frame lasts until the end of the frames added so far.
+        let end_centiseconds = (self.frame_count * 100 + 30) / 60;
+        let delay = end_centiseconds - self.written_centiseconds;
+        self.written_centiseconds = end_centiseconds;
+
+        let mut frame = gif::Frame::from_palette_pixels(
+            self.width as u16,
+            self.height as u16,
+            &indices,
+            &palette,
+            None,
+        );
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        match encoder.write_frame(&frame) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    pub fn finish(mut self: Self) -> Result<(), String> {
+        let result = match self.encoder {
+            Encoder::Gif(_) => self.write_pending_gif_frame(),
+            Encoder::Y4m(ref mut file) => match file.flush() {
+                Ok(()) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            },
+        };
+        match result {
+            // The GIF trailer is written when the encoder is dropped.
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("Failed to write {}: {}", self.path, err)),
+        }
+    }
+}
+
+// Screen images only have a handful of colours, so they always fit in a GIF palette.
+fn to_indexed(image: &Image) -> (Vec<u8>, Vec<u8>) {
+    let mut colors: Vec<[u8; 3]> = Vec::new();
+    let mut indices = Vec::with_capacity((image.width * image.height) as usize);
+    for pixel in image.pixels.chunks_exact(3) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        let index = match colors.iter().position(|c| *c == color) {
+            Some(index) => index,
+            None if colors.len() < 256 => {
+                colors.push(color);
+                colors.len() - 1
+            }
+            None => 0,
+        };
+        indices.push(index as u8);
+    }
+
+    (colors.concat(), indices)
+}
+
+fn write_y4m_frame(file: &mut BufWriter<File>, image: &Image) -> Result<(), String> {
+    let pixel_count = (image.width * image.height) as usize;
+    let mut planes = vec![0u8; pixel_count * 3];
+    for (i, pixel) in image.pixels.chunks_exact(3).enumerate() {
+        // BT.601, studio range.
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+        let u = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+        let v = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+        planes[i] = y.round() as u8;
+        planes[pixel_count + i] = u.round() as u8;
+        planes[pixel_count * 2 + i] = v.round() as u8;
+    }
+
+    let result = file
+        .write_all(b"FRAME\n")
+        .and_then(|_| file.write_all(&planes));
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let file_name = format!("chip8emu-{}-{}", std::process::id(), name);
+        std::env::temp_dir()
+            .join(file_name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    // A 4x2 image with its first `lit` pixels white and the others black.
+    fn image(lit: usize) -> Image {
+        let pixels = (0..8)
+            .flat_map(|i| if i < lit { [255; 3] } else { [0; 3] })
+            .collect();
+        Image {
+            width: 4,
+            height: 2,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn picks_the_format_from_the_extension() {
+        assert!(RecordingFormat::from_path("run.GIF") == Ok(RecordingFormat::Gif));
+        assert!(RecordingFormat::from_path("run.y4m") == Ok(RecordingFormat::Y4m));
+        assert!(RecordingFormat::from_path("run.mp4").is_err());
+    }
+
+    #[test]
+    fn writes_gif_frames_with_their_duration() {
+        let path = temp_path("recording.gif");
+        let mut recorder = Recorder::start(&path, 1, 4, 2).unwrap();
+        for lit in [1, 1, 1, 5] {
+            recorder.add_frame(&image(lit)).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(File::open(&path).unwrap()).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            let lit = frame.buffer.chunks_exact(4).filter(|p| p[0] == 255).count();
+            frames.push((lit, frame.delay));
+        }
+        std::fs::remove_file(&path).unwrap();
+        // Identical frames are merged, and 4 60ths of a second round to 7 hundredths.
+        assert_eq!(frames, [(1, 5), (5, 2)]);
+    }
+
+    #[test]
+    fn writes_y4m_frames() {
+        let path = temp_path("recording.y4m");
+        let mut recorder = Recorder::start(&path, 1, 4, 2).unwrap();
+        recorder.add_frame(&image(8)).unwrap();
+        recorder.add_frame(&image(0)).unwrap();
+        recorder.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"YUV4MPEG2 W4 H2 F60:1 Ip A1:1 C444\n";
+        assert!(bytes.starts_with(header));
+        let frames: Vec<&[u8]> = bytes[header.len()..].chunks(6 + 8 * 3).collect();
+        assert_eq!(frames.len(), 2);
+        let white = [b"FRAME\n".as_slice(), &[235; 8], &[128; 16]].concat();
+        let black = [b"FRAME\n".as_slice(), &[16; 8], &[128; 16]].concat();
+        assert_eq!(frames[0], white.as_slice());
+        assert_eq!(frames[1], black.as_slice());
+    }
+
+    #[test]
+    fn rejects_frames_of_another_size() {
+        let path = temp_path("resized.y4m");
+        let mut recorder = Recorder::start(&path, 1, 4, 2).unwrap();
+        let err = recorder.add_frame(&image(0).scaled(2)).err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.as_deref(),
+            Some("Recorded frames must all have the same size.")
+        );
+    }
+}
@@ -1,57 +1,354 @@
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+// How long the tone takes to fade in or out. Switching the level abruptly makes an audible
+// click, which is noticeable given how often programs toggle the buzzer.
+const RAMP_SECONDS: f32 = 0.005;
+
+// Samples generated ahead of the audio device, in emulated frames. More frames than this
+// means the emulator is running ahead of the device, and the oldest samples are dropped to
+// keep the latency down.
+const MAX_BUFFERED_FRAMES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Result<Waveform, String> {
+        match name {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!(
+                "Unknown waveform {}, expected square, sine, triangle or noise.",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tone {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    // Between 0 and 1.
+    pub volume: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Tone {
+        Tone {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+        }
+    }
+}
+
+// Generates the buzzer's samples from emulated time: every emulated frame produces exactly
+// a 60th of a second of audio, so a sound timer of N lasts N/60 seconds whatever the host
+// is doing.
+pub struct Synth {
+    tone: Tone,
+    sample_rate: u32,
+    // Left over from dividing the sample rate between frames, in 60ths of a sample.
+    sample_remainder: u32,
+    phase: f32,
+    // Ramps towards 1 while the buzzer sounds and towards 0 otherwise.
+    gain: f32,
+    gain_step: f32,
+    noise_state: u32,
+    noise_value: f32,
+}
+
+impl Synth {
+    pub fn new(tone: Tone, sample_rate: u32) -> Synth {
+        Synth {
+            tone: Tone {
+                volume: tone.volume.clamp(0.0, 1.0),
+                ..tone
+            },
+            sample_rate,
+            sample_remainder: 0,
+            phase: 0.0,
+            gain: 0.0,
+            gain_step: 1.0 / (RAMP_SECONDS * sample_rate as f32),
+            noise_state: 0x1234_5678,
+            noise_value: 0.0,
+        }
+    }
+
+    // Appends the samples for one emulated frame, with the buzzer on or off for all of it.
+    pub fn generate_frame(self: &mut Self, buzzer: bool, out: &mut Vec<f32>) {
+        let total = self.sample_rate + self.sample_remainder;
+        let count = total / FRAMES_PER_SECOND;
+        self.sample_remainder = total % FRAMES_PER_SECOND;
+
+        let phase_inc = self.tone.frequency / self.sample_rate as f32;
+        for _ in 0..count {
+            self.gain = if buzzer {
+                (self.gain + self.gain_step).min(1.0)
+            } else {
+                (self.gain - self.gain_step).max(0.0)
+            };
+            out.push(self.sample() * self.tone.volume * self.gain);
+
+            let next_phase = self.phase + phase_inc;
+            // Noise picks a new level every half period, so the frequency still sets its pitch.
+            if (self.phase < 0.5 && next_phase >= 0.5) || next_phase >= 1.0 {
+                self.noise_value = self.next_noise();
+            }
+            self.phase = next_phase % 1.0;
+        }
+    }
+
+    fn next_noise(self: &mut Self) -> f32 {
+        // xorshift32, plenty random for a buzzer.
+        self.noise_state ^= self.noise_state << 13;
+        self.noise_state ^= self.noise_state >> 17;
+        self.noise_state ^= self.noise_state << 5;
+        (self.noise_state as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    fn sample(self: &Self) -> f32 {
+        match self.tone.waveform {
+            Waveform::Square => {
+                if self.phase <= 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise_value,
+        }
+    }
+}
+
+// A null sound has no audio device: samples are still generated, e.g. for recording, but
+// not played. It is what the frontend falls back to without a working audio device. Muting
+// only silences the device, so that recordings keep the sound.
 pub struct Sound {
-    audio_device: AudioDevice<SquareWave>,
+    audio_device: Option<AudioDevice<Playback>>,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    max_buffered: usize,
+    synth: Synth,
+    samples: Vec<f32>,
+    muted: bool,
 }
 
 impl Sound {
-    pub fn new(sdl_context: &sdl2::Sdl) -> Result<Sound, String> {
+    pub fn new(sdl_context: &sdl2::Sdl, tone: Tone) -> Result<Sound, String> {
         let audio_subsystem = sdl_context.audio()?;
         let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
+            freq: Some(SAMPLE_RATE as i32),
             channels: Some(1),
-            samples: None,
+            // About 12 ms, so that the device doesn't add much latency on top of the buffer.
+            samples: Some(512),
+        };
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let audio_device = audio_subsystem.open_playback(None, &desired_spec, |_| Playback {
+            buffer: buffer.clone(),
+        })?;
+
+        let sample_rate = audio_device.spec().freq as u32;
+        let max_buffered = (sample_rate * MAX_BUFFERED_FRAMES / FRAMES_PER_SECOND) as usize;
+        let mut sound = Sound {
+            audio_device: None,
+            buffer,
+            max_buffered,
+            synth: Synth::new(tone, sample_rate),
+            samples: Vec::new(),
+            muted: false,
         };
-        let audio_device =
-            audio_subsystem.open_playback(None, &desired_spec, |spec| SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
-            })?;
 
-        let sound = Sound { audio_device };
+        // A frame of silence up front absorbs the jitter between the emulator's frames and
+        // the device's callbacks.
+        sound.push_frame(false);
+        audio_device.resume();
+        sound.audio_device = Some(audio_device);
 
         Ok(sound)
     }
 
-    pub fn play(self: &mut Self) {
-        if self.audio_device.status() != AudioStatus::Playing {
-            self.audio_device.resume();
+    pub fn null(tone: Tone) -> Sound {
+        Sound {
+            audio_device: None,
+            buffer: Arc::new(Mutex::new(VecDeque::new())),
+            max_buffered: 0,
+            synth: Synth::new(tone, SAMPLE_RATE),
+            samples: Vec::new(),
+            muted: false,
+        }
+    }
+
+    // Generates the audio for one emulated frame and queues it for playback.
+    pub fn push_frame(self: &mut Self, buzzer: bool) {
+        self.samples.clear();
+        self.synth.generate_frame(buzzer, &mut self.samples);
+        if self.audio_device.is_none() {
+            return;
+        }
+
+        let mut buffer = self.buffer.lock().unwrap();
+        if self.muted {
+            // Silence still has to be queued, to keep the device going at the same pace.
+            buffer.extend(self.samples.iter().map(|_| 0.0));
+        } else {
+            buffer.extend(self.samples.iter());
+        }
+        let excess = buffer.len().saturating_sub(self.max_buffered);
+        buffer.drain(..excess);
+    }
+
+    pub fn toggle_mute(self: &mut Self) -> bool {
+        self.muted = !self.muted;
+        self.muted
+    }
+
+    pub fn sample_rate(self: &Self) -> u32 {
+        match &self.audio_device {
+            Some(audio_device) => audio_device.spec().freq as u32,
+            None => SAMPLE_RATE,
         }
     }
 
-    pub fn stop(self: &mut Self) {
-        self.audio_device.pause();
+    // The samples generated by the last call to `push_frame`.
+    pub fn last_frame(self: &Self) -> &[f32] {
+        &self.samples
     }
 }
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+// Plays back the samples queued by the emulator, and silence when there aren't any, e.g.
+// while the emulator is paused.
+struct Playback {
+    buffer: Arc<Mutex<VecDeque<f32>>>,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Playback {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            *x = buffer.pop_front().unwrap_or(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A second of buzzer with the given tone, without the ramp at its start.
+    fn second_of(waveform: Waveform, frequency: f32, volume: f32) -> Vec<f32> {
+        let tone = Tone {
+            waveform,
+            frequency,
+            volume,
+        };
+        let mut synth = Synth::new(tone, SAMPLE_RATE);
+        let mut samples = Vec::new();
+        for _ in 0..FRAMES_PER_SECOND {
+            synth.generate_frame(true, &mut samples);
+        }
+        samples.split_off((RAMP_SECONDS * SAMPLE_RATE as f32) as usize + 1)
+    }
+
+    fn sign_changes(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn parses_waveform_names() {
+        assert_eq!(Waveform::from_name("triangle"), Ok(Waveform::Triangle));
+        assert_eq!(Waveform::from_name("noise"), Ok(Waveform::Noise));
+        assert!(Waveform::from_name("Square").is_err());
+    }
+
+    #[test]
+    fn waveforms_have_their_frequency_and_volume() {
+        for waveform in [Waveform::Square, Waveform::Sine, Waveform::Triangle] {
+            let samples = second_of(waveform, 440.0, 0.5);
+            let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!((peak - 0.5).abs() < 0.01, "{:?} {}", waveform, peak);
+            // Two sign changes per period.
+            let changes = sign_changes(&samples) as f32;
+            assert!((changes - 880.0).abs() < 10.0, "{:?} {}", waveform, changes);
+        }
+        let square = second_of(Waveform::Square, 440.0, 0.5);
+        assert!(square.iter().all(|s| s.abs() == 0.5));
+    }
+
+    #[test]
+    fn noise_changes_level_at_its_frequency() {
+        let samples = second_of(Waveform::Noise, 1000.0, 1.0);
+        let mut levels = samples.clone();
+        levels.dedup();
+        // A new level every half period, some of which happen to repeat the sign.
+        assert!((1900..=2000).contains(&levels.len()), "{}", levels.len());
+        assert!(sign_changes(&samples) > 500);
+        assert!(samples.iter().all(|s| s.abs() <= 1.0));
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        let samples = second_of(Waveform::Square, 440.0, 3.0);
+        assert!(samples.iter().all(|s| s.abs() == 1.0));
+    }
+
+    #[test]
+    fn frames_add_up_to_whole_seconds() {
+        for sample_rate in [22050, 44100, 48000] {
+            let mut synth = Synth::new(Tone::default(), sample_rate);
+            let mut samples = Vec::new();
+            for _ in 0..FRAMES_PER_SECOND {
+                synth.generate_frame(true, &mut samples);
+            }
+            assert_eq!(samples.len(), sample_rate as usize);
+        }
+    }
+
+    #[test]
+    fn tone_lasts_as_long_as_the_buzzer() {
+        let mut synth = Synth::new(Tone::default(), SAMPLE_RATE);
+        let mut samples = Vec::new();
+        for frame in 0..12 {
+            synth.generate_frame(frame < 6, &mut samples);
+        }
+        let frame_length = (SAMPLE_RATE / FRAMES_PER_SECOND) as usize;
+        let ramp = (RAMP_SECONDS * SAMPLE_RATE as f32) as usize + 1;
+        let sounding = |range: std::ops::Range<usize>| samples[range].iter().any(|&s| s != 0.0);
+        assert!(sounding(ramp..6 * frame_length));
+        assert!(samples[ramp..6 * frame_length]
+            .iter()
+            .all(|s| s.abs() == Tone::default().volume));
+        assert!(!sounding(6 * frame_length + ramp..12 * frame_length));
+    }
+
+    #[test]
+    fn muting_keeps_the_recorded_samples() {
+        let mut sound = Sound::null(Tone::default());
+        assert!(sound.toggle_mute());
+        for _ in 0..2 {
+            sound.push_frame(true);
         }
+        assert!(sound.last_frame().iter().all(|&s| s != 0.0));
+        assert!(!sound.toggle_mute());
     }
 }
@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::ops::Range;
+
+// A named (or anonymous) part of the ROM that holds data rather than code.
+#[derive(Debug, Clone)]
+pub struct DataRegion {
+    pub range: Range<u16>,
+    pub name: Option<String>,
+}
+
+// Labels for addresses and data region annotations, which the debugging tools show in
+// place of raw addresses. The file format has one entry per line, with hexadecimal
+// addresses and data regions including their last address:
+//
+//   ; comment
+//   2A4 draw_player
+//   data 300 30F player_sprite
+#[derive(Debug, Default)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+    data: Vec<DataRegion>,
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Reading {}: {}", path, e))?;
+        Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        for (number, line) in text.lines().enumerate() {
+            symbols
+                .parse_line(line)
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        }
+        Ok(symbols)
+    }
+
+    fn parse_line(self: &mut Self, line: &str) -> Result<(), String> {
+        let line = line.split(';').next().unwrap_or("").trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Ok(()),
+            ["data", start, end, name @ ..] if name.len() <= 1 => {
+                let start = parse_address(start)?;
+                let end = parse_address(end)?;
+                if end < start {
+                    return Err(format!("Data region ends before it starts: {}", line));
+                }
+                self.add_data(start..end + 1, name.first().map(|n| n.to_string()));
+                Ok(())
+            }
+            [address, name] => self.add_label(parse_address(address)?, name),
+            _ => Err(format!("Expected \"<address> <label>\": {}", line)),
+        }
+    }
+
+    pub fn add_label(self: &mut Self, address: u16, name: &str) -> Result<(), String> {
+        // Labels win over hexadecimal addresses when both would fit, but can't start with
+        // anything that only a number starts with.
+        if name.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+            return Err(format!("A label can't start like a number: {}", name));
+        }
+        if let Some(previous) = self.addresses.get(name) {
+            if *previous != address {
+                return Err(format!("Label {} is defined twice.", name));
+            }
+        }
+        self.addresses.insert(name.to_string(), address);
+        self.labels.insert(address, name.to_string());
+        Ok(())
+    }
+
+    pub fn add_data(self: &mut Self, range: Range<u16>, name: Option<String>) {
+        self.data.push(DataRegion { range, name });
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.labels.is_empty() && self.data.is_empty()
+    }
+
+    pub fn label(self: &Self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(|name| name.as_str())
+    }
+
+    pub fn address(self: &Self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    pub fn data_region(self: &Self, address: u16) -> Option<&DataRegion> {
+        self.data
+            .iter()
+            .find(|region| region.range.contains(&address))
+    }
+
+    // The label at the address if there is one, or the address as #NNN.
+    pub fn name(self: &Self, address: u16) -> String {
+        match self.label(address) {
+            Some(label) => label.to_string(),
+            None => format!("#{:03X}", address),
+        }
+    }
+
+    // A label, or a hexadecimal address with an optional 0x or # prefix.
+    pub fn parse_address(self: &Self, text: &str) -> Result<u16, String> {
+        match self.address(text) {
+            Some(address) => Ok(address),
+            None => parse_hex(text).map_err(|_| format!("Not an address or a label: {}", text)),
+        }
+    }
+
+    pub fn to_text(self: &Self) -> String {
+        let mut text = String::new();
+        for (address, name) in &self.labels {
+            let _ = writeln!(text, "{:03X} {}", address, name);
+        }
+        for region in &self.data {
+            let _ = write!(
+                text,
+                "data {:03X} {:03X}",
+                region.range.start,
+                region.range.end - 1
+            );
+            match &region.name {
+                Some(name) => {
+                    let _ = writeln!(text, " {}", name);
+                }
+                None => text.push('\n'),
+            }
+        }
+        text
+    }
+}
+
+// Accepts an optional 0x or # prefix.
+pub fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('#'))
+        .unwrap_or(text);
+    match u16::from_str_radix(digits, 16) {
+        Ok(value) => Ok(value),
+        Err(..) => Err(format!("Not a hexadecimal number: {}", text)),
+    }
+}
+
+// A hexadecimal address that fits in the CHIP-8's memory.
+fn parse_address(text: &str) -> Result<u16, String> {
+    match parse_hex(text)? {
+        address if address <= 0xfff => Ok(address),
+        _ => Err(format!("Address out of memory: {}", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_labels_and_data_regions() {
+        let symbols = Symbols::parse(
+            "; comment\n\n2A4 draw_player ; trailing comment\ndata 300 30F player_sprite\ndata 310 310\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.label(0x2a4), Some("draw_player"));
+        assert_eq!(symbols.address("draw_player"), Some(0x2a4));
+        let region = symbols.data_region(0x30f).unwrap();
+        assert_eq!(region.range, 0x300..0x310);
+        assert_eq!(region.name.as_deref(), Some("player_sprite"));
+        assert!(symbols.data_region(0x310).unwrap().name.is_none());
+        assert!(symbols.data_region(0x311).is_none());
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let text = "2A4 draw_player\ndata 300 30F player_sprite\ndata 310 310\n";
+        assert_eq!(Symbols::parse(text).unwrap().to_text(), text);
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        for text in [
+            "2A4",
+            "2A4 draw player",
+            "XYZ draw_player",
+            "2A4 1st",
+            "2A4 a\n2A6 a",
+            "data 30F 300",
+            "data 300 FFFF",
+            "1000 past_the_end",
+        ] {
+            assert!(Symbols::parse(text).is_err(), "{}", text);
+        }
+        assert_eq!(
+            Symbols::parse("2A4 a\n2A6 a").unwrap_err(),
+            "line 2: Label a is defined twice."
+        );
+        assert_eq!(
+            Symbols::parse("2A4 a\ndata 300 1000").unwrap_err(),
+            "line 2: Address out of memory: 1000"
+        );
+    }
+
+    #[test]
+    fn parses_hex_with_or_without_a_prefix() {
+        assert_eq!(parse_hex("2a4"), Ok(0x2a4));
+        assert_eq!(parse_hex("0x2A4"), Ok(0x2a4));
+        assert_eq!(parse_hex("#2A4"), Ok(0x2a4));
+        assert_eq!(
+            parse_hex("0x"),
+            Err("Not a hexadecimal number: 0x".to_string())
+        );
+        assert!(parse_hex("2A4G").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::{stdout, Stdout, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{
+    self, Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, terminal};
+
+use crate::interpreter::{Interpreter, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::keymap::Keymap;
+use crate::keypad::{Key, Keypad};
+use crate::palette::Palette;
+
+// Most terminals only report key presses (and auto-repeats), not releases. There, a key
+// counts as held until this long after its last press or repeat, which has to outlast the
+// delay before auto-repeat starts (usually 250-500 ms).
+const KEY_HOLD_MS: u32 = 600;
+
+// Runs the interpreter in the terminal until Escape or Ctrl-C is pressed. Every character
+// cell shows two pixels stacked on top of each other, using the upper half block with the
+// top pixel as foreground colour and the bottom one as background colour.
+pub fn run(
+    interpreter: &mut Interpreter,
+    cycles_per_frame: u32,
+    palette: &Palette,
+    keymap: &Keymap,
+) -> Result<(), String> {
+    let mut out = stdout();
+    if let Err(err) = terminal::enable_raw_mode() {
+        return Err(format!("Failed to set up the terminal: {}", err));
+    }
+    // Terminals implementing the kitty keyboard protocol can report key releases.
+    let reports_releases = matches!(terminal::supports_keyboard_enhancement(), Ok(true));
+    let mut setup = execute!(out, terminal::EnterAlternateScreen, cursor::Hide);
+    if reports_releases && setup.is_ok() {
+        setup = execute!(
+            out,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        );
+    }
+
+    let result = match setup {
+        Ok(()) => run_loop(
+            &mut out,
+            interpreter,
+            cycles_per_frame,
+            palette,
+            keymap,
+            reports_releases,
+        ),
+        Err(err) => Err(format!("Failed to set up the terminal: {}", err)),
+    };
+
+    if reports_releases {
+        let _ = execute!(out, PopKeyboardEnhancementFlags);
+    }
+    let _ = execute!(
+        out,
+        ResetColor,
+        cursor::Show,
+        terminal::LeaveAlternateScreen
+    );
+    let _ = terminal::disable_raw_mode();
+
+    result
+}
+
+fn run_loop(
+    out: &mut Stdout,
+    interpreter: &mut Interpreter,
+    cycles_per_frame: u32,
+    palette: &Palette,
+    keymap: &Keymap,
+    reports_releases: bool,
+) -> Result<(), String> {
+    let start = Instant::now();
+    let mut keypad = Keypad::new(0);
+    // When each held key is released, if the terminal can't tell us.
+    let mut held_until: [Option<u32>; 0x10] = [None; 0x10];
+    let mut drawn_framebuffer: Option<[u8; 256]> = None;
+    let mut was_beeping = false;
+
+    loop {
+        let frame_start = Instant::now();
+        let now = start.elapsed().as_millis() as u32;
+        keypad.begin_frame(now);
+
+        while poll_event()? {
+            let key_event = match event::read() {
+                Ok(Event::Key(key_event)) => key_event,
+                Ok(Event::Resize(..)) => {
+                    drawn_framebuffer = None;
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(err) => return Err(err.to_string()),
+            };
+            let ctrl_c = key_event.code == KeyCode::Char('c')
+                && key_event.modifiers.contains(KeyModifiers::CONTROL);
+            if key_event.code == KeyCode::Esc || ctrl_c {
+                return Ok(());
+            }
+
+            let key = match key_event.code {
+                KeyCode::Char(c) => keymap.lookup_char(c),
+                _ => None,
+            };
+            let key = match key {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let held = &mut held_until[key as usize];
+            if key_event.kind == KeyEventKind::Release {
+                if held.is_some() {
+                    keypad.push_event(now, key, false);
+                }
+                *held = None;
+            } else {
+                if held.is_none() {
+                    keypad.push_event(now, key, true);
+                }
+                *held = Some(if reports_releases {
+                    u32::MAX
+                } else {
+                    now + KEY_HOLD_MS
+                });
+            }
+        }
+
+        for (key, held) in held_until.iter_mut().enumerate() {
+            if let Some(until) = *held {
+                if now >= until {
+                    keypad.push_event(now, Key::from(key as u8), false);
+                    *held = None;
+                }
+            }
+        }
+
+        let beeping = interpreter.run_frame(&mut keypad, cycles_per_frame)?;
+        if beeping && !was_beeping {
+            // The terminal bell is the closest thing to a buzzer we have.
+            queue_or_fail(out, Print('\x07'))?;
+        }
+        was_beeping = beeping;
+
+        if drawn_framebuffer != Some(*interpreter.framebuffer()) {
+            draw(out, interpreter.framebuffer(), palette)?;
+            drawn_framebuffer = Some(*interpreter.framebuffer());
+        }
+        if let Err(err) = out.flush() {
+            return Err(err.to_string());
+        }
+
+        if let Some(remaining) = Duration::from_micros(16666).checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}
+
+fn poll_event() -> Result<bool, String> {
+    match event::poll(Duration::ZERO) {
+        Ok(ready) => Ok(ready),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn queue_or_fail(out: &mut Stdout, command: impl crossterm::Command) -> Result<(), String> {
+    match queue!(out, command) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn draw(out: &mut Stdout, framebuffer: &[u8; 256], palette: &Palette) -> Result<(), String> {
+    let colors = palette.colors.map(|rgb| Color::Rgb {
+        r: (rgb >> 16) as u8,
+        g: (rgb >> 8) as u8,
+        b: rgb as u8,
+    });
+    let lit = |x: usize, y: usize| framebuffer[x / 8 + y * 8].wrapping_shr(7 - (x % 8) as u32) & 1;
+
+    for row in 0..(SCREEN_HEIGHT / 2) as usize {
+        queue_or_fail(out, cursor::MoveTo(0, row as u16))?;
+        for x in 0..SCREEN_WIDTH as usize {
+            let top = colors[lit(x, row * 2) as usize];
+            let bottom = colors[lit(x, row * 2 + 1) as usize];
+            queue_or_fail(out, SetForegroundColor(top))?;
+            queue_or_fail(out, SetBackgroundColor(bottom))?;
+            queue_or_fail(out, Print('▀'))?;
+        }
+    }
+    queue_or_fail(out, ResetColor)
+}
@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// Writes mono 16-bit PCM WAV files. The sizes in the header are only known at the end, so
+// they are filled in by `finish`.
+pub struct WavWriter {
+    path: String,
+    file: BufWriter<File>,
+    sample_count: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32) -> Result<WavWriter, String> {
+        let file = match File::create(path) {
+            Ok(file) => BufWriter::new(file),
+            Err(err) => return Err(format!("Failed to create {}: {}", path, err)),
+        };
+        let mut writer = WavWriter {
+            path: path.to_string(),
+            file,
+            sample_count: 0,
+        };
+
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, one channel.
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        // Bytes per sample, and bits per sample.
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write(&header)?;
+
+        Ok(writer)
+    }
+
+    pub fn path(self: &Self) -> &str {
+        &self.path
+    }
+
+    pub fn write_samples(self: &mut Self, samples: &[f32]) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.sample_count += samples.len() as u32;
+        self.write(&bytes)
+    }
+
+    pub fn finish(mut self: Self) -> Result<(), String> {
+        let data_size = self.sample_count * 2;
+        let result = self
+            .file
+            .seek(SeekFrom::Start(4))
+            .and_then(|_| self.file.write_all(&(36 + data_size).to_le_bytes()))
+            .and_then(|_| self.file.seek(SeekFrom::Start(40)))
+            .and_then(|_| self.file.write_all(&data_size.to_le_bytes()))
+            .and_then(|_| self.file.flush());
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("Failed to write {}: {}", self.path, err)),
+        }
+    }
+
+    fn write(self: &mut Self, bytes: &[u8]) -> Result<(), String> {
+        match self.file.write_all(bytes) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("Failed to write {}: {}", self.path, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_pcm_header_and_samples() {
+        let path = std::env::temp_dir().join(format!("chip8emu-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut wav = WavWriter::create(path, 22050).unwrap();
+        wav.write_samples(&[0.0, 1.0]).unwrap();
+        wav.write_samples(&[-1.0, 0.5, 2.0]).unwrap();
+        wav.finish().unwrap();
+
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!((u16_at(20), u16_at(22)), (1, 1));
+        assert_eq!((u32_at(24), u32_at(28)), (22050, 44100));
+        assert_eq!((u16_at(32), u16_at(34)), (2, 16));
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(40), 10);
+
+        let samples: Vec<i16> = bytes[44..]
+            .chunks_exact(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        // Samples out of range are clipped.
+        assert_eq!(samples, [0, 32767, -32767, 16384, 32767]);
+    }
+
+    #[test]
+    fn reports_files_that_cannot_be_created() {
+        let err = WavWriter::create("/nonexistent/audio.wav", 44100)
+            .err()
+            .unwrap();
+        assert!(
+            err.starts_with("Failed to create /nonexistent/audio.wav:"),
+            "{}",
+            err
+        );
+    }
+}